@@ -0,0 +1,818 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::path::{Path, PathBuf};
+
+/// Name of the config file that is picked up from the working directory when no path is given.
+pub const DEFAULT_CONFIG_FILE: &str = "ragtime.toml";
+
+/// Environment variable that points at a config file.
+pub const CONFIG_PATH_ENV: &str = "RAGTIME_CONFIG";
+
+/// Settings shared by `vectordb_stuff` and `lambda_stuff`.
+///
+/// Values are resolved in this order: built-in defaults, then the TOML file, then
+/// environment variable overrides (see `Config::apply_env_overrides`).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub models: ModelsConfig,
+    pub database: DatabaseConfig,
+    pub chunking: ChunkingConfig,
+    pub extraction: ExtractionConfig,
+    pub retrieval: RetrievalConfig,
+    pub ingestion: IngestionConfig,
+    pub server: ServerConfig,
+    pub conversation: ConversationConfig,
+    pub prompts: PromptsConfig,
+    pub retry: RetryConfig,
+    pub cache: CacheConfig,
+    pub embedding_cache: EmbeddingCacheConfig,
+    pub dedup: DedupConfig,
+
+    /// Where the file settings came from, if any file was read.
+    #[serde(skip)]
+    pub source: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelsConfig {
+    /// Bedrock model used to answer questions.
+    pub chat_model: String,
+    /// Bedrock model used to embed chunks and questions.
+    pub embeddings_model: String,
+    /// Other chat models callers may ask for, with their inference settings.
+    /// `chat_model` is always allowed, with the settings listed here if it has an entry.
+    pub chat_models: BTreeMap<String, ModelParams>,
+    /// Chat models tried in order when the requested one keeps failing.
+    pub fallback_models: Vec<String>,
+}
+
+impl Default for ModelsConfig {
+    fn default() -> Self {
+        ModelsConfig {
+            chat_model: String::from("anthropic.claude-3-5-haiku-20241022-v1:0"),
+            embeddings_model: String::from("amazon.titan-embed-text-v2:0"),
+            chat_models: BTreeMap::new(),
+            fallback_models: Vec::new(),
+        }
+    }
+}
+
+impl ModelsConfig {
+    /// Chat models a request may choose, sorted.
+    pub fn allowed_chat_models(&self) -> Vec<&str> {
+        let mut models: Vec<&str> = self.chat_models.keys().map(String::as_str).collect();
+        if !self.chat_models.contains_key(&self.chat_model) {
+            models.push(&self.chat_model);
+            models.sort();
+        }
+        models
+    }
+
+    /// `model` followed by the fallback models, without repeats, each with its inference settings.
+    pub fn fallback_chain(&self, model: &str) -> Vec<(String, ModelParams)> {
+        let mut chain: Vec<(String, ModelParams)> = Vec::new();
+        for name in std::iter::once(model).chain(self.fallback_models.iter().map(String::as_str)) {
+            if chain.iter().all(|(m, _)| m != name) {
+                let params = self.chat_models.get(name).cloned().unwrap_or_default();
+                chain.push((name.to_string(), params));
+            }
+        }
+        chain
+    }
+
+    /// Inference settings for `model`, or `None` if it isn't allowed.
+    pub fn chat_model_params(&self, model: &str) -> Option<ModelParams> {
+        match self.chat_models.get(model) {
+            Some(params) => Some(params.clone()),
+            None if model == self.chat_model => Some(ModelParams::default()),
+            None => None,
+        }
+    }
+}
+
+/// Converse inference settings for one model. Anything left unset uses the model's default.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelParams {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stop_sequences: Vec<String>,
+}
+
+impl ModelParams {
+    fn validate(&self, model: &str) -> Result<()> {
+        if self.max_tokens.is_some_and(|t| t <= 0) {
+            bail!(
+                "models.chat_models.\"{}\".max_tokens must be greater than 0",
+                model
+            );
+        }
+        if self.temperature.is_some_and(|t| !(0.0..=1.0).contains(&t)) {
+            bail!(
+                "models.chat_models.\"{}\".temperature must be between 0 and 1",
+                model
+            );
+        }
+        if self.top_p.is_some_and(|p| !(0.0..=1.0).contains(&p)) {
+            bail!(
+                "models.chat_models.\"{}\".top_p must be between 0 and 1",
+                model
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// Local path of the SQLite embeddings database.
+    pub local_path: String,
+    /// S3 bucket the database is published to and downloaded from.
+    pub s3_bucket: String,
+    /// Key of the database object inside `s3_bucket`.
+    pub s3_key: String,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            local_path: String::from("/tmp/embeddings.db"),
+            s3_bucket: String::new(),
+            s3_key: String::from("embeddings/embeddings.db"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChunkingConfig {
+    pub chunker: Chunker,
+    /// Maximum chunk length in bytes.
+    pub chunk_size: usize,
+    /// Number of bytes carried over from the end of one chunk into the next.
+    pub chunk_overlap: usize,
+}
+
+impl Default for ChunkingConfig {
+    fn default() -> Self {
+        ChunkingConfig {
+            chunker: Chunker::Recursive,
+            chunk_size: 600,
+            chunk_overlap: 120,
+        }
+    }
+}
+
+/// How extracted text is split into chunks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Chunker {
+    /// Split on paragraphs, then lines, then sentences, then words, whichever first fits.
+    Recursive,
+    /// Pack whole sentences into each chunk, overlapping by whole sentences.
+    Sentences,
+}
+
+/// Cleanup applied to the text extracted from each PDF, before it is chunked.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExtractionConfig {
+    /// Drop lines repeated at the top or bottom of most pages, like running headers and
+    /// page numbers.
+    pub strip_headers_footers: bool,
+    /// Rejoin words hyphenated across a line break.
+    pub repair_hyphenation: bool,
+    /// Apply Unicode NFKC normalization, which also turns ligatures like "ﬁ" into letters.
+    pub normalize_unicode: bool,
+    /// Collapse runs of spaces and of blank lines.
+    pub collapse_whitespace: bool,
+    /// Find tables from the positions of the text and store each as a Markdown chunk.
+    pub tables: bool,
+    /// Average non-whitespace characters per page below which a document is reported as
+    /// image-only, like a scan without a text layer.
+    pub min_chars_per_page: usize,
+    /// Tried on encrypted PDFs the empty password doesn't open. Only set from
+    /// `RAGTIME_PDF_PASSWORD` or `--pdf-password`, so it is never written to a config file.
+    #[serde(skip)]
+    pub password: Option<String>,
+}
+
+impl Default for ExtractionConfig {
+    fn default() -> Self {
+        ExtractionConfig {
+            strip_headers_footers: true,
+            repair_hyphenation: true,
+            normalize_unicode: true,
+            collapse_whitespace: true,
+            tables: true,
+            min_chars_per_page: 50,
+            password: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetrievalConfig {
+    /// Number of similar chunks retrieved for each question.
+    pub top_k: usize,
+    /// Most (estimated) tokens of passage text put into a prompt.
+    pub max_context_tokens: usize,
+    /// Size of the chat model's context window.
+    pub context_window_tokens: usize,
+    /// Tokens of the context window kept free for the answer.
+    pub answer_tokens: usize,
+}
+
+impl Default for RetrievalConfig {
+    fn default() -> Self {
+        RetrievalConfig {
+            top_k: 5,
+            max_context_tokens: 4000,
+            context_window_tokens: 200_000,
+            answer_tokens: 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IngestionConfig {
+    /// Directory scanned for PDF files.
+    pub pdf_dir: String,
+    /// Embedding requests in flight at once.
+    pub concurrency: usize,
+    /// Embedding requests started per second, to stay within the Bedrock quota.
+    pub requests_per_second: f64,
+    /// Chunks written to the database per transaction.
+    pub batch_size: usize,
+    /// Price of the embeddings model in USD, used to estimate the cost of an ingestion run.
+    pub embedding_cost_per_1k_tokens: f64,
+}
+
+impl Default for IngestionConfig {
+    fn default() -> Self {
+        IngestionConfig {
+            pdf_dir: String::from("pdfs"),
+            concurrency: 8,
+            requests_per_second: 10.0,
+            batch_size: 64,
+            // Amazon Titan Text Embeddings V2, on-demand.
+            embedding_cost_per_1k_tokens: 0.00002,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Use Lambda response streaming. Requires the function URL invoke mode to be RESPONSE_STREAM.
+    pub response_streaming: bool,
+}
+
+/// Where conversation turns are kept between requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionStoreKind {
+    Sqlite,
+    Memory,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConversationConfig {
+    pub store: SessionStoreKind,
+    /// SQLite file used when `store = "sqlite"`.
+    pub sqlite_path: String,
+    /// Number of previous turns sent to the model with each question.
+    pub history_turns: usize,
+    /// Rewrite follow-up questions into standalone questions before retrieval.
+    pub condense_questions: bool,
+}
+
+impl Default for ConversationConfig {
+    fn default() -> Self {
+        ConversationConfig {
+            store: SessionStoreKind::Sqlite,
+            sqlite_path: String::from("/tmp/sessions.db"),
+            history_turns: 5,
+            condense_questions: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PromptsConfig {
+    /// Name of the prompt template used to answer questions, see `crate::prompts`.
+    pub template: String,
+    /// Directory holding `<template>.toml` files.
+    pub dir: String,
+}
+
+impl Default for PromptsConfig {
+    fn default() -> Self {
+        PromptsConfig {
+            template: String::from(crate::prompts::DEFAULT_TEMPLATE),
+            dir: String::from("prompts"),
+        }
+    }
+}
+
+/// Retries with jittered exponential backoff, see `crate::retry`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Attempts per call, including the first one.
+    pub max_attempts: u32,
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_attempts: 3,
+            base_delay_ms: 200,
+            max_delay_ms: 4000,
+        }
+    }
+}
+
+/// Answer cache used by `lambda_stuff`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub enabled: bool,
+    /// Answers kept in memory per Lambda instance.
+    pub capacity: usize,
+    /// How long a cached answer is served, in seconds.
+    pub ttl_seconds: u64,
+    /// SQLite file that keeps answers across cold starts. Empty keeps them in memory only.
+    pub sqlite_path: String,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            enabled: true,
+            capacity: 256,
+            ttl_seconds: 3600,
+            sqlite_path: String::new(),
+        }
+    }
+}
+
+/// Embeddings already computed by either binary, see `crate::embedding_cache`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmbeddingCacheConfig {
+    pub enabled: bool,
+    pub sqlite_path: String,
+}
+
+impl Default for EmbeddingCacheConfig {
+    fn default() -> Self {
+        EmbeddingCacheConfig {
+            enabled: true,
+            sqlite_path: String::from("/tmp/embedding_cache.db"),
+        }
+    }
+}
+
+/// Repeated chunks found while loading documents, see `vectordb_stuff`'s `dedup` module.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DedupConfig {
+    pub mode: DedupMode,
+    /// Estimated share of word trigrams two chunks must have in common to count as
+    /// near-duplicates. 1.0 only catches exact duplicates.
+    pub similarity: f64,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        DedupConfig {
+            mode: DedupMode::Skip,
+            similarity: 0.9,
+        }
+    }
+}
+
+/// What happens to a chunk that repeats one already stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DedupMode {
+    /// Store every chunk.
+    Off,
+    /// Leave out repeats of an earlier chunk of the same document.
+    Skip,
+    /// Also leave out repeats of other documents' chunks, and list each repeat's source and
+    /// chunk index under `duplicates` in the metadata of the chunk it repeats.
+    Link,
+}
+
+impl Config {
+    /// Loads the configuration from `path`, or from `$RAGTIME_CONFIG`, or from `ragtime.toml`
+    /// in the working directory, falling back to the defaults when no file is found.
+    /// Environment overrides are applied and the result is validated.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let explicit = path
+            .map(Path::to_path_buf)
+            .or_else(|| env::var(CONFIG_PATH_ENV).ok().map(PathBuf::from));
+
+        let mut config = match explicit {
+            Some(path) => Self::from_file(&path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).is_file() => {
+                Self::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => Config::default(),
+        };
+
+        config.apply_env_overrides(|key| env::var(key).ok())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file: {}", path.display()))?;
+        let mut config = Self::from_toml(&contents)
+            .with_context(|| format!("Failed to parse config file: {}", path.display()))?;
+        config.source = Some(path.to_path_buf());
+        Ok(config)
+    }
+
+    pub fn from_toml(contents: &str) -> Result<Self> {
+        Ok(toml::from_str(contents)?)
+    }
+
+    /// Overrides individual settings from environment variables.
+    /// `lookup` is `std::env::var` in practice and a map in tests.
+    pub fn apply_env_overrides<F>(&mut self, lookup: F) -> Result<()>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        if let Some(v) = lookup("RAGTIME_CHAT_MODEL") {
+            self.models.chat_model = v;
+        }
+        // Comma-separated model ids, allowed with default settings unless configured in the file.
+        if let Some(v) = lookup("RAGTIME_ALLOWED_MODELS") {
+            for model in v.split(',').map(str::trim).filter(|m| !m.is_empty()) {
+                self.models
+                    .chat_models
+                    .entry(model.to_string())
+                    .or_default();
+            }
+        }
+        if let Some(v) = lookup("RAGTIME_FALLBACK_MODELS") {
+            self.models.fallback_models = v
+                .split(',')
+                .map(str::trim)
+                .filter(|m| !m.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+        if let Some(v) = lookup("RAGTIME_EMBEDDINGS_MODEL") {
+            self.models.embeddings_model = v;
+        }
+        if let Some(v) = lookup("RAGTIME_DB_PATH") {
+            self.database.local_path = v;
+        }
+        // S3_BUCKET_NAME predates the config file and is what the Makefiles and deploy set.
+        if let Some(v) = lookup("S3_BUCKET_NAME") {
+            self.database.s3_bucket = v;
+        }
+        if let Some(v) = lookup("RAGTIME_S3_KEY") {
+            self.database.s3_key = v;
+        }
+        if let Some(v) = lookup("RAGTIME_CHUNK_SIZE") {
+            self.chunking.chunk_size = parse_env("RAGTIME_CHUNK_SIZE", &v)?;
+        }
+        if let Some(v) = lookup("RAGTIME_CHUNK_OVERLAP") {
+            self.chunking.chunk_overlap = parse_env("RAGTIME_CHUNK_OVERLAP", &v)?;
+        }
+        if let Some(v) = lookup("RAGTIME_TOP_K") {
+            self.retrieval.top_k = parse_env("RAGTIME_TOP_K", &v)?;
+        }
+        if let Some(v) = lookup("RAGTIME_MAX_CONTEXT_TOKENS") {
+            self.retrieval.max_context_tokens = parse_env("RAGTIME_MAX_CONTEXT_TOKENS", &v)?;
+        }
+        if let Some(v) = lookup("RAGTIME_PDF_DIR") {
+            self.ingestion.pdf_dir = v;
+        }
+        if let Some(v) = lookup("RAGTIME_PDF_PASSWORD") {
+            self.extraction.password = Some(v);
+        }
+        if let Some(v) = lookup("RAGTIME_INGEST_CONCURRENCY") {
+            self.ingestion.concurrency = parse_env("RAGTIME_INGEST_CONCURRENCY", &v)?;
+        }
+        if let Some(v) = lookup("RAGTIME_INGEST_RPS") {
+            self.ingestion.requests_per_second = parse_env("RAGTIME_INGEST_RPS", &v)?;
+        }
+        if let Some(v) = lookup("RAGTIME_SESSION_DB_PATH") {
+            self.conversation.sqlite_path = v;
+        }
+        if let Some(v) = lookup("RAGTIME_HISTORY_TURNS") {
+            self.conversation.history_turns = parse_env("RAGTIME_HISTORY_TURNS", &v)?;
+        }
+        if let Some(v) = lookup("RAGTIME_PROMPT_TEMPLATE") {
+            self.prompts.template = v;
+        }
+        if let Some(v) = lookup("RAGTIME_PROMPT_DIR") {
+            self.prompts.dir = v;
+        }
+        if let Some(v) = lookup("RAGTIME_RETRY_MAX_ATTEMPTS") {
+            self.retry.max_attempts = parse_env("RAGTIME_RETRY_MAX_ATTEMPTS", &v)?;
+        }
+        if let Some(v) = lookup("RAGTIME_CACHE_ENABLED") {
+            self.cache.enabled = parse_env("RAGTIME_CACHE_ENABLED", &v)?;
+        }
+        if let Some(v) = lookup("RAGTIME_CACHE_TTL_SECONDS") {
+            self.cache.ttl_seconds = parse_env("RAGTIME_CACHE_TTL_SECONDS", &v)?;
+        }
+        if let Some(v) = lookup("RAGTIME_CACHE_DB_PATH") {
+            self.cache.sqlite_path = v;
+        }
+        if let Some(v) = lookup("RAGTIME_EMBEDDING_CACHE_ENABLED") {
+            self.embedding_cache.enabled = parse_env("RAGTIME_EMBEDDING_CACHE_ENABLED", &v)?;
+        }
+        if let Some(v) = lookup("RAGTIME_EMBEDDING_CACHE_PATH") {
+            self.embedding_cache.sqlite_path = v;
+        }
+        if let Some(v) = lookup("RAGTIME_RESPONSE_STREAMING") {
+            self.server.response_streaming = parse_env("RAGTIME_RESPONSE_STREAMING", &v)?;
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.models.chat_model.trim().is_empty() {
+            bail!("models.chat_model must not be empty");
+        }
+        if self.models.embeddings_model.trim().is_empty() {
+            bail!("models.embeddings_model must not be empty");
+        }
+        for (model, params) in &self.models.chat_models {
+            params.validate(model)?;
+        }
+        if self.database.local_path.trim().is_empty() {
+            bail!("database.local_path must not be empty");
+        }
+        if self.database.s3_key.trim().is_empty() {
+            bail!("database.s3_key must not be empty");
+        }
+        if self.chunking.chunk_size == 0 {
+            bail!("chunking.chunk_size must be greater than 0");
+        }
+        if self.chunking.chunk_overlap >= self.chunking.chunk_size {
+            bail!(
+                "chunking.chunk_overlap ({}) must be smaller than chunking.chunk_size ({})",
+                self.chunking.chunk_overlap,
+                self.chunking.chunk_size
+            );
+        }
+        if self.retrieval.top_k == 0 {
+            bail!("retrieval.top_k must be greater than 0");
+        }
+        if self.retrieval.max_context_tokens == 0 {
+            bail!("retrieval.max_context_tokens must be greater than 0");
+        }
+        if self.retrieval.answer_tokens >= self.retrieval.context_window_tokens {
+            bail!(
+                "retrieval.answer_tokens ({}) must be smaller than retrieval.context_window_tokens ({})",
+                self.retrieval.answer_tokens,
+                self.retrieval.context_window_tokens
+            );
+        }
+        if self.ingestion.concurrency == 0 {
+            bail!("ingestion.concurrency must be greater than 0");
+        }
+        if self.ingestion.requests_per_second.is_nan() || self.ingestion.requests_per_second <= 0.0
+        {
+            bail!("ingestion.requests_per_second must be greater than 0");
+        }
+        if self.ingestion.batch_size == 0 {
+            bail!("ingestion.batch_size must be greater than 0");
+        }
+        if self.conversation.store == SessionStoreKind::Sqlite
+            && self.conversation.sqlite_path.trim().is_empty()
+        {
+            bail!(
+                "conversation.sqlite_path must not be empty when conversation.store = \"sqlite\""
+            );
+        }
+        if self.retry.max_attempts == 0 {
+            bail!("retry.max_attempts must be at least 1");
+        }
+        if self.retry.base_delay_ms > self.retry.max_delay_ms {
+            bail!("retry.base_delay_ms must not be larger than retry.max_delay_ms");
+        }
+        if self.prompts.template.trim().is_empty() {
+            bail!("prompts.template must not be empty");
+        }
+        if self.cache.enabled && self.cache.capacity == 0 {
+            bail!("cache.capacity must be greater than 0");
+        }
+        if self.embedding_cache.enabled && self.embedding_cache.sqlite_path.trim().is_empty() {
+            bail!(
+                "embedding_cache.sqlite_path must not be empty when the embedding cache is enabled"
+            );
+        }
+        if !(self.dedup.similarity > 0.0 && self.dedup.similarity <= 1.0) {
+            bail!("dedup.similarity must be greater than 0 and at most 1");
+        }
+        Ok(())
+    }
+
+    /// The effective settings as TOML, for `config show`.
+    pub fn to_toml(&self) -> Result<String> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+}
+
+fn parse_env<T>(key: &str, value: &str) -> Result<T>
+where
+    T: std::str::FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    value
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid value for {}: {:?}", key, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_defaults_are_valid() {
+        let config = Config::default();
+        config.validate().unwrap();
+        assert_eq!(config.database.local_path, "/tmp/embeddings.db");
+        assert_eq!(config.chunking.chunk_size, 600);
+        assert_eq!(config.retrieval.top_k, 5);
+    }
+
+    #[test]
+    fn test_partial_toml_keeps_defaults() {
+        let config = Config::from_toml(
+            r#"
+            [retrieval]
+            top_k = 8
+
+            [database]
+            s3_bucket = "my-bucket"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.retrieval.top_k, 8);
+        assert_eq!(config.database.s3_bucket, "my-bucket");
+        assert_eq!(config.database.s3_key, "embeddings/embeddings.db");
+        assert_eq!(
+            config.models.embeddings_model,
+            "amazon.titan-embed-text-v2:0"
+        );
+    }
+
+    #[test]
+    fn test_unknown_keys_are_rejected() {
+        assert!(Config::from_toml("[retrieval]\ntopk = 3\n").is_err());
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let env: HashMap<&str, &str> = HashMap::from([
+            ("S3_BUCKET_NAME", "env-bucket"),
+            ("RAGTIME_TOP_K", "3"),
+            ("RAGTIME_CHAT_MODEL", "some.other-model"),
+            ("RAGTIME_PDF_PASSWORD", "hunter2"),
+        ]);
+        let mut config = Config::default();
+        config
+            .apply_env_overrides(|k| env.get(k).map(|v| v.to_string()))
+            .unwrap();
+        assert_eq!(config.database.s3_bucket, "env-bucket");
+        assert_eq!(config.retrieval.top_k, 3);
+        assert_eq!(config.models.chat_model, "some.other-model");
+        assert_eq!(config.extraction.password.as_deref(), Some("hunter2"));
+        assert!(!config.to_toml().unwrap().contains("hunter2"));
+    }
+
+    #[test]
+    fn test_invalid_env_number() {
+        let mut config = Config::default();
+        let err = config
+            .apply_env_overrides(|k| (k == "RAGTIME_CHUNK_SIZE").then(|| "big".to_string()))
+            .unwrap_err();
+        assert!(err.to_string().contains("RAGTIME_CHUNK_SIZE"));
+    }
+
+    #[test]
+    fn test_validation() {
+        let mut config = Config::default();
+        config.chunking.chunk_overlap = config.chunking.chunk_size;
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.retrieval.top_k = 0;
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.retrieval.answer_tokens = config.retrieval.context_window_tokens;
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.dedup.similarity = 0.0;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_chat_model_allow_list() {
+        let config = Config::from_toml(
+            r#"
+            [models]
+            chat_model = "anthropic.claude-3-5-haiku-20241022-v1:0"
+
+            [models.chat_models."anthropic.claude-3-5-sonnet-20241022-v2:0"]
+            max_tokens = 2048
+            temperature = 0.2
+            stop_sequences = ["</answer>"]
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        assert_eq!(
+            config.models.allowed_chat_models(),
+            vec![
+                "anthropic.claude-3-5-haiku-20241022-v1:0",
+                "anthropic.claude-3-5-sonnet-20241022-v2:0"
+            ]
+        );
+        let params = config
+            .models
+            .chat_model_params("anthropic.claude-3-5-sonnet-20241022-v2:0")
+            .unwrap();
+        assert_eq!(params.max_tokens, Some(2048));
+        assert_eq!(params.stop_sequences, vec!["</answer>"]);
+        assert_eq!(
+            config
+                .models
+                .chat_model_params("anthropic.claude-3-5-haiku-20241022-v1:0"),
+            Some(ModelParams::default())
+        );
+        assert_eq!(config.models.chat_model_params("some.other-model"), None);
+
+        let mut models = config.models.clone();
+        models.fallback_models = vec![
+            "anthropic.claude-3-5-sonnet-20241022-v2:0".to_string(),
+            "anthropic.claude-3-5-haiku-20241022-v1:0".to_string(),
+        ];
+        let chain = models.fallback_chain("anthropic.claude-3-5-haiku-20241022-v1:0");
+        assert_eq!(chain.len(), 2);
+        assert_eq!(chain[1].0, "anthropic.claude-3-5-sonnet-20241022-v2:0");
+        assert_eq!(chain[1].1.max_tokens, Some(2048));
+
+        let mut config = Config::default();
+        config
+            .apply_env_overrides(|k| (k == "RAGTIME_ALLOWED_MODELS").then(|| "a, b".to_string()))
+            .unwrap();
+        assert_eq!(config.models.allowed_chat_models().len(), 3);
+
+        let mut config = Config::default();
+        config.models.chat_models.insert(
+            "hot".to_string(),
+            ModelParams {
+                temperature: Some(1.5),
+                ..Default::default()
+            },
+        );
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_example_config_is_valid() {
+        let config = Config::from_toml(include_str!("../../ragtime.example.toml")).unwrap();
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let config = Config::default();
+        let parsed = Config::from_toml(&config.to_toml().unwrap()).unwrap();
+        assert_eq!(parsed.chunking.chunk_overlap, config.chunking.chunk_overlap);
+        assert_eq!(parsed.models.chat_model, config.models.chat_model);
+    }
+}
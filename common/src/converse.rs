@@ -0,0 +1,280 @@
+//! Pieces of a Bedrock Converse request shared by the Lambda and `vectordb_stuff answer`:
+//! the request settings, error classification, and retrying with fallback models.
+
+use crate::config::{Config, ModelParams};
+use crate::retry::retry;
+use aws_sdk_bedrockruntime::error::{DisplayErrorContext, ProvideErrorMetadata, SdkError};
+use aws_sdk_bedrockruntime::operation::converse::ConverseOutput;
+use aws_sdk_bedrockruntime::types::{InferenceConfiguration, SystemContentBlock};
+use std::future::Future;
+
+/// Why a Converse or ConverseStream call failed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BedrockConverseError {
+    /// ThrottlingException or ServiceQuotaExceededException.
+    Throttled(String),
+    /// The model took too long, or isn't ready yet.
+    ModelUnavailable(String),
+    /// The request was rejected, e.g. an inference setting the model doesn't support.
+    Validation(String),
+    /// Missing model access or IAM permissions.
+    AccessDenied(String),
+    /// Unknown model id.
+    ModelNotFound(String),
+    /// Errors on the Bedrock side, including errors returned by the model.
+    Service(String),
+    /// The request never got a response: connection failures, timeouts.
+    Transport(String),
+    /// The model answered with something other than text.
+    BadOutput(String),
+}
+
+impl BedrockConverseError {
+    /// Classifies an error by its service error code, e.g. `ThrottlingException`.
+    fn from_code(code: Option<&str>, message: String) -> Self {
+        match code {
+            Some("ThrottlingException") | Some("ServiceQuotaExceededException") => {
+                BedrockConverseError::Throttled(message)
+            }
+            Some("ModelTimeoutException") | Some("ModelNotReadyException") => {
+                BedrockConverseError::ModelUnavailable(message)
+            }
+            Some("ValidationException") => BedrockConverseError::Validation(message),
+            Some("AccessDeniedException") => BedrockConverseError::AccessDenied(message),
+            Some("ResourceNotFoundException") => BedrockConverseError::ModelNotFound(message),
+            _ => BedrockConverseError::Service(message),
+        }
+    }
+
+    pub fn from_sdk<E, R>(err: SdkError<E, R>) -> Self
+    where
+        E: ProvideErrorMetadata + std::error::Error + 'static,
+        R: std::fmt::Debug,
+    {
+        let message = DisplayErrorContext(&err).to_string();
+        match &err {
+            SdkError::ServiceError(_) => Self::from_code(err.code(), message),
+            SdkError::ConstructionFailure(_) => BedrockConverseError::Validation(message),
+            _ => BedrockConverseError::Transport(message),
+        }
+    }
+
+    /// Whether trying the same call again later might succeed.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            BedrockConverseError::Throttled(_)
+                | BedrockConverseError::ModelUnavailable(_)
+                | BedrockConverseError::Service(_)
+                | BedrockConverseError::Transport(_)
+        )
+    }
+}
+
+impl std::fmt::Display for BedrockConverseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (kind, reason) = match self {
+            BedrockConverseError::Throttled(m) => ("throttled", m),
+            BedrockConverseError::ModelUnavailable(m) => ("model unavailable", m),
+            BedrockConverseError::Validation(m) => ("invalid request", m),
+            BedrockConverseError::AccessDenied(m) => ("access denied", m),
+            BedrockConverseError::ModelNotFound(m) => ("model not found", m),
+            BedrockConverseError::Service(m) => ("service error", m),
+            BedrockConverseError::Transport(m) => ("no response", m),
+            BedrockConverseError::BadOutput(m) => ("unexpected output", m),
+        };
+        write!(
+            f,
+            "Can't invoke bedrock model ({}). Reason: {}",
+            kind, reason
+        )
+    }
+}
+
+impl std::error::Error for BedrockConverseError {}
+
+/// The text of the model's reply.
+pub fn get_converse_output_text(output: ConverseOutput) -> Result<String, BedrockConverseError> {
+    let message = output
+        .output()
+        .ok_or(BedrockConverseError::BadOutput("No output content".into()))?
+        .as_message()
+        .map_err(|_| BedrockConverseError::BadOutput("Output not a message".into()))?;
+
+    let text = message
+        .content()
+        .first()
+        .and_then(|content| content.as_text().ok())
+        .ok_or(BedrockConverseError::BadOutput(
+            "No text content found in message".into(),
+        ))?;
+
+    Ok(text.to_string())
+}
+
+/// The result of a model call, and which model produced it after how many calls.
+pub struct Answered<T> {
+    pub value: T,
+    pub model: String,
+    pub params: ModelParams,
+    pub attempts: u32,
+}
+
+/// Calls `call` with `model` and then each fallback model in turn until one succeeds.
+/// Retryable errors are retried with backoff before moving on to the next model.
+pub async fn with_fallback<T, F, Fut>(
+    config: &Config,
+    model: &str,
+    mut call: F,
+) -> Result<Answered<T>, BedrockConverseError>
+where
+    F: FnMut(String, ModelParams) -> Fut,
+    Fut: Future<Output = Result<T, BedrockConverseError>>,
+{
+    let mut attempts = 0;
+    let mut last_error = None;
+    for (model, params) in config.models.fallback_chain(model) {
+        let (result, tries) = retry(&config.retry, BedrockConverseError::is_retryable, || {
+            call(model.clone(), params.clone())
+        })
+        .await;
+        attempts += tries;
+        match result {
+            Ok(value) => {
+                return Ok(Answered {
+                    value,
+                    model,
+                    params,
+                    attempts,
+                })
+            }
+            Err(e) => {
+                tracing::warn!("Model {} failed after {} attempt(s): {}", model, tries, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.expect("the fallback chain starts with the requested model"))
+}
+
+/// A model's inference settings as Converse expects them, omitted when none are configured.
+pub fn inference_config(params: &ModelParams) -> Option<InferenceConfiguration> {
+    if params == &ModelParams::default() {
+        return None;
+    }
+    let stop_sequences = &params.stop_sequences;
+    Some(
+        InferenceConfiguration::builder()
+            .set_max_tokens(params.max_tokens)
+            .set_temperature(params.temperature)
+            .set_top_p(params.top_p)
+            .set_stop_sequences((!stop_sequences.is_empty()).then(|| stop_sequences.clone()))
+            .build(),
+    )
+}
+
+/// A rendered system prompt as the Converse `system` field; omitted when empty.
+pub fn system_prompt(system: &str) -> Option<Vec<SystemContentBlock>> {
+    if system.is_empty() {
+        None
+    } else {
+        Some(vec![SystemContentBlock::Text(system.to_string())])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RetryConfig;
+
+    #[test]
+    fn test_inference_config() {
+        assert!(inference_config(&ModelParams::default()).is_none());
+        let params = ModelParams {
+            max_tokens: Some(512),
+            temperature: Some(0.2),
+            ..Default::default()
+        };
+        let inference = inference_config(&params).unwrap();
+        assert_eq!(inference.max_tokens(), Some(512));
+        assert_eq!(inference.temperature(), Some(0.2));
+        assert_eq!(inference.top_p(), None);
+        assert!(inference.stop_sequences().is_empty());
+    }
+
+    #[test]
+    fn test_error_classification() {
+        let throttled =
+            BedrockConverseError::from_code(Some("ThrottlingException"), "slow down".into());
+        assert_eq!(
+            throttled,
+            BedrockConverseError::Throttled("slow down".into())
+        );
+        assert!(throttled.is_retryable());
+
+        let denied = BedrockConverseError::from_code(Some("AccessDeniedException"), "no".into());
+        assert_eq!(denied, BedrockConverseError::AccessDenied("no".into()));
+        assert!(!denied.is_retryable());
+
+        assert!(
+            !BedrockConverseError::from_code(Some("ValidationException"), "".into()).is_retryable()
+        );
+        assert!(
+            BedrockConverseError::from_code(Some("ModelTimeoutException"), "".into())
+                .is_retryable()
+        );
+        assert!(BedrockConverseError::from_code(None, "".into()).is_retryable());
+    }
+
+    fn fallback_config() -> Config {
+        let mut config = Config::default();
+        config.retry = RetryConfig {
+            max_attempts: 2,
+            base_delay_ms: 0,
+            max_delay_ms: 0,
+        };
+        config.models.chat_model = "primary".to_string();
+        config.models.fallback_models = vec!["backup".to_string()];
+        config
+    }
+
+    #[tokio::test]
+    async fn test_fallback_after_retries() {
+        let config = fallback_config();
+        let calls = std::cell::RefCell::new(Vec::new());
+        let answered = with_fallback(&config, "primary", |model, _| {
+            calls.borrow_mut().push(model.clone());
+            async move {
+                if model == "primary" {
+                    Err(BedrockConverseError::Throttled("slow down".into()))
+                } else {
+                    Ok("answer")
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(answered.value, "answer");
+        assert_eq!(answered.model, "backup");
+        assert_eq!(answered.attempts, 3);
+        assert_eq!(*calls.borrow(), vec!["primary", "primary", "backup"]);
+    }
+
+    #[tokio::test]
+    async fn test_fallback_skips_retries_for_permanent_errors() {
+        let config = fallback_config();
+        let result = with_fallback(&config, "primary", |_, _| async {
+            Err::<(), _>(BedrockConverseError::AccessDenied("no access".into()))
+        })
+        .await;
+        assert_eq!(
+            result.err(),
+            Some(BedrockConverseError::AccessDenied("no access".into()))
+        );
+
+        let answered = with_fallback(&config, "primary", |_, _| async { Ok(1) })
+            .await
+            .unwrap();
+        assert_eq!((answered.model.as_str(), answered.attempts), ("primary", 1));
+    }
+}
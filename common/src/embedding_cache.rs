@@ -0,0 +1,132 @@
+use crate::vectordb::{embedding_bytes, embedding_from_bytes};
+use anyhow::Result;
+use rusqlite::{Connection, OptionalExtension};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Embeddings that have already been computed, keyed by a hash of the model id and the
+/// whitespace-normalized text, so identical chunks and repeated questions aren't sent to
+/// Bedrock twice.
+pub struct EmbeddingCache {
+    conn: Mutex<Connection>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Lookups made through an `EmbeddingCache` since it was opened.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EmbeddingCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl std::fmt::Display for EmbeddingCacheStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} hits, {} misses", self.hits, self.misses)
+    }
+}
+
+/// Content address of `text` embedded with `model`. Runs of whitespace are collapsed first,
+/// since they don't change what a chunk means.
+pub fn cache_key(model: &str, text: &str) -> String {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    let mut hasher = Sha256::new();
+    hasher.update(model.as_bytes());
+    hasher.update([0]);
+    hasher.update(text.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+impl EmbeddingCache {
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    pub fn in_memory() -> Result<Self> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(conn: Connection) -> Result<Self> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS embedding_cache (
+                key TEXT PRIMARY KEY,
+                model TEXT NOT NULL,
+                embedding BLOB NOT NULL
+            )",
+        )?;
+        Ok(EmbeddingCache {
+            conn: Mutex::new(conn),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        })
+    }
+
+    pub fn get(&self, model: &str, text: &str) -> Result<Option<Vec<f32>>> {
+        let bytes: Option<Vec<u8>> = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT embedding FROM embedding_cache WHERE key = ?1",
+                [cache_key(model, text)],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let counter = if bytes.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        Ok(bytes.map(|bytes| embedding_from_bytes(&bytes)))
+    }
+
+    pub fn put(&self, model: &str, text: &str, embedding: &[f32]) -> Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO embedding_cache (key, model, embedding) VALUES (?1, ?2, ?3)",
+            rusqlite::params![cache_key(model, text), model, embedding_bytes(embedding)],
+        )?;
+        Ok(())
+    }
+
+    pub fn stats(&self) -> EmbeddingCacheStats {
+        EmbeddingCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_key() {
+        assert_eq!(
+            cache_key("titan", "Each player  takes\n45 cars"),
+            cache_key("titan", " Each player takes 45 cars ")
+        );
+        assert_ne!(
+            cache_key("titan", "45 cars"),
+            cache_key("cohere", "45 cars")
+        );
+        assert_ne!(cache_key("titan", "45 cars"), cache_key("titan", "45 Cars"));
+    }
+
+    #[test]
+    fn test_round_trip_and_stats() {
+        let cache = EmbeddingCache::in_memory().unwrap();
+        assert_eq!(cache.get("titan", "north").unwrap(), None);
+
+        cache.put("titan", "north", &[1.0, -0.5, 0.25]).unwrap();
+        assert_eq!(
+            cache.get("titan", "north").unwrap(),
+            Some(vec![1.0, -0.5, 0.25])
+        );
+        assert_eq!(cache.get("other-model", "north").unwrap(), None);
+        assert_eq!(cache.stats(), EmbeddingCacheStats { hits: 1, misses: 2 });
+    }
+}
@@ -1,22 +1,74 @@
-// use anyhow::Result;
+use aws_sdk_bedrockruntime::error::{DisplayErrorContext, ProvideErrorMetadata, SdkError};
 use aws_sdk_bedrockruntime::primitives::Blob;
-use aws_sdk_bedrockruntime::{
-    // operation::converse::{ConverseError, ConverseOutput},
-    // types::{ContentBlock, ConversationRole, Message},
-    Client,
-};
+use aws_sdk_bedrockruntime::Client;
 // use lambda_http::{Body, Error, Request, Response};
+use crate::config::{Config, RetryConfig};
+use crate::embedding_cache::{EmbeddingCache, EmbeddingCacheStats};
+use crate::rate_limit::TokenBucket;
+use crate::retry::retry;
 use serde_json::{json, Value};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-pub async fn create_embeddings(question: &str, model_name: &str) -> anyhow::Result<Value> {
-    let config = aws_config::load_from_env().await;
-    let bedrock_client = Client::new(&config);
+/// A failed InvokeModel call, and whether trying it again later might succeed.
+#[derive(Debug)]
+pub struct EmbeddingError {
+    pub retryable: bool,
+    pub error: anyhow::Error,
+}
+
+impl EmbeddingError {
+    fn from_sdk<E, R>(err: SdkError<E, R>) -> Self
+    where
+        E: ProvideErrorMetadata + std::error::Error + Send + Sync + 'static,
+        R: std::fmt::Debug,
+    {
+        let retryable = match &err {
+            SdkError::ServiceError(_) => matches!(
+                err.code(),
+                Some(
+                    "ThrottlingException"
+                        | "ServiceUnavailableException"
+                        | "ModelTimeoutException"
+                        | "ModelNotReadyException"
+                        | "InternalServerException"
+                )
+            ),
+            SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) => true,
+            _ => false,
+        };
+        EmbeddingError {
+            retryable,
+            error: anyhow::anyhow!("InvokeModel failed: {}", DisplayErrorContext(&err)),
+        }
+    }
+
+    fn permanent(error: impl Into<anyhow::Error>) -> Self {
+        EmbeddingError {
+            retryable: false,
+            error: error.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for EmbeddingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#}", self.error)
+    }
+}
 
+impl std::error::Error for EmbeddingError {}
+
+pub async fn create_embeddings(
+    bedrock_client: &Client,
+    text: &str,
+    model_name: &str,
+) -> Result<Value, EmbeddingError> {
     let input_json = json!({
-        "inputText": question
+        "inputText": text
     });
 
-    let input_bytes = serde_json::to_vec(&input_json)?;
+    let input_bytes = serde_json::to_vec(&input_json).map_err(EmbeddingError::permanent)?;
 
     let response = bedrock_client
         .invoke_model()
@@ -25,11 +77,141 @@ pub async fn create_embeddings(question: &str, model_name: &str) -> anyhow::Resu
         .content_type("application/json")
         .accept("application/json")
         .send()
-        .await?;
+        .await
+        .map_err(EmbeddingError::from_sdk)?;
 
     let response_body = response.body.as_ref();
-    let embeddings: Value = serde_json::from_slice(response_body)?;
-    println!("embeddings: {:?}", embeddings);
+    serde_json::from_slice(response_body).map_err(EmbeddingError::permanent)
+}
+
+/// Pulls the vector out of a Titan embeddings response (`{"embedding": [...]}`). Any element
+/// that isn't a number is an error, rather than a zero that would be cached and stored.
+pub fn embedding_vector(embeddings: &Value) -> anyhow::Result<Vec<f32>> {
+    embeddings
+        .get("embedding")
+        .and_then(|e| e.as_array())
+        .ok_or_else(|| anyhow::anyhow!("Embeddings field not found or not an array"))?
+        .iter()
+        .enumerate()
+        .map(|(i, v)| {
+            v.as_f64()
+                .map(|x| x as f32)
+                .ok_or_else(|| anyhow::anyhow!("Embedding element {} is not a number: {}", i, v))
+        })
+        .collect()
+}
+
+/// Embeds text with the configured embeddings model, going through the embedding cache
+/// when `embedding_cache.enabled` is on. One Bedrock client is shared by every call, and
+/// throttled calls are retried as configured in `[retry]`.
+pub struct Embedder {
+    client: Client,
+    model: String,
+    cache: Option<EmbeddingCache>,
+    retry: RetryConfig,
+    limiter: Option<TokenBucket>,
+    calls: AtomicU64,
+    characters: AtomicU64,
+}
+
+/// Bedrock usage of an `Embedder` so far. Cache hits aren't counted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EmbedderUsage {
+    /// InvokeModel calls, including retries.
+    pub calls: u64,
+    /// Characters of text that were embedded by Bedrock.
+    pub characters: u64,
+}
+
+impl Embedder {
+    pub async fn new(config: &Config) -> anyhow::Result<Self> {
+        let aws_config = aws_config::load_from_env().await;
+        Self::with_client(config, Client::new(&aws_config))
+    }
+
+    /// Like `new`, but calls Bedrock through `client`, for callers that already have one.
+    pub fn with_client(config: &Config, client: Client) -> anyhow::Result<Self> {
+        let cache = if config.embedding_cache.enabled {
+            Some(EmbeddingCache::open(Path::new(
+                &config.embedding_cache.sqlite_path,
+            ))?)
+        } else {
+            None
+        };
+        Ok(Embedder {
+            client,
+            model: config.models.embeddings_model.clone(),
+            cache,
+            retry: config.retry.clone(),
+            limiter: None,
+            calls: AtomicU64::new(0),
+            characters: AtomicU64::new(0),
+        })
+    }
+
+    /// Lets at most `per_second` Bedrock calls start each second, after an initial `burst`.
+    /// Cache hits don't count.
+    pub fn with_rate_limit(mut self, per_second: f64, burst: usize) -> Self {
+        self.limiter = Some(TokenBucket::new(per_second, burst));
+        self
+    }
+
+    pub async fn embed(&self, text: &str) -> anyhow::Result<Vec<f32>> {
+        if let Some(cache) = &self.cache {
+            if let Some(vector) = cache.get(&self.model, text)? {
+                return Ok(vector);
+            }
+        }
+        let (result, attempts) = retry(
+            &self.retry,
+            |e: &EmbeddingError| e.retryable,
+            || async {
+                if let Some(limiter) = &self.limiter {
+                    limiter.acquire().await;
+                }
+                self.calls.fetch_add(1, Ordering::Relaxed);
+                create_embeddings(&self.client, text, &self.model).await
+            },
+        )
+        .await;
+        if attempts > 1 {
+            tracing::debug!("Embedding needed {} attempts", attempts);
+        }
+        // Kept as an `EmbeddingError` so callers can tell Bedrock failures from cache errors.
+        let vector = embedding_vector(&result.map_err(anyhow::Error::new)?)?;
+        self.characters
+            .fetch_add(text.chars().count() as u64, Ordering::Relaxed);
+        if let Some(cache) = &self.cache {
+            cache.put(&self.model, text, &vector)?;
+        }
+        Ok(vector)
+    }
+
+    pub fn usage(&self) -> EmbedderUsage {
+        EmbedderUsage {
+            calls: self.calls.load(Ordering::Relaxed),
+            characters: self.characters.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Cache hits and misses so far, or `None` when the cache is disabled.
+    pub fn cache_stats(&self) -> Option<EmbeddingCacheStats> {
+        self.cache.as_ref().map(EmbeddingCache::stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    Ok(embeddings)
+    #[test]
+    fn test_embedding_vector() {
+        assert_eq!(
+            embedding_vector(&json!({"embedding": [0.5, -1, 2e-3]})).unwrap(),
+            vec![0.5, -1.0, 0.002]
+        );
+        assert!(embedding_vector(&json!({"vector": [0.5]})).is_err());
+        let error = embedding_vector(&json!({"embedding": [0.5, null, "1"]})).unwrap_err();
+        assert!(error.to_string().contains("element 1"));
+    }
 }
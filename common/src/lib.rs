@@ -1,4 +1,10 @@
+pub mod config;
+pub mod converse;
+pub mod embedding_cache;
 pub mod embeddings;
+pub mod prompts;
+pub mod rate_limit;
+pub mod retry;
 pub mod vectordb;
 
 #[cfg(test)]
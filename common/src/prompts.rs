@@ -0,0 +1,271 @@
+use anyhow::{bail, Context, Result};
+use minijinja::{Environment, UndefinedBehavior};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::Path;
+
+/// Name of the template that is compiled into the binaries.
+pub const DEFAULT_TEMPLATE: &str = "default";
+
+const DEFAULT_TEMPLATE_TOML: &str = include_str!("../prompts/default.toml");
+
+/// A named, versioned pair of system and user prompts, loaded from a TOML file.
+/// Both prompts are minijinja templates; see `PromptVars` for the variables they can use.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PromptTemplate {
+    pub name: String,
+    pub version: String,
+    #[serde(default)]
+    pub description: String,
+    pub system: String,
+    pub user: String,
+}
+
+/// Variables available to prompt templates.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PromptVars {
+    pub question: String,
+    pub passages: Vec<Passage>,
+    pub history: Vec<HistoryTurn>,
+    /// Today's date as `YYYY-MM-DD`.
+    pub date: String,
+}
+
+/// A retrieved chunk as seen by a template. `number` starts at 1 and is what answers cite.
+#[derive(Debug, Clone, Serialize)]
+pub struct Passage {
+    pub number: usize,
+    pub text: String,
+    pub source: Option<String>,
+    pub chunk_index: Option<u64>,
+    /// The outline path of the section the chunk is in, like "Rules › Scoring".
+    pub heading: Option<String>,
+    pub score: f32,
+}
+
+impl PromptVars {
+    /// Variables for `question` asked today.
+    pub fn new(question: &str, passages: Vec<Passage>, history: Vec<HistoryTurn>) -> Self {
+        PromptVars {
+            question: question.to_string(),
+            passages,
+            history,
+            date: chrono::Utc::now().format("%Y-%m-%d").to_string(),
+        }
+    }
+}
+
+impl Passage {
+    /// The passage numbered `number` for a retrieved chunk, with its source, position and
+    /// heading taken from the chunk's metadata.
+    pub fn from_chunk(number: usize, text: &str, metadata: Option<&Value>, score: f32) -> Self {
+        let field = |key: &str| metadata.and_then(|m| m.get(key));
+        Passage {
+            number,
+            text: text.to_string(),
+            source: field("source").and_then(Value::as_str).map(str::to_string),
+            chunk_index: field("chunk_index").and_then(Value::as_u64),
+            heading: field("heading").and_then(Value::as_str).map(str::to_string),
+            score,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HistoryTurn {
+    pub question: String,
+    pub answer: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderedPrompt {
+    pub system: String,
+    pub user: String,
+}
+
+impl PromptTemplate {
+    /// Loads `<dir>/<name>.toml`. The built-in default is used when asked for `default`
+    /// and there is no such file.
+    pub fn load(dir: &Path, name: &str) -> Result<Self> {
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            bail!("Invalid prompt template name: {:?}", name);
+        }
+        let path = dir.join(format!("{}.toml", name));
+        let template = if path.is_file() {
+            let contents = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read prompt template: {}", path.display()))?;
+            Self::from_toml(&contents)
+                .with_context(|| format!("Failed to parse prompt template: {}", path.display()))?
+        } else if name == DEFAULT_TEMPLATE {
+            Self::builtin()
+        } else {
+            bail!("Prompt template not found: {}", path.display());
+        };
+        if template.name != name {
+            bail!(
+                "Prompt template {} is named {:?}, expected {:?}",
+                path.display(),
+                template.name,
+                name
+            );
+        }
+        Ok(template)
+    }
+
+    pub fn builtin() -> Self {
+        Self::from_toml(DEFAULT_TEMPLATE_TOML).expect("built-in prompt template is valid")
+    }
+
+    /// Parses a template and checks that both prompts compile.
+    pub fn from_toml(contents: &str) -> Result<Self> {
+        let template: PromptTemplate = toml::from_str(contents)?;
+        let env = environment();
+        env.template_from_str(&template.system)
+            .context("Invalid system prompt")?;
+        env.template_from_str(&template.user)
+            .context("Invalid user prompt")?;
+        Ok(template)
+    }
+
+    pub fn render(&self, vars: &PromptVars) -> Result<RenderedPrompt> {
+        let env = environment();
+        let render = |source: &str, which: &str| -> Result<String> {
+            let rendered = env.render_str(source, vars).with_context(|| {
+                format!(
+                    "Failed to render {} prompt of template {}",
+                    which, self.name
+                )
+            })?;
+            Ok(rendered.trim().to_string())
+        };
+        Ok(RenderedPrompt {
+            system: render(&self.system, "system")?,
+            user: render(&self.user, "user")?,
+        })
+    }
+}
+
+fn environment() -> Environment<'static> {
+    let mut env = Environment::new();
+    // A typo in a variable name should fail loudly rather than silently render nothing.
+    env.set_undefined_behavior(UndefinedBehavior::Strict);
+    env.set_keep_trailing_newline(true);
+    env
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> PromptVars {
+        PromptVars {
+            question: "How many train cars does each player get?".to_string(),
+            passages: vec![Passage {
+                number: 1,
+                text: "Each player takes a set of 45 Colored Train Cars".to_string(),
+                source: Some("ticket_to_ride.pdf".to_string()),
+                chunk_index: Some(0),
+                heading: Some("Setting up".to_string()),
+                score: 0.9,
+            }],
+            history: vec![],
+            date: "2025-02-20".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_builtin_template() {
+        let template = PromptTemplate::builtin();
+        assert_eq!(template.name, DEFAULT_TEMPLATE);
+
+        let prompt = template.render(&vars()).unwrap();
+        assert!(prompt.system.contains("Today's date is 2025-02-20."));
+        assert!(prompt
+            .user
+            .contains("[1] (ticket_to_ride.pdf #0, Setting up)\nEach player takes"));
+        assert!(prompt
+            .user
+            .ends_with("Question: How many train cars does each player get?"));
+        assert!(!prompt.user.contains("Human:"));
+    }
+
+    #[test]
+    fn test_passage_from_chunk() {
+        let metadata = serde_json::json!({
+            "source": "ticket_to_ride.pdf",
+            "chunk_index": 4,
+            "heading": "Scoring › Longest Route"
+        });
+        let passage = Passage::from_chunk(2, "The bonus is 10 points.", Some(&metadata), 0.7);
+        assert_eq!(passage.number, 2);
+        assert_eq!(passage.source.as_deref(), Some("ticket_to_ride.pdf"));
+        assert_eq!(passage.chunk_index, Some(4));
+        assert_eq!(passage.heading.as_deref(), Some("Scoring › Longest Route"));
+
+        let bare = Passage::from_chunk(1, "text", None, 0.1);
+        assert_eq!(
+            (bare.source, bare.chunk_index, bare.heading),
+            (None, None, None)
+        );
+    }
+
+    #[test]
+    fn test_builtin_template_without_passages() {
+        let mut vars = vars();
+        vars.passages.clear();
+        let prompt = PromptTemplate::builtin().render(&vars).unwrap();
+        assert!(prompt.user.starts_with("No passages were found"));
+    }
+
+    #[test]
+    fn test_custom_template_uses_history() {
+        let template = PromptTemplate::from_toml(
+            r#"
+            name = "terse"
+            version = "3"
+            system = "Be brief."
+            user = "{% for turn in history %}Q: {{ turn.question }} A: {{ turn.answer }}\n{% endfor %}{{ question }}"
+            "#,
+        )
+        .unwrap();
+        let mut vars = vars();
+        vars.history.push(HistoryTurn {
+            question: "Hi".to_string(),
+            answer: "Hello".to_string(),
+        });
+        let prompt = template.render(&vars).unwrap();
+        assert_eq!(prompt.system, "Be brief.");
+        assert_eq!(
+            prompt.user,
+            "Q: Hi A: Hello\nHow many train cars does each player get?"
+        );
+    }
+
+    #[test]
+    fn test_template_errors() {
+        assert!(PromptTemplate::from_toml(
+            "name = \"x\"\nversion = \"1\"\nsystem = \"{% if %}\"\nuser = \"\""
+        )
+        .is_err());
+
+        let typo = PromptTemplate::from_toml(
+            "name = \"x\"\nversion = \"1\"\nsystem = \"\"\nuser = \"{{ questoin }}\"",
+        )
+        .unwrap();
+        assert!(typo.render(&vars()).is_err());
+
+        assert!(PromptTemplate::load(Path::new("/nonexistent"), "missing").is_err());
+        assert!(PromptTemplate::load(Path::new("/nonexistent"), "../etc/passwd").is_err());
+        assert_eq!(
+            PromptTemplate::load(Path::new("/nonexistent"), DEFAULT_TEMPLATE)
+                .unwrap()
+                .version,
+            "2"
+        );
+    }
+}
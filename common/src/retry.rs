@@ -0,0 +1,112 @@
+use crate::config::RetryConfig;
+use std::future::Future;
+use std::time::Duration;
+
+/// Delay before retry number `retry` (the first retry is 1): a random duration between zero and
+/// `base_delay_ms * 2^(retry - 1)`, capped at `max_delay_ms`. Spreading retries out like this
+/// ("full jitter") keeps concurrent callers from all retrying at the same moment.
+pub fn backoff_delay(config: &RetryConfig, retry: u32) -> Duration {
+    let exponent = retry.saturating_sub(1).min(20);
+    let ceiling = config
+        .base_delay_ms
+        .saturating_mul(1 << exponent)
+        .min(config.max_delay_ms);
+    Duration::from_millis(fastrand::u64(0..=ceiling))
+}
+
+/// Runs `op` until it succeeds, fails with an error `is_retryable` rejects, or
+/// `config.max_attempts` attempts have been made, sleeping `backoff_delay` between attempts.
+/// Returns the last result together with the number of attempts made.
+pub async fn retry<T, E, F, Fut, R>(
+    config: &RetryConfig,
+    is_retryable: R,
+    mut op: F,
+) -> (Result<T, E>, u32)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    R: Fn(&E) -> bool,
+{
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match op().await {
+            Err(e) if attempts < config.max_attempts && is_retryable(&e) => {
+                tokio::time::sleep(backoff_delay(config, attempts)).await;
+            }
+            result => return (result, attempts),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn no_delay(max_attempts: u32) -> RetryConfig {
+        RetryConfig {
+            max_attempts,
+            base_delay_ms: 0,
+            max_delay_ms: 0,
+        }
+    }
+
+    #[test]
+    fn test_backoff_delay_is_capped() {
+        let config = RetryConfig {
+            max_attempts: 10,
+            base_delay_ms: 100,
+            max_delay_ms: 1000,
+        };
+        for _ in 0..100 {
+            assert!(backoff_delay(&config, 1) <= Duration::from_millis(100));
+            assert!(backoff_delay(&config, 3) <= Duration::from_millis(400));
+            assert!(backoff_delay(&config, 40) <= Duration::from_millis(1000));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_until_success() {
+        let calls = Cell::new(0);
+        let (result, attempts) = retry(
+            &no_delay(5),
+            |_: &&str| true,
+            || {
+                calls.set(calls.get() + 1);
+                let n = calls.get();
+                async move {
+                    if n < 3 {
+                        Err("throttled")
+                    } else {
+                        Ok(n)
+                    }
+                }
+            },
+        )
+        .await;
+        assert_eq!(result, Ok(3));
+        assert_eq!(attempts, 3);
+    }
+
+    #[tokio::test]
+    async fn test_retry_gives_up() {
+        let (result, attempts) = retry(
+            &no_delay(3),
+            |_: &&str| true,
+            || async { Err::<(), _>("throttled") },
+        )
+        .await;
+        assert_eq!(result, Err("throttled"));
+        assert_eq!(attempts, 3);
+
+        let (result, attempts) = retry(
+            &no_delay(3),
+            |e: &&str| *e == "throttled",
+            || async { Err::<(), _>("access denied") },
+        )
+        .await;
+        assert_eq!(result, Err("access denied"));
+        assert_eq!(attempts, 1);
+    }
+}
@@ -1,11 +1,14 @@
-use anyhow::{Context, Error, Result};
+use crate::config::DatabaseConfig;
+use anyhow::{bail, Context, Error, Result};
 // use aws_config::meta::region::RegionProviderChain;
 use aws_sdk_s3::Client as S3Client;
 use bytes::Bytes;
 // use lambda_http::{Body, Request, Response};
-use rusqlite::Connection; // Result
+use rusqlite::{Connection, OptionalExtension}; // Result
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::env;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::path::Path;
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -19,24 +22,30 @@ pub struct VectorDb {
 
 /// new() will create a new VectorDb instance with a connection to the local SQLite database.
 impl VectorDb {
-    pub async fn new(prefer_local: bool) -> Result<Self, anyhow::Error> {
-        let local_path = String::from("/tmp/embeddings.db");
-        let s3_bucket = env::var("S3_BUCKET_NAME")
-            .map_err(|_| Error::msg("S3_BUCKET_NAME environment variable not set"))?;
-        let s3_key = String::from("embeddings/embeddings.db");
+    pub async fn new(config: &DatabaseConfig, prefer_local: bool) -> Result<Self, anyhow::Error> {
+        let local_path = config.local_path.clone();
+        if config.s3_bucket.is_empty() {
+            return Err(Error::msg(
+                "S3 bucket not configured: set database.s3_bucket or S3_BUCKET_NAME",
+            ));
+        }
+        let s3_bucket = config.s3_bucket.clone();
+        let s3_key = config.s3_key.clone();
 
         let should_download = !prefer_local || !Path::new(&local_path).exists();
 
         if should_download {
-            println!("Downloading embeddings database from S3...");
-            Self::download_from_s3(&local_path, &s3_bucket, &s3_key)
+            tracing::info!("Downloading embeddings database from S3...");
+            let aws_config = aws_config::load_from_env().await;
+            let s3_client = S3Client::new(&aws_config);
+            Self::download_from_s3(&s3_client, &local_path, &s3_bucket, &s3_key, None)
                 .await
                 .context("Failed to download database from S3")?;
         } else {
-            println!("Using existing local database");
+            tracing::info!("Using existing local database");
         }
 
-        println!("Connecting to vector database at: {}", local_path);
+        tracing::info!("Connecting to vector database at: {}", local_path);
         let conn = Connection::open(local_path.clone())?;
         Ok(VectorDb {
             conn,
@@ -46,6 +55,19 @@ impl VectorDb {
         })
     }
 
+    /// Opens (or creates) the database at `config.local_path` without touching S3.
+    pub fn open_local(config: &DatabaseConfig) -> Result<Self> {
+        tracing::debug!("Opening local vector database at: {}", config.local_path);
+        let conn = Connection::open(&config.local_path)
+            .with_context(|| format!("Failed to open database: {}", config.local_path))?;
+        Ok(VectorDb {
+            conn,
+            local_path: config.local_path.clone(),
+            s3_bucket: config.s3_bucket.clone(),
+            s3_key: config.s3_key.clone(),
+        })
+    }
+
     pub async fn push_to_s3(&self) -> Result<()> {
         let config = aws_config::load_from_env().await;
         let s3_client = S3Client::new(&config);
@@ -59,15 +81,18 @@ impl VectorDb {
             .await
             .context("Failed to read file contents")?;
 
-        println!(
+        tracing::info!(
             "Uploading database file {} to S3: {}/{}",
-            self.local_path, self.s3_bucket, self.s3_key
+            self.local_path,
+            self.s3_bucket,
+            self.s3_key
         );
         // Upload to S3
         s3_client
             .put_object()
             .bucket(&self.s3_bucket)
             .key(&self.s3_key)
+            .metadata(SNAPSHOT_METADATA_KEY, self.snapshot_version()?)
             .body(buffer.into())
             .send()
             .await
@@ -76,15 +101,54 @@ impl VectorDb {
         Ok(())
     }
 
-    pub async fn download_from_s3(local_path: &str, s3_bucket: &str, s3_key: &str) -> Result<()> {
-        let config = aws_config::load_from_env().await;
-        let s3_client = S3Client::new(&config);
+    /// The snapshot version of the database published at `database.s3_key`, read with a
+    /// HEAD request so the database itself isn't downloaded. Databases pushed before the
+    /// version was stored with them are identified by their ETag.
+    pub async fn published_version(
+        s3_client: &S3Client,
+        config: &DatabaseConfig,
+    ) -> Result<PublishedSnapshot> {
+        let head = s3_client
+            .head_object()
+            .bucket(&config.s3_bucket)
+            .key(&config.s3_key)
+            .send()
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to look up object in S3: bucket={}, key={}",
+                    config.s3_bucket, config.s3_key
+                )
+            })?;
+        let e_tag = head.e_tag().map(str::to_string);
+        let version = head
+            .metadata()
+            .and_then(|m| m.get(SNAPSHOT_METADATA_KEY))
+            .cloned()
+            .or_else(|| {
+                e_tag
+                    .as_deref()
+                    .map(|tag| format!("etag-{}", tag.trim_matches('"')))
+            })
+            .context("The S3 object has neither a snapshot version nor an ETag")?;
+        Ok(PublishedSnapshot { version, e_tag })
+    }
 
+    /// Downloads the database to `local_path`. With `if_match`, only the object with that
+    /// ETag is accepted, so a database published in the meantime isn't mistaken for it.
+    pub async fn download_from_s3(
+        s3_client: &S3Client,
+        local_path: &str,
+        s3_bucket: &str,
+        s3_key: &str,
+        if_match: Option<&str>,
+    ) -> Result<()> {
         // Get object from S3
         let response = s3_client
             .get_object()
             .bucket(s3_bucket)
             .key(s3_key)
+            .set_if_match(if_match.map(str::to_string))
             .send()
             .await
             .with_context(|| {
@@ -129,7 +193,7 @@ impl VectorDb {
     }
 
     pub fn create_embeddings_table(&self) -> Result<()> {
-        println!("Creating embeddings table if it doesn't exist...");
+        tracing::debug!("Creating embeddings table if it doesn't exist...");
         self.conn
             .execute(
                 "CREATE TABLE IF NOT EXISTS embeddings (
@@ -141,23 +205,152 @@ impl VectorDb {
                 [],
             )
             .map_err(|e| {
-                eprintln!("❌ Failed to create embeddings table: {}", e);
+                tracing::error!("Failed to create embeddings table: {}", e);
                 anyhow::anyhow!("Database error: {}", e)
             })?;
 
-        println!("✅ Successfully created embeddings table");
+        tracing::debug!("Created embeddings table");
+        self.create_journal_table()?;
+        self.create_documents_table()?;
+        self.create_meta_table()
+    }
+
+    /// Key/value facts about the database as a whole, such as `schema_version` and the
+    /// `embeddings_model` its vectors were made with. `database_id` is random and
+    /// `generation` counts the writes to the embeddings table; see `snapshot_version`.
+    fn create_meta_table(&self) -> Result<()> {
+        self.conn.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS ragtime_meta (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            );
+            INSERT OR IGNORE INTO ragtime_meta (key, value) VALUES ('schema_version', '{}');
+            INSERT OR IGNORE INTO ragtime_meta (key, value)
+                VALUES ('database_id', lower(hex(randomblob(8))));
+            INSERT OR IGNORE INTO ragtime_meta (key, value) VALUES ('generation', '0');",
+            SCHEMA_VERSION
+        ))?;
         Ok(())
     }
 
+    pub fn meta(&self, key: &str) -> Result<Option<String>> {
+        let value = self
+            .conn
+            .query_row(
+                "SELECT value FROM ragtime_meta WHERE key = ?1",
+                [key],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(value)
+    }
+
+    pub fn set_meta(&self, key: &str, value: &str) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO ragtime_meta (key, value) VALUES (?1, ?2)",
+            [key, value],
+        )?;
+        Ok(())
+    }
+
+    /// The ingestion journal has one row per document: how many of its chunks have been
+    /// committed, and whether it finished. See `commit_chunks`.
+    fn create_journal_table(&self) -> Result<()> {
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS ingest_journal (
+                source TEXT PRIMARY KEY,
+                fingerprint TEXT NOT NULL,
+                chunk_count INTEGER NOT NULL,
+                committed_chunks INTEGER NOT NULL,
+                status TEXT NOT NULL,
+                error TEXT,
+                updated_at TEXT NOT NULL
+            )",
+            [],
+        )?;
+        Ok(())
+    }
+
+    /// What was read from each document itself: its info dictionary, page count and outline.
+    fn create_documents_table(&self) -> Result<()> {
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS documents (
+                source TEXT PRIMARY KEY,
+                title TEXT,
+                author TEXT,
+                creation_date TEXT,
+                page_count INTEGER NOT NULL,
+                outline TEXT NOT NULL,
+                updated_at TEXT NOT NULL
+            )",
+            [],
+        )?;
+        Ok(())
+    }
+
+    pub fn save_document_metadata(&self, source: &str, metadata: &DocumentMetadata) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO documents
+                (source, title, author, creation_date, page_count, outline, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            rusqlite::params![
+                source,
+                metadata.title,
+                metadata.author,
+                metadata.creation_date,
+                metadata.page_count as i64,
+                serde_json::to_string(&metadata.outline)?,
+                now()
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn document_metadata(&self, source: &str) -> Result<Option<DocumentMetadata>> {
+        let row = self
+            .conn
+            .query_row(
+                "SELECT title, author, creation_date, page_count, outline
+                 FROM documents WHERE source = ?1",
+                [source],
+                |row| {
+                    Ok((
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        row.get::<_, i64>(3)?,
+                        row.get::<_, String>(4)?,
+                    ))
+                },
+            )
+            .optional()?;
+        row.map(|(title, author, creation_date, page_count, outline)| {
+            Ok(DocumentMetadata {
+                title,
+                author,
+                creation_date,
+                page_count: page_count as usize,
+                outline: serde_json::from_str(&outline)
+                    .with_context(|| format!("Invalid outline stored for {}", source))?,
+            })
+        })
+        .transpose()
+    }
+
     pub fn drop_embeddings_table(&self) -> Result<()> {
-        println!("Dropping embeddings table...");
-        match self.conn.execute("DROP TABLE IF EXISTS embeddings", []) {
+        tracing::info!("Dropping embeddings table...");
+        match self.conn.execute_batch(
+            "DROP TABLE IF EXISTS embeddings;
+                 DROP TABLE IF EXISTS ingest_journal;
+                 DROP TABLE IF EXISTS documents;
+                 DROP TABLE IF EXISTS ragtime_meta;",
+        ) {
             Ok(_) => {
-                println!("✅ Successfully dropped embeddings table");
+                tracing::info!("Dropped embeddings table");
                 Ok(())
             }
             Err(e) => {
-                eprintln!("❌ Failed to drop embeddings table: {}", e);
+                tracing::error!("Failed to drop embeddings table: {}", e);
                 Err(e.into())
             }
         }
@@ -170,67 +363,909 @@ impl VectorDb {
         Ok(count)
     }
 
+    /// What the database holds, for spotting broken ingestions such as a document that
+    /// extracted to no text, or chunks embedded with different models.
+    pub fn stats(&self) -> Result<DatabaseStats> {
+        let (chunks, characters, empty_chunks): (i64, i64, i64) = self.conn.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(LENGTH(text)), 0),
+                    COALESCE(SUM(LENGTH(TRIM(text)) = 0), 0)
+             FROM embeddings",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+
+        let mut stmt = self.conn.prepare(
+            "SELECT json_extract(metadata, '$.source'), COUNT(*), SUM(LENGTH(text))
+             FROM embeddings GROUP BY 1",
+        )?;
+        let mut documents: BTreeMap<Option<String>, DocumentStats> = BTreeMap::new();
+        for row in stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, Option<String>>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, i64>(2)?,
+            ))
+        })? {
+            let (source, chunks, characters) = row?;
+            documents.insert(
+                source.clone(),
+                DocumentStats {
+                    source,
+                    status: None,
+                    chunks: chunks as usize,
+                    characters: characters as usize,
+                },
+            );
+        }
+        // Documents that produced no chunks are only in the journal.
+        for document in self.list_documents()? {
+            documents
+                .entry(Some(document.source.clone()))
+                .or_insert_with(|| DocumentStats {
+                    source: Some(document.source),
+                    status: None,
+                    chunks: 0,
+                    characters: 0,
+                })
+                .status = Some(document.status);
+        }
+
+        let dimensions = self.dimensions()?;
+
+        let duplicate_chunks: i64 = self.conn.query_row(
+            "SELECT COALESCE(SUM(copies - 1), 0)
+             FROM (SELECT COUNT(*) AS copies FROM embeddings GROUP BY text HAVING copies > 1)",
+            [],
+            |row| row.get(0),
+        )?;
+
+        let mut chunk_length_histogram: Vec<HistogramBucket> = CHUNK_LENGTH_BUCKETS
+            .iter()
+            .enumerate()
+            .map(|(i, &min)| HistogramBucket {
+                min,
+                max: CHUNK_LENGTH_BUCKETS.get(i + 1).copied(),
+                chunks: 0,
+            })
+            .collect();
+        let mut stmt = self.conn.prepare("SELECT LENGTH(text) FROM embeddings")?;
+        for length in stmt.query_map([], |row| row.get::<_, i64>(0))? {
+            let length = length? as usize;
+            if let Some(bucket) = chunk_length_histogram
+                .iter_mut()
+                .rev()
+                .find(|b| b.min <= length)
+            {
+                bucket.chunks += 1;
+            }
+        }
+
+        let mut stmt = self.conn.prepare(
+            "SELECT name FROM sqlite_master
+             WHERE type = 'index' AND tbl_name = 'embeddings' ORDER BY name",
+        )?;
+        let indexes = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+
+        Ok(DatabaseStats {
+            schema_version: self.meta("schema_version")?,
+            embeddings_model: self.meta("embeddings_model")?,
+            file_bytes: std::fs::metadata(&self.local_path)
+                .map(|m| m.len())
+                .unwrap_or(0),
+            chunks: chunks as usize,
+            characters: characters as usize,
+            average_chunk_characters: if chunks > 0 {
+                characters as f64 / chunks as f64
+            } else {
+                0.0
+            },
+            empty_chunks: empty_chunks as usize,
+            duplicate_chunks: duplicate_chunks as usize,
+            dimensions,
+            chunk_length_histogram,
+            indexes,
+            documents: documents.into_values().collect(),
+        })
+    }
+
+    /// The distinct lengths of the stored vectors, smallest first.
+    pub fn dimensions(&self) -> Result<Vec<usize>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT DISTINCT LENGTH(embedding) / 4 FROM embeddings ORDER BY 1")?;
+        let dimensions = stmt
+            .query_map([], |row| Ok(row.get::<_, i64>(0)? as usize))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(dimensions)
+    }
+
+    /// Identifies the current contents of the embeddings table, so answers cached against an
+    /// older corpus are not served after it changes. Every write to the table, text or
+    /// metadata, bumps the generation; the database id keeps a rebuilt database from reusing
+    /// an old version.
+    pub fn snapshot_version(&self) -> Result<String> {
+        let database_id = self
+            .meta("database_id")?
+            .context("The database has no database_id; create the tables first")?;
+        let generation = self.meta("generation")?.unwrap_or_else(|| "0".to_string());
+        Ok(format!("{}-{}", database_id, generation))
+    }
+
     pub fn insert_embedding(
         &self,
         text: &str,
         embedding: &[f32],
         metadata: Option<&Value>,
     ) -> Result<i64> {
-        let embedding_bytes: Vec<u8> = unsafe {
-            std::slice::from_raw_parts(
-                embedding.as_ptr() as *const u8,
-                embedding.len() * std::mem::size_of::<f32>(),
-            )
-            .to_vec()
-        };
-
         let metadata_str = metadata.map(|m| m.to_string());
         self.conn.execute(
             "INSERT INTO embeddings (text, embedding, metadata) VALUES (?1, ?2, ?3)",
-            rusqlite::params![text, embedding_bytes, metadata_str],
+            rusqlite::params![text, embedding_bytes(embedding), metadata_str],
         )?;
+        let id = self.conn.last_insert_rowid();
+        bump_generation(&self.conn)?;
+        Ok(id)
+    }
 
-        Ok(self.conn.last_insert_rowid())
+    /// The journal entry for `source`, if it has been ingested before.
+    pub fn document_progress(&self, source: &str) -> Result<Option<DocumentProgress>> {
+        let progress = self
+            .conn
+            .query_row(
+                "SELECT source, fingerprint, chunk_count, committed_chunks, status
+                 FROM ingest_journal WHERE source = ?1",
+                [source],
+                |row| {
+                    Ok(DocumentProgress {
+                        source: row.get(0)?,
+                        fingerprint: row.get(1)?,
+                        chunk_count: row.get::<_, i64>(2)? as usize,
+                        committed_chunks: row.get::<_, i64>(3)? as usize,
+                        status: DocumentStatus::parse(&row.get::<_, String>(4)?),
+                    })
+                },
+            )
+            .optional()?;
+        Ok(progress)
+    }
+
+    /// Starts (or restarts) `source` from its first chunk, removing any chunks stored for it
+    /// by an earlier run.
+    pub fn start_document(
+        &self,
+        source: &str,
+        fingerprint: &str,
+        chunk_count: usize,
+    ) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        delete_chunks(&tx, source)?;
+        tx.execute(
+            "INSERT OR REPLACE INTO ingest_journal
+                (source, fingerprint, chunk_count, committed_chunks, status, error, updated_at)
+             VALUES (?1, ?2, ?3, 0, ?4, NULL, ?5)",
+            rusqlite::params![
+                source,
+                fingerprint,
+                chunk_count as i64,
+                DocumentStatus::InProgress.as_str(),
+                now()
+            ],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Inserts a batch of `source`'s chunks and records that its first `committed_chunks`
+    /// chunks are stored, in one transaction, so the journal always matches the table.
+    pub fn commit_chunks(
+        &self,
+        source: &str,
+        rows: &[NewEmbedding],
+        committed_chunks: usize,
+    ) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        insert_rows(&tx, rows)?;
+        tx.execute(
+            "UPDATE ingest_journal SET committed_chunks = ?2, updated_at = ?3 WHERE source = ?1",
+            rusqlite::params![source, committed_chunks as i64, now()],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn finish_document(&self, source: &str) -> Result<()> {
+        self.set_document_status(source, DocumentStatus::Done, None)
+    }
+
+    pub fn fail_document(&self, source: &str, error: &str) -> Result<()> {
+        self.set_document_status(source, DocumentStatus::Failed, Some(error))
+    }
+
+    fn set_document_status(
+        &self,
+        source: &str,
+        status: DocumentStatus,
+        error: Option<&str>,
+    ) -> Result<()> {
+        self.conn.execute(
+            "UPDATE ingest_journal SET status = ?2, error = ?3, updated_at = ?4 WHERE source = ?1",
+            rusqlite::params![source, status.as_str(), error, now()],
+        )?;
+        Ok(())
+    }
+
+    /// Every document in the journal, with the number of chunks actually stored for it and
+    /// the title and page count read from it.
+    pub fn list_documents(&self) -> Result<Vec<DocumentInfo>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT j.source, j.status, j.chunk_count, j.error, j.updated_at,
+                    (SELECT COUNT(*) FROM embeddings e
+                     WHERE json_extract(e.metadata, '$.source') = j.source),
+                    d.title, d.page_count
+             FROM ingest_journal j LEFT JOIN documents d ON d.source = j.source
+             ORDER BY j.source",
+        )?;
+        let documents = stmt
+            .query_map([], |row| {
+                Ok(DocumentInfo {
+                    source: row.get(0)?,
+                    status: DocumentStatus::parse(&row.get::<_, String>(1)?),
+                    chunk_count: row.get::<_, i64>(2)? as usize,
+                    error: row.get(3)?,
+                    updated_at: row.get(4)?,
+                    chunks_stored: row.get::<_, i64>(5)? as usize,
+                    title: row.get(6)?,
+                    page_count: row.get::<_, Option<i64>>(7)?.map(|p| p as usize),
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(documents)
+    }
+
+    /// Removes `source`'s chunks, its journal entry and its metadata. Returns the number of
+    /// chunks removed.
+    pub fn delete_document(&self, source: &str) -> Result<usize> {
+        let tx = self.conn.unchecked_transaction()?;
+        let deleted = delete_chunks(&tx, source)?;
+        tx.execute("DELETE FROM ingest_journal WHERE source = ?1", [source])?;
+        tx.execute("DELETE FROM documents WHERE source = ?1", [source])?;
+        tx.commit()?;
+        Ok(deleted)
+    }
+
+    /// Swaps `source`'s chunks for `rows` in one transaction and records the document as done,
+    /// so searches see either the old chunks or the new ones, never a mix.
+    /// Returns the ids of the new chunks.
+    pub fn replace_document(
+        &self,
+        source: &str,
+        fingerprint: &str,
+        rows: &[NewEmbedding],
+    ) -> Result<Vec<i64>> {
+        let tx = self.conn.unchecked_transaction()?;
+        delete_chunks(&tx, source)?;
+        let ids = insert_rows(&tx, rows)?;
+        tx.execute(
+            "INSERT OR REPLACE INTO ingest_journal
+                (source, fingerprint, chunk_count, committed_chunks, status, error, updated_at)
+             VALUES (?1, ?2, ?3, ?3, ?4, NULL, ?5)",
+            rusqlite::params![
+                source,
+                fingerprint,
+                rows.len() as i64,
+                DocumentStatus::Done.as_str(),
+                now()
+            ],
+        )?;
+        tx.commit()?;
+        Ok(ids)
+    }
+
+    /// Removes every chunk whose metadata contains all of `filters`, with the same matching
+    /// as `search`. Journal entries are adjusted to the chunks that remain, and dropped for
+    /// documents left with none. Returns the number of chunks removed.
+    pub fn delete_matching(&self, filters: &HashMap<String, Value>) -> Result<usize> {
+        if filters.is_empty() {
+            bail!("Refusing to delete without a filter; drop the table to remove everything");
+        }
+        let tx = self.conn.unchecked_transaction()?;
+        let mut matching: Vec<(i64, Option<String>)> = Vec::new();
+        {
+            let mut stmt = tx.prepare("SELECT id, metadata FROM embeddings")?;
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let metadata: Option<Value> = row
+                    .get::<_, Option<String>>(1)?
+                    .and_then(|m| serde_json::from_str(&m).ok());
+                if matches_filters(metadata.as_ref(), filters) {
+                    let source = metadata
+                        .as_ref()
+                        .and_then(|m| m.get("source"))
+                        .and_then(|s| s.as_str())
+                        .map(str::to_string);
+                    matching.push((row.get(0)?, source));
+                }
+            }
+        }
+
+        let mut sources = BTreeSet::new();
+        if !matching.is_empty() {
+            bump_generation(&tx)?;
+        }
+        let ids: Vec<i64> = matching.iter().map(|(id, _)| *id).collect();
+        promote_duplicates(&tx, &ids)?;
+        for (id, source) in &matching {
+            tx.execute("DELETE FROM embeddings WHERE id = ?1", [id])?;
+            if let Some(source) = source {
+                sources.insert(source.as_str());
+            }
+        }
+        for source in sources {
+            let remaining: i64 = tx.query_row(
+                "SELECT COUNT(*) FROM embeddings WHERE json_extract(metadata, '$.source') = ?1",
+                [source],
+                |row| row.get(0),
+            )?;
+            if remaining == 0 {
+                tx.execute("DELETE FROM ingest_journal WHERE source = ?1", [source])?;
+                tx.execute("DELETE FROM documents WHERE source = ?1", [source])?;
+                unlink_duplicates(&tx, source)?;
+            } else {
+                tx.execute(
+                    "UPDATE ingest_journal
+                     SET chunk_count = ?2, committed_chunks = ?2, updated_at = ?3
+                     WHERE source = ?1",
+                    rusqlite::params![source, remaining, now()],
+                )?;
+            }
+        }
+        tx.commit()?;
+        Ok(matching.len())
+    }
+
+    /// Sets the keys in `set` and removes the keys in `unset` from chunk `id`'s metadata,
+    /// leaving its text and embedding alone. Returns the new metadata.
+    pub fn update_metadata(
+        &self,
+        id: i64,
+        set: &serde_json::Map<String, Value>,
+        unset: &[String],
+    ) -> Result<Value> {
+        let tx = self.conn.unchecked_transaction()?;
+        let metadata: Option<String> = tx
+            .query_row(
+                "SELECT metadata FROM embeddings WHERE id = ?1",
+                [id],
+                |row| row.get(0),
+            )
+            .optional()?
+            .with_context(|| format!("No chunk with id {}", id))?;
+        let mut metadata = match metadata.and_then(|m| serde_json::from_str(&m).ok()) {
+            Some(Value::Object(map)) => map,
+            _ => serde_json::Map::new(),
+        };
+        for key in unset {
+            metadata.remove(key);
+        }
+        for (key, value) in set {
+            metadata.insert(key.clone(), value.clone());
+        }
+        let metadata = Value::Object(metadata);
+        tx.execute(
+            "UPDATE embeddings SET metadata = ?2 WHERE id = ?1",
+            rusqlite::params![id, metadata.to_string()],
+        )?;
+        bump_generation(&tx)?;
+        tx.commit()?;
+        Ok(metadata)
+    }
+
+    /// Adds `duplicates` to the `duplicates` list in the metadata of `source`'s chunk
+    /// `chunk_index`, skipping entries already listed. Returns false if there is no such chunk.
+    pub fn link_duplicates(
+        &self,
+        source: &str,
+        chunk_index: usize,
+        duplicates: &[Value],
+    ) -> Result<bool> {
+        let tx = self.conn.unchecked_transaction()?;
+        let row: Option<(i64, Option<String>)> = tx
+            .query_row(
+                "SELECT id, metadata FROM embeddings
+                 WHERE json_extract(metadata, '$.source') = ?1
+                   AND json_extract(metadata, '$.chunk_index') = ?2
+                 ORDER BY id LIMIT 1",
+                rusqlite::params![source, chunk_index as i64],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        let Some((id, metadata)) = row else {
+            return Ok(false);
+        };
+        let mut metadata = match metadata.and_then(|m| serde_json::from_str(&m).ok()) {
+            Some(Value::Object(map)) => map,
+            _ => serde_json::Map::new(),
+        };
+        let mut linked = match metadata.remove("duplicates") {
+            Some(Value::Array(linked)) => linked,
+            _ => Vec::new(),
+        };
+        for duplicate in duplicates {
+            if !linked.contains(duplicate) {
+                linked.push(duplicate.clone());
+            }
+        }
+        metadata.insert("duplicates".to_string(), Value::Array(linked));
+        tx.execute(
+            "UPDATE embeddings SET metadata = ?2 WHERE id = ?1",
+            rusqlite::params![id, Value::Object(metadata).to_string()],
+        )?;
+        bump_generation(&tx)?;
+        tx.commit()?;
+        Ok(true)
+    }
+
+    /// Calls `f` with every stored chunk, in id order.
+    pub fn for_each_embedding<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(StoredEmbedding) -> Result<()>,
+    {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT id, text, embedding, metadata FROM embeddings ORDER BY id")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let metadata: Option<String> = row.get(3)?;
+            f(StoredEmbedding {
+                id: row.get(0)?,
+                text: row.get(1)?,
+                embedding: embedding_from_bytes(&row.get::<_, Vec<u8>>(2)?),
+                metadata: metadata.and_then(|m| serde_json::from_str(&m).ok()),
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Checks the database before it is published. Returns a description of each problem
+    /// found; an empty list means it looks consistent.
+    pub fn verify(&self) -> Result<Vec<String>> {
+        let mut problems = Vec::new();
+
+        let integrity: String = self
+            .conn
+            .query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+        if integrity != "ok" {
+            problems.push(format!("SQLite integrity check failed: {}", integrity));
+        }
+
+        let mut stmt = self.conn.prepare(
+            "SELECT LENGTH(embedding), COUNT(*) FROM embeddings GROUP BY 1 ORDER BY 2 DESC",
+        )?;
+        let sizes = stmt
+            .query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        if sizes.len() > 1 {
+            let sizes: Vec<String> = sizes
+                .iter()
+                .map(|(bytes, count)| format!("{} with {} bytes", count, bytes))
+                .collect();
+            problems.push(format!(
+                "Embeddings have different sizes: {}",
+                sizes.join(", ")
+            ));
+        }
+        if sizes.iter().any(|(bytes, _)| *bytes == 0 || bytes % 4 != 0) {
+            problems.push("Some embeddings are empty or not a whole number of f32s".to_string());
+        }
+
+        for document in self.list_documents()? {
+            match document.status {
+                DocumentStatus::Done if document.chunks_stored != document.chunk_count => problems
+                    .push(format!(
+                        "{} should have {} chunks but has {}",
+                        document.source, document.chunk_count, document.chunks_stored
+                    )),
+                DocumentStatus::Done => {}
+                status => problems.push(format!(
+                    "{} is {} ({}/{} chunks stored)",
+                    document.source,
+                    status.as_str(),
+                    document.chunks_stored,
+                    document.chunk_count
+                )),
+            }
+        }
+        Ok(problems)
+    }
+
+    /// Inserts `rows` in one transaction, so a batch is stored completely or not at all.
+    /// Returns the new ids in the same order.
+    pub fn insert_embeddings(&self, rows: &[NewEmbedding]) -> Result<Vec<i64>> {
+        let tx = self.conn.unchecked_transaction()?;
+        let ids = insert_rows(&tx, rows)?;
+        tx.commit()?;
+        Ok(ids)
     }
 
     pub fn search_similar(&self, query_embedding: &[f32], limit: usize) -> Result<Vec<String>> {
+        let hits = self.search(query_embedding, limit, &HashMap::new())?;
+
+        // Return only the strings
+        Ok(hits.into_iter().map(|hit| hit.text).collect())
+    }
+
+    /// Returns the `limit` chunks most similar to `query_embedding`, best first.
+    /// Only chunks whose metadata contains every key/value pair in `filters` are considered.
+    pub fn search(
+        &self,
+        query_embedding: &[f32],
+        limit: usize,
+        filters: &HashMap<String, Value>,
+    ) -> Result<Vec<SearchHit>> {
         let mut stmt = self
             .conn
-            .prepare("SELECT text, embedding FROM embeddings")?;
+            .prepare("SELECT id, text, embedding, metadata FROM embeddings")?;
 
-        let mut results: Vec<(String, f32)> = Vec::new();
+        let mut results: Vec<SearchHit> = Vec::new();
 
         let rows = stmt.query_map([], |row| {
-            let text: String = row.get(0)?;
-            let embedding_bytes: Vec<u8> = row.get(1)?;
+            let id: i64 = row.get(0)?;
+            let text: String = row.get(1)?;
+            let embedding_bytes: Vec<u8> = row.get(2)?;
+            let metadata: Option<String> = row.get(3)?;
 
-            let embedding: Vec<f32> = unsafe {
-                std::slice::from_raw_parts(
-                    embedding_bytes.as_ptr() as *const f32,
-                    embedding_bytes.len() / std::mem::size_of::<f32>(),
-                )
-                .to_vec()
-            };
-
-            Ok((text, embedding))
+            Ok((id, text, embedding_from_bytes(&embedding_bytes), metadata))
         })?;
 
-        for row in rows {
-            if let Ok((text, embedding)) = row {
-                let similarity = cosine_similarity(query_embedding, &embedding);
-                results.push((text, similarity));
+        for (id, text, embedding, metadata) in rows.flatten() {
+            let metadata: Option<Value> = metadata.and_then(|m| serde_json::from_str(&m).ok());
+            if !matches_filters(metadata.as_ref(), filters) {
+                continue;
             }
+            let score = cosine_similarity(query_embedding, &embedding);
+            results.push(SearchHit {
+                id,
+                text,
+                score,
+                metadata,
+            });
         }
 
         // Sort by similarity (highest first)
-        results.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+        results.sort_by(|a, b| b.score.total_cmp(&a.score));
         results.truncate(limit);
 
-        // Return only the strings
-        Ok(results.into_iter().map(|(text, _)| text).collect())
+        Ok(results)
     }
 } // end of VectorDb impl
 
+/// A chunk and its embedding, ready for `VectorDb::insert_embeddings`.
+#[derive(Debug, Clone)]
+pub struct NewEmbedding {
+    pub text: String,
+    pub embedding: Vec<f32>,
+    pub metadata: Option<Value>,
+}
+
+fn insert_rows(conn: &Connection, rows: &[NewEmbedding]) -> Result<Vec<i64>> {
+    let mut stmt = conn
+        .prepare_cached("INSERT INTO embeddings (text, embedding, metadata) VALUES (?1, ?2, ?3)")?;
+    let mut ids = Vec::with_capacity(rows.len());
+    for row in rows {
+        stmt.execute(rusqlite::params![
+            row.text,
+            embedding_bytes(&row.embedding),
+            row.metadata.as_ref().map(|m| m.to_string())
+        ])?;
+        ids.push(conn.last_insert_rowid());
+    }
+    if !rows.is_empty() {
+        bump_generation(conn)?;
+    }
+    Ok(ids)
+}
+
+/// Deletes `source`'s chunks. Repeats other documents linked to them are stored in their
+/// place, and `source`'s own repeats are unlinked from other documents' chunks.
+fn delete_chunks(conn: &Connection, source: &str) -> Result<usize> {
+    let ids = conn
+        .prepare("SELECT id FROM embeddings WHERE json_extract(metadata, '$.source') = ?1")?
+        .query_map([source], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<i64>>>()?;
+    promote_duplicates(conn, &ids)?;
+    let deleted = conn.execute(
+        "DELETE FROM embeddings WHERE json_extract(metadata, '$.source') = ?1",
+        [source],
+    )?;
+    let unlinked = unlink_duplicates(conn, source)?;
+    if deleted > 0 || unlinked {
+        bump_generation(conn)?;
+    }
+    Ok(deleted)
+}
+
+/// Before the chunks `ids` are deleted, stores a copy of each under the first repeat from
+/// another document in its `duplicates`, which lists the rest, so text that other documents
+/// share with it stays searchable under their source. The copy has the deleted chunk's text
+/// and embedding, which for a near-duplicate are close to, not exactly, what it says.
+fn promote_duplicates(conn: &Connection, ids: &[i64]) -> Result<()> {
+    for id in ids {
+        let (text, embedding, metadata): (String, Vec<u8>, Option<String>) = conn.query_row(
+            "SELECT text, embedding, metadata FROM embeddings WHERE id = ?1",
+            [id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+        let Some(metadata) = metadata.and_then(|m| serde_json::from_str::<Value>(&m).ok()) else {
+            continue;
+        };
+        let Some(Value::Array(duplicates)) = metadata.get("duplicates") else {
+            continue;
+        };
+        let mut others = duplicates
+            .iter()
+            .filter(|d| d.get("source") != metadata.get("source"))
+            .cloned();
+        let Some(first) = others.next() else {
+            continue;
+        };
+        let mut promoted = serde_json::Map::new();
+        for key in ["source", "chunk_index"] {
+            if let Some(value) = first.get(key) {
+                promoted.insert(key.to_string(), value.clone());
+            }
+        }
+        let rest: Vec<Value> = others.collect();
+        if !rest.is_empty() {
+            promoted.insert("duplicates".to_string(), Value::Array(rest));
+        }
+        conn.execute(
+            "INSERT INTO embeddings (text, embedding, metadata) VALUES (?1, ?2, ?3)",
+            rusqlite::params![text, embedding, Value::Object(promoted).to_string()],
+        )?;
+        conn.execute(
+            "UPDATE ingest_journal
+             SET chunk_count = chunk_count + 1, committed_chunks = committed_chunks + 1,
+                 updated_at = ?2
+             WHERE source = ?1",
+            rusqlite::params![first.get("source").and_then(Value::as_str), now()],
+        )?;
+    }
+    Ok(())
+}
+
+/// Removes `source`'s repeats from the `duplicates` lists of the remaining chunks, once its
+/// chunks are gone. Returns whether any list changed.
+fn unlink_duplicates(conn: &Connection, source: &str) -> Result<bool> {
+    let linked = conn
+        .prepare(
+            "SELECT id, metadata FROM embeddings
+             WHERE json_extract(metadata, '$.duplicates') IS NOT NULL",
+        )?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<Vec<(i64, String)>>>()?;
+    let mut changed = false;
+    for (id, metadata) in linked {
+        let Ok(Value::Object(mut metadata)) = serde_json::from_str(&metadata) else {
+            continue;
+        };
+        let Some(Value::Array(duplicates)) = metadata.get_mut("duplicates") else {
+            continue;
+        };
+        let before = duplicates.len();
+        duplicates.retain(|d| d.get("source").and_then(Value::as_str) != Some(source));
+        if duplicates.len() == before {
+            continue;
+        }
+        if duplicates.is_empty() {
+            metadata.remove("duplicates");
+        }
+        conn.execute(
+            "UPDATE embeddings SET metadata = ?2 WHERE id = ?1",
+            rusqlite::params![id, Value::Object(metadata).to_string()],
+        )?;
+        changed = true;
+    }
+    Ok(changed)
+}
+
+/// Records a change to the embeddings table. Called in the same transaction as the change.
+fn bump_generation(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "UPDATE ragtime_meta SET value = CAST(value AS INTEGER) + 1 WHERE key = 'generation'",
+        [],
+    )?;
+    Ok(())
+}
+
+fn now() -> String {
+    chrono::Utc::now().to_rfc3339()
+}
+
+/// Identifies a document's chunks, so a resumed run can tell whether the document changed
+/// (or was chunked differently) since its progress was recorded.
+pub fn document_fingerprint<S: AsRef<str>>(chunks: &[S]) -> String {
+    let mut hasher = Sha256::new();
+    for chunk in chunks {
+        hasher.update(chunk.as_ref().as_bytes());
+        hasher.update([0]);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DocumentStatus {
+    InProgress,
+    Done,
+    Failed,
+}
+
+impl DocumentStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DocumentStatus::InProgress => "in_progress",
+            DocumentStatus::Done => "done",
+            DocumentStatus::Failed => "failed",
+        }
+    }
+
+    fn parse(status: &str) -> Self {
+        match status {
+            "done" => DocumentStatus::Done,
+            "failed" => DocumentStatus::Failed,
+            _ => DocumentStatus::InProgress,
+        }
+    }
+}
+
+/// A document's row in the ingestion journal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DocumentProgress {
+    pub source: String,
+    pub fingerprint: String,
+    pub chunk_count: usize,
+    /// Chunks `0..committed_chunks` are stored.
+    pub committed_chunks: usize,
+    pub status: DocumentStatus,
+}
+
+/// Version of the table layout, stored in `ragtime_meta` when the tables are created.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// The database published in S3, see `VectorDb::published_version`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublishedSnapshot {
+    /// The `snapshot_version` it was pushed with.
+    pub version: String,
+    pub e_tag: Option<String>,
+}
+
+/// S3 object metadata holding the `snapshot_version` of a pushed database.
+const SNAPSHOT_METADATA_KEY: &str = "ragtime-snapshot";
+
+/// Lower bounds, in characters, of the buckets in `DatabaseStats::chunk_length_histogram`.
+const CHUNK_LENGTH_BUCKETS: [usize; 7] = [0, 50, 100, 200, 400, 800, 1600];
+
+/// See `VectorDb::stats`.
+#[derive(Debug, Clone, Serialize)]
+pub struct DatabaseStats {
+    pub schema_version: Option<String>,
+    /// Model recorded by the last ingestion; `None` for databases built before it was recorded.
+    pub embeddings_model: Option<String>,
+    pub file_bytes: u64,
+    pub chunks: usize,
+    pub characters: usize,
+    pub average_chunk_characters: f64,
+    /// Chunks with nothing but whitespace.
+    pub empty_chunks: usize,
+    /// Chunks whose text is identical to an earlier chunk's.
+    pub duplicate_chunks: usize,
+    /// Distinct vector dimensions; more than one means different models were mixed.
+    pub dimensions: Vec<usize>,
+    pub chunk_length_histogram: Vec<HistogramBucket>,
+    /// Indexes on the embeddings table. Searches scan every row either way.
+    pub indexes: Vec<String>,
+    pub documents: Vec<DocumentStats>,
+}
+
+/// Chunks with at least `min` and less than `max` characters.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HistogramBucket {
+    pub min: usize,
+    pub max: Option<usize>,
+    pub chunks: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DocumentStats {
+    /// `None` for chunks without a source in their metadata.
+    pub source: Option<String>,
+    /// `None` for documents that aren't in the ingestion journal, e.g. imported ones.
+    pub status: Option<DocumentStatus>,
+    pub chunks: usize,
+    pub characters: usize,
+}
+
+/// A document as listed by `VectorDb::list_documents`.
+#[derive(Debug, Clone, Serialize)]
+pub struct DocumentInfo {
+    pub source: String,
+    pub status: DocumentStatus,
+    /// Chunks the document was split into.
+    pub chunk_count: usize,
+    pub chunks_stored: usize,
+    pub error: Option<String>,
+    pub updated_at: String,
+    /// From the document's metadata, if it was recorded.
+    pub title: Option<String>,
+    pub page_count: Option<usize>,
+}
+
+/// What a document says about itself, stored in the `documents` table.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DocumentMetadata {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    /// RFC 3339 when the document's date could be read, without an offset if it had none.
+    pub creation_date: Option<String>,
+    pub page_count: usize,
+    /// Bookmarks in document order.
+    pub outline: Vec<OutlineEntry>,
+}
+
+/// A bookmark. Top-level entries have `level` 1.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutlineEntry {
+    pub level: usize,
+    pub title: String,
+    pub page: usize,
+}
+
+/// A row of the embeddings table, see `VectorDb::for_each_embedding`.
+#[derive(Debug, Clone)]
+pub struct StoredEmbedding {
+    pub id: i64,
+    pub text: String,
+    pub embedding: Vec<f32>,
+    pub metadata: Option<Value>,
+}
+
+/// Embeddings are stored as little-endian `f32`s, so databases built on one machine read the
+/// same everywhere.
+pub(crate) fn embedding_from_bytes(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+pub(crate) fn embedding_bytes(embedding: &[f32]) -> Vec<u8> {
+    embedding.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+/// A chunk returned by `VectorDb::search`.
+#[derive(Debug, Clone)]
+pub struct SearchHit {
+    pub id: i64,
+    pub text: String,
+    pub score: f32,
+    pub metadata: Option<Value>,
+}
+
+fn matches_filters(metadata: Option<&Value>, filters: &HashMap<String, Value>) -> bool {
+    if filters.is_empty() {
+        return true;
+    }
+    match metadata {
+        Some(metadata) => filters
+            .iter()
+            .all(|(key, expected)| metadata.get(key) == Some(expected)),
+        None => false,
+    }
+}
+
 pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
     let dot_product: f32 = a.iter().zip(b.iter()).map(|(x, y)| x * y).sum();
     let norm_a: f32 = a.iter().map(|x| x * x).sum::<f32>().sqrt();
@@ -241,12 +1276,399 @@ pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
 #[cfg(test)]
 mod tests {
     use super::*; // Import everything from the parent module
+    use serde_json::json;
+
+    fn in_memory_db() -> VectorDb {
+        VectorDb {
+            conn: Connection::open_in_memory().unwrap(),
+            local_path: String::new(),
+            s3_bucket: String::new(),
+            s3_key: String::new(),
+        }
+    }
 
     #[test]
     fn test_basic_functionality() {
-        //let use_local_db = true;
-        //let vdb_client = VectorDb::new(use_local_db).await?;
-        //vdb_client.search_similar(question_embeddings, 5);
-        // assert_eq!(2 + 2, 4);
+        let vdb = in_memory_db();
+        vdb.create_embeddings_table().unwrap();
+        vdb.insert_embedding("north", &[1.0, 0.0], None).unwrap();
+        vdb.insert_embedding("east", &[0.0, 1.0], None).unwrap();
+        vdb.insert_embedding("north-east", &[0.7, 0.7], None)
+            .unwrap();
+        assert_eq!(vdb.count_embeddings().unwrap(), 3);
+        let version = vdb.snapshot_version().unwrap();
+        assert!(version.ends_with("-3"));
+
+        let similar = vdb.search_similar(&[1.0, 0.1], 2).unwrap();
+        assert_eq!(similar, vec!["north", "north-east"]);
+
+        vdb.insert_embedding("south", &[-1.0, 0.0], None).unwrap();
+        assert_ne!(vdb.snapshot_version().unwrap(), version);
+    }
+
+    #[test]
+    fn test_search_with_filters() {
+        let vdb = in_memory_db();
+        vdb.create_embeddings_table().unwrap();
+        let monopoly = json!({"source": "monopoly.pdf"});
+        let ticket = json!({"source": "ticket_to_ride.pdf"});
+        vdb.insert_embedding("a", &[1.0, 0.0], Some(&monopoly))
+            .unwrap();
+        vdb.insert_embedding("b", &[0.9, 0.1], Some(&ticket))
+            .unwrap();
+        vdb.insert_embedding("c", &[1.0, 0.0], None).unwrap();
+
+        let filters = HashMap::from([("source".to_string(), json!("ticket_to_ride.pdf"))]);
+        let hits = vdb.search(&[1.0, 0.0], 5, &filters).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].text, "b");
+        assert_eq!(hits[0].metadata, Some(ticket));
+    }
+
+    fn chunk(source: &str, chunk_index: usize) -> NewEmbedding {
+        NewEmbedding {
+            text: format!("{} chunk {}", source, chunk_index),
+            embedding: vec![1.0, chunk_index as f32],
+            metadata: Some(json!({"source": source, "chunk_index": chunk_index})),
+        }
+    }
+
+    #[test]
+    fn test_ingest_journal() {
+        let vdb = in_memory_db();
+        vdb.create_embeddings_table().unwrap();
+        assert_eq!(vdb.document_progress("a.pdf").unwrap(), None);
+
+        vdb.start_document("a.pdf", "fp1", 3).unwrap();
+        vdb.commit_chunks("a.pdf", &[chunk("a.pdf", 0), chunk("a.pdf", 1)], 2)
+            .unwrap();
+        vdb.fail_document("a.pdf", "ThrottlingException").unwrap();
+        let progress = vdb.document_progress("a.pdf").unwrap().unwrap();
+        assert_eq!(progress.committed_chunks, 2);
+        assert_eq!(progress.chunk_count, 3);
+        assert_eq!(progress.status, DocumentStatus::Failed);
+
+        // Resuming only adds the missing chunk.
+        vdb.commit_chunks("a.pdf", &[chunk("a.pdf", 2)], 3).unwrap();
+        vdb.finish_document("a.pdf").unwrap();
+        assert_eq!(vdb.count_embeddings().unwrap(), 3);
+        assert_eq!(
+            vdb.document_progress("a.pdf").unwrap().unwrap().status,
+            DocumentStatus::Done
+        );
+
+        // Starting over removes what the document had stored, and nothing else.
+        vdb.start_document("b.pdf", "fp2", 1).unwrap();
+        vdb.commit_chunks("b.pdf", &[chunk("b.pdf", 0)], 1).unwrap();
+        vdb.start_document("a.pdf", "fp3", 1).unwrap();
+        assert_eq!(vdb.count_embeddings().unwrap(), 1);
+        assert_eq!(
+            vdb.document_progress("a.pdf")
+                .unwrap()
+                .unwrap()
+                .committed_chunks,
+            0
+        );
+    }
+
+    #[test]
+    fn test_list_delete_and_verify_documents() {
+        let vdb = in_memory_db();
+        vdb.create_embeddings_table().unwrap();
+        vdb.start_document("a.pdf", "fp1", 2).unwrap();
+        vdb.commit_chunks("a.pdf", &[chunk("a.pdf", 0), chunk("a.pdf", 1)], 2)
+            .unwrap();
+        vdb.finish_document("a.pdf").unwrap();
+        vdb.start_document("b.pdf", "fp2", 2).unwrap();
+        vdb.commit_chunks("b.pdf", &[chunk("b.pdf", 0)], 1).unwrap();
+
+        let documents = vdb.list_documents().unwrap();
+        assert_eq!(documents.len(), 2);
+        assert_eq!(documents[0].source, "a.pdf");
+        assert_eq!(documents[0].chunks_stored, 2);
+        assert_eq!(documents[1].status, DocumentStatus::InProgress);
+        assert_eq!(
+            vdb.verify().unwrap(),
+            vec!["b.pdf is in_progress (1/2 chunks stored)"]
+        );
+
+        assert_eq!(vdb.delete_document("b.pdf").unwrap(), 1);
+        assert!(vdb.verify().unwrap().is_empty());
+        assert_eq!(vdb.count_embeddings().unwrap(), 2);
+
+        let mut texts = Vec::new();
+        vdb.for_each_embedding(|row| {
+            texts.push((row.text, row.embedding));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            texts,
+            vec![
+                ("a.pdf chunk 0".to_string(), vec![1.0, 0.0]),
+                ("a.pdf chunk 1".to_string(), vec![1.0, 1.0])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_replace_and_delete_matching() {
+        let vdb = in_memory_db();
+        vdb.create_embeddings_table().unwrap();
+        vdb.start_document("a.pdf", "fp1", 3).unwrap();
+        vdb.commit_chunks("a.pdf", &[chunk("a.pdf", 0), chunk("a.pdf", 1)], 2)
+            .unwrap();
+        vdb.start_document("b.pdf", "fp2", 2).unwrap();
+        vdb.commit_chunks("b.pdf", &[chunk("b.pdf", 0), chunk("b.pdf", 1)], 2)
+            .unwrap();
+        vdb.finish_document("b.pdf").unwrap();
+
+        // Replacing a half-loaded document leaves it complete.
+        vdb.replace_document("a.pdf", "fp3", &[chunk("a.pdf", 0)])
+            .unwrap();
+        let a = vdb.document_progress("a.pdf").unwrap().unwrap();
+        assert_eq!((a.chunk_count, a.status), (1, DocumentStatus::Done));
+        assert_eq!(vdb.count_embeddings().unwrap(), 3);
+        assert!(vdb.verify().unwrap().is_empty());
+
+        let filters = HashMap::from([("chunk_index".to_string(), json!(1))]);
+        assert_eq!(vdb.delete_matching(&filters).unwrap(), 1);
+        assert_eq!(
+            vdb.document_progress("b.pdf").unwrap().unwrap().chunk_count,
+            1
+        );
+        let filters = HashMap::from([("source".to_string(), json!("a.pdf"))]);
+        assert_eq!(vdb.delete_matching(&filters).unwrap(), 1);
+        assert_eq!(vdb.document_progress("a.pdf").unwrap(), None);
+        assert!(vdb.verify().unwrap().is_empty());
+        assert!(vdb.delete_matching(&HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_update_metadata() {
+        let vdb = in_memory_db();
+        vdb.create_embeddings_table().unwrap();
+        let id = vdb
+            .insert_embedding(
+                "a",
+                &[1.0, 0.0],
+                Some(&json!({"source": "a.pdf", "page": 1})),
+            )
+            .unwrap();
+        let set = serde_json::Map::from_iter([("section".to_string(), json!("Scoring"))]);
+        let metadata = vdb
+            .update_metadata(id, &set, &["page".to_string()])
+            .unwrap();
+        assert_eq!(metadata, json!({"source": "a.pdf", "section": "Scoring"}));
+        let hits = vdb.search(&[1.0, 0.0], 1, &HashMap::new()).unwrap();
+        assert_eq!(hits[0].metadata, Some(metadata));
+        assert!(vdb.update_metadata(id + 1, &set, &[]).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_version_tracks_every_write() {
+        let vdb = in_memory_db();
+        vdb.create_embeddings_table().unwrap();
+        let mut versions = vec![vdb.snapshot_version().unwrap()];
+        let id = vdb
+            .insert_embedding(
+                "a.pdf chunk 9",
+                &[1.0, 0.0],
+                Some(&json!({"source": "a.pdf"})),
+            )
+            .unwrap();
+        versions.push(vdb.snapshot_version().unwrap());
+
+        let set = serde_json::Map::from_iter([("heading".to_string(), json!("Setup"))]);
+        vdb.update_metadata(id, &set, &[]).unwrap();
+        versions.push(vdb.snapshot_version().unwrap());
+
+        // Same number of chunks with text of the same length.
+        vdb.replace_document("a.pdf", "fp", &[chunk("a.pdf", 0)])
+            .unwrap();
+        versions.push(vdb.snapshot_version().unwrap());
+
+        vdb.drop_embeddings_table().unwrap();
+        vdb.create_embeddings_table().unwrap();
+        versions.push(vdb.snapshot_version().unwrap());
+
+        let distinct: BTreeSet<&String> = versions.iter().collect();
+        assert_eq!(distinct.len(), versions.len());
+    }
+
+    #[test]
+    fn test_link_duplicates() {
+        let vdb = in_memory_db();
+        vdb.create_embeddings_table().unwrap();
+        vdb.insert_embedding(
+            "Copyright 2004",
+            &[1.0, 0.0],
+            Some(&json!({"source": "a.pdf", "chunk_index": 3})),
+        )
+        .unwrap();
+        let b = json!({"source": "b.pdf", "chunk_index": 7});
+        assert!(vdb.link_duplicates("a.pdf", 3, &[b.clone()]).unwrap());
+        assert!(vdb.link_duplicates("a.pdf", 3, &[b.clone()]).unwrap());
+        assert!(!vdb.link_duplicates("a.pdf", 4, &[b.clone()]).unwrap());
+
+        let hits = vdb.search(&[1.0, 0.0], 1, &HashMap::new()).unwrap();
+        assert_eq!(
+            hits[0].metadata,
+            Some(json!({"source": "a.pdf", "chunk_index": 3, "duplicates": [b]}))
+        );
+    }
+
+    #[test]
+    fn test_deleting_an_original_keeps_its_duplicates() {
+        let vdb = in_memory_db();
+        vdb.create_embeddings_table().unwrap();
+        let copyright = NewEmbedding {
+            text: "Copyright 2004".to_string(),
+            embedding: vec![0.0, 1.0],
+            metadata: Some(json!({"source": "a.pdf", "chunk_index": 3})),
+        };
+        vdb.replace_document("a.pdf", "fp1", &[chunk("a.pdf", 0), copyright])
+            .unwrap();
+        vdb.replace_document("b.pdf", "fp2", &[chunk("b.pdf", 0)])
+            .unwrap();
+        let b7 = json!({"source": "b.pdf", "chunk_index": 7});
+        let c2 = json!({"source": "c.pdf", "chunk_index": 2});
+        let a5 = json!({"source": "a.pdf", "chunk_index": 5});
+        vdb.link_duplicates("a.pdf", 3, &[a5, b7, c2.clone()])
+            .unwrap();
+        let a1 = json!({"source": "a.pdf", "chunk_index": 1});
+        vdb.link_duplicates("b.pdf", 0, &[a1]).unwrap();
+
+        vdb.delete_document("a.pdf").unwrap();
+        let filters = HashMap::from([("source".to_string(), json!("b.pdf"))]);
+        let hits = vdb.search(&[0.0, 1.0], 1, &filters).unwrap();
+        assert_eq!(hits[0].text, "Copyright 2004");
+        assert_eq!(
+            hits[0].metadata,
+            Some(json!({"source": "b.pdf", "chunk_index": 7, "duplicates": [c2]}))
+        );
+        // a.pdf's repeat of b.pdf's chunk went with it.
+        let hits = vdb.search(&[1.0, 0.0], 1, &filters).unwrap();
+        assert_eq!(
+            hits[0].metadata,
+            Some(json!({"source": "b.pdf", "chunk_index": 0}))
+        );
+        assert_eq!(
+            vdb.document_progress("b.pdf").unwrap().unwrap().chunk_count,
+            2
+        );
+        assert!(vdb.verify().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_stats() {
+        let vdb = in_memory_db();
+        vdb.create_embeddings_table().unwrap();
+        vdb.set_meta("embeddings_model", "titan").unwrap();
+        vdb.start_document("a.pdf", "fp1", 2).unwrap();
+        vdb.commit_chunks("a.pdf", &[chunk("a.pdf", 0), chunk("a.pdf", 0)], 2)
+            .unwrap();
+        vdb.finish_document("a.pdf").unwrap();
+        vdb.start_document("scan.pdf", "fp2", 0).unwrap();
+        vdb.finish_document("scan.pdf").unwrap();
+        vdb.insert_embedding(" ", &[1.0, 0.0, 0.0], None).unwrap();
+
+        let stats = vdb.stats().unwrap();
+        assert_eq!(stats.schema_version, Some(SCHEMA_VERSION.to_string()));
+        assert_eq!(stats.embeddings_model.as_deref(), Some("titan"));
+        assert_eq!(stats.chunks, 3);
+        assert_eq!(stats.characters, 27);
+        assert_eq!(stats.empty_chunks, 1);
+        assert_eq!(stats.duplicate_chunks, 1);
+        assert_eq!(stats.dimensions, vec![2, 3]);
+        assert_eq!(stats.chunk_length_histogram[0].chunks, 3);
+        assert!(stats.indexes.is_empty());
+        assert_eq!(
+            stats.documents,
+            vec![
+                DocumentStats {
+                    source: None,
+                    status: None,
+                    chunks: 1,
+                    characters: 1,
+                },
+                DocumentStats {
+                    source: Some("a.pdf".to_string()),
+                    status: Some(DocumentStatus::Done),
+                    chunks: 2,
+                    characters: 26,
+                },
+                DocumentStats {
+                    source: Some("scan.pdf".to_string()),
+                    status: Some(DocumentStatus::Done),
+                    chunks: 0,
+                    characters: 0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_document_metadata() {
+        let vdb = in_memory_db();
+        vdb.create_embeddings_table().unwrap();
+        let metadata = DocumentMetadata {
+            title: Some("Ticket to Ride".to_string()),
+            author: None,
+            creation_date: Some("2015-03-09T10:16:51-07:00".to_string()),
+            page_count: 4,
+            outline: vec![OutlineEntry {
+                level: 1,
+                title: "Scoring".to_string(),
+                page: 4,
+            }],
+        };
+        vdb.start_document("a.pdf", "fp1", 1).unwrap();
+        vdb.commit_chunks("a.pdf", &[chunk("a.pdf", 0)], 1).unwrap();
+        vdb.save_document_metadata("a.pdf", &metadata).unwrap();
+        assert_eq!(vdb.document_metadata("a.pdf").unwrap(), Some(metadata));
+        let documents = vdb.list_documents().unwrap();
+        assert_eq!(documents[0].title.as_deref(), Some("Ticket to Ride"));
+        assert_eq!(documents[0].page_count, Some(4));
+
+        vdb.delete_document("a.pdf").unwrap();
+        assert_eq!(vdb.document_metadata("a.pdf").unwrap(), None);
+    }
+
+    #[test]
+    fn test_document_fingerprint() {
+        let chunks = vec!["ab".to_string(), "c".to_string()];
+        let other = vec!["a".to_string(), "bc".to_string()];
+        assert_eq!(document_fingerprint(&chunks), document_fingerprint(&chunks));
+        assert_ne!(document_fingerprint(&chunks), document_fingerprint(&other));
+    }
+
+    #[test]
+    fn test_embedding_bytes_are_little_endian() {
+        let bytes = embedding_bytes(&[1.0, -2.5]);
+        assert_eq!(bytes, [0, 0, 128, 63, 0, 0, 32, 192]);
+        assert_eq!(embedding_from_bytes(&bytes), vec![1.0, -2.5]);
+    }
+
+    #[test]
+    fn test_insert_embeddings_batch() {
+        let vdb = in_memory_db();
+        vdb.create_embeddings_table().unwrap();
+        let rows: Vec<NewEmbedding> = ["a", "b", "c"]
+            .iter()
+            .enumerate()
+            .map(|(i, text)| NewEmbedding {
+                text: text.to_string(),
+                embedding: vec![i as f32, 1.0],
+                metadata: Some(json!({"chunk_index": i})),
+            })
+            .collect();
+        let ids = vdb.insert_embeddings(&rows).unwrap();
+        assert_eq!(ids, vec![1, 2, 3]);
+        assert_eq!(vdb.count_embeddings().unwrap(), 3);
+
+        let hits = vdb.search(&[2.0, 1.0], 1, &HashMap::new()).unwrap();
+        assert_eq!(hits[0].text, "c");
+        assert_eq!(hits[0].metadata, Some(json!({"chunk_index": 2})));
     }
 }
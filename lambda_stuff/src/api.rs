@@ -0,0 +1,516 @@
+use common::config::{ModelParams, ModelsConfig};
+use lambda_http::{Body, Error, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+// Request and response types for the JSON API. The field names here are the documented
+// schema (see lambda_stuff/README.md), so rename with care.
+
+/// Body of `POST /ask`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AskRequest {
+    pub question: String,
+    /// Number of chunks to retrieve; defaults to `retrieval.top_k` from the config.
+    #[serde(default)]
+    pub top_k: Option<usize>,
+    /// Only chunks whose metadata has these exact key/value pairs are retrieved.
+    #[serde(default)]
+    pub filters: HashMap<String, Value>,
+    /// Bedrock model id; defaults to `models.chat_model` from the config.
+    /// Must be one of the allowed chat models.
+    #[serde(default)]
+    pub model: Option<String>,
+    /// Continues an earlier conversation; a new id is generated when absent.
+    #[serde(default)]
+    pub conversation_id: Option<String>,
+    /// `"bypass"` skips the answer cache lookup; the fresh answer still replaces the cached one.
+    #[serde(default)]
+    pub cache: CacheMode,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheMode {
+    #[default]
+    Use,
+    Bypass,
+}
+
+impl CacheMode {
+    /// Parses the `cache` query parameter.
+    pub fn parse(value: &str) -> Result<Self, ApiError> {
+        match value {
+            "use" => Ok(CacheMode::Use),
+            "bypass" => Ok(CacheMode::Bypass),
+            _ => Err(ApiError::InvalidRequest(
+                "cache must be \"use\" or \"bypass\"".to_string(),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AskResponse {
+    pub conversation_id: String,
+    pub question: String,
+    pub answer: String,
+    pub sources: Vec<Source>,
+    pub metadata: ResponseMetadata,
+}
+
+/// A retrieved chunk that was given to the model as context.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Source {
+    pub id: i64,
+    pub score: f32,
+    pub text: String,
+    pub metadata: Option<Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResponseMetadata {
+    pub model: String,
+    /// Inference settings the model was called with; unset ones used the model's defaults.
+    pub inference: ModelParams,
+    /// The model that was asked for. `model` is the one that answered, which is a fallback
+    /// model if this one kept failing.
+    pub requested_model: String,
+    /// Model calls made to get the answer, counting retries and fallbacks.
+    pub attempts: u32,
+    /// Name and version of the prompt template the prompts were rendered from.
+    pub prompt_template: String,
+    pub prompt_version: String,
+    pub system_prompt: String,
+    pub prompt: String,
+    pub timestamp: String,
+    pub top_k: usize,
+    /// Number of earlier turns sent to the model along with the question.
+    pub history_turns: usize,
+    /// The follow-up question rewritten for retrieval, when history was used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub standalone_question: Option<String>,
+    pub context: ContextReport,
+    /// Whether the answer came from the answer cache.
+    pub cache: CacheStatus,
+    pub input_tokens: i32,
+    pub output_tokens: i32,
+    pub total_tokens: i32,
+}
+
+/// How the retrieved passages were fitted into the prompt's token budget.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ContextReport {
+    pub budget_tokens: usize,
+    pub used_tokens: usize,
+    /// Every retrieved passage, in rank order, whether or not it made it into the prompt.
+    pub passages: Vec<PassageUsage>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PassageUsage {
+    pub id: i64,
+    /// Estimated tokens of the passage as it was sent, or of the whole passage if dropped.
+    pub tokens: usize,
+    pub status: PassageStatus,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PassageStatus {
+    Included,
+    /// Cut short at a sentence boundary to fit the budget.
+    Truncated,
+    Dropped,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheStatus {
+    /// Served from the cache; the token counts are those of the original answer.
+    Hit,
+    /// Not cached yet; this answer has been stored.
+    Miss,
+    /// The request asked to skip the lookup; this answer has been stored.
+    Bypass,
+    /// Not cacheable: the cache is disabled or the question continues a conversation.
+    #[default]
+    Skip,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseFormat {
+    Html,
+    Json,
+    /// Server-Sent Events, see `crate::sse`.
+    EventStream,
+}
+
+impl ResponseFormat {
+    pub fn media_type(&self) -> &'static str {
+        match self {
+            ResponseFormat::Html => "text/html",
+            ResponseFormat::Json => "application/json",
+            ResponseFormat::EventStream => "text/event-stream",
+        }
+    }
+}
+
+/// Picks the response format from an `Accept` header.
+/// A missing header or a wildcard gets `default`; `None` means nothing we produce is acceptable.
+pub fn negotiate(accept: Option<&str>, default: ResponseFormat) -> Option<ResponseFormat> {
+    let accept = match accept {
+        Some(accept) if !accept.trim().is_empty() => accept,
+        _ => return Some(default),
+    };
+
+    let mut best: Option<(f32, u8, ResponseFormat)> = None;
+    for range in accept.split(',') {
+        let mut parts = range.split(';');
+        let media = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let q = parts
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if q <= 0.0 {
+            continue;
+        }
+
+        // Exact matches win over `type/*`, which wins over `*/*`, at equal q.
+        let candidate = match media.as_str() {
+            "text/html" => Some((2, ResponseFormat::Html)),
+            "application/json" => Some((2, ResponseFormat::Json)),
+            "text/event-stream" => Some((2, ResponseFormat::EventStream)),
+            "text/*" => Some((1, ResponseFormat::Html)),
+            "application/*" => Some((1, ResponseFormat::Json)),
+            "*/*" => Some((0, default)),
+            _ => None,
+        };
+        if let Some((specificity, format)) = candidate {
+            let better = match best {
+                None => true,
+                Some((best_q, best_specificity, _)) => {
+                    q > best_q || (q == best_q && specificity > best_specificity)
+                }
+            };
+            if better {
+                best = Some((q, specificity, format));
+            }
+        }
+    }
+    best.map(|(_, _, format)| format)
+}
+
+/// Errors returned to API callers. Every variant is rendered as the same JSON shape:
+/// `{"error": {"code": "...", "message": "..."}}`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ApiError {
+    MissingQuestion,
+    InvalidRequest(String),
+    UnsupportedMediaType,
+    NotAcceptable,
+    MethodNotAllowed,
+    UnsupportedModel { model: String, allowed: Vec<String> },
+    Bedrock(String),
+    Internal(String),
+}
+
+impl ApiError {
+    pub fn status(&self) -> u16 {
+        match self {
+            ApiError::MissingQuestion
+            | ApiError::InvalidRequest(_)
+            | ApiError::UnsupportedModel { .. } => 400,
+            ApiError::MethodNotAllowed => 405,
+            ApiError::NotAcceptable => 406,
+            ApiError::UnsupportedMediaType => 415,
+            ApiError::Bedrock(_) => 502,
+            ApiError::Internal(_) => 500,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::MissingQuestion => "missing_question",
+            ApiError::InvalidRequest(_) => "invalid_request",
+            ApiError::UnsupportedMediaType => "unsupported_media_type",
+            ApiError::NotAcceptable => "not_acceptable",
+            ApiError::MethodNotAllowed => "method_not_allowed",
+            ApiError::UnsupportedModel { .. } => "unsupported_model",
+            ApiError::Bedrock(_) => "bedrock_error",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            ApiError::MissingQuestion => {
+                "Please provide a question, either as the question_text query parameter or in a JSON body to POST /ask".to_string()
+            }
+            ApiError::InvalidRequest(details) => format!("Invalid request: {}", details),
+            ApiError::UnsupportedMediaType => "POST /ask expects an application/json body".to_string(),
+            ApiError::NotAcceptable => "Responses are available as text/html, application/json or text/event-stream".to_string(),
+            ApiError::MethodNotAllowed => "Use GET / or POST /ask".to_string(),
+            ApiError::UnsupportedModel { model, allowed } => format!(
+                "Model {} is not available; use one of: {}",
+                model,
+                allowed.join(", ")
+            ),
+            ApiError::Bedrock(details) => format!("Bedrock API error: {}", details),
+            ApiError::Internal(details) => format!("Internal error: {}", details),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut body = json!({
+            "error": {
+                "code": self.code(),
+                "message": self.message(),
+            }
+        });
+        if let ApiError::UnsupportedModel { allowed, .. } = self {
+            body["error"]["allowed_models"] = json!(allowed);
+        }
+        body
+    }
+
+    pub fn into_response(self) -> Result<Response<Body>, Error> {
+        Ok(Response::builder()
+            .status(self.status())
+            .header("content-type", "application/json")
+            .body(self.to_json().to_string().into())?)
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.code(), self.message())
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// Parses the body of `POST /ask`.
+pub fn parse_ask_request(content_type: Option<&str>, body: &[u8]) -> Result<AskRequest, ApiError> {
+    let is_json = content_type
+        .map(|ct| {
+            ct.split(';')
+                .next()
+                .unwrap_or("")
+                .trim()
+                .eq_ignore_ascii_case("application/json")
+        })
+        .unwrap_or(false);
+    if !is_json {
+        return Err(ApiError::UnsupportedMediaType);
+    }
+
+    let request: AskRequest =
+        serde_json::from_slice(body).map_err(|e| ApiError::InvalidRequest(e.to_string()))?;
+    if request.question.trim().is_empty() {
+        return Err(ApiError::MissingQuestion);
+    }
+    if request.top_k == Some(0) {
+        return Err(ApiError::InvalidRequest(
+            "top_k must be greater than 0".to_string(),
+        ));
+    }
+    if let Some(id) = &request.conversation_id {
+        validate_conversation_id(id)?;
+    }
+    Ok(request)
+}
+
+/// Rejects a requested model that isn't on the configured allow-list.
+pub fn check_model(request: &AskRequest, models: &ModelsConfig) -> Result<(), ApiError> {
+    match &request.model {
+        Some(model) if models.chat_model_params(model).is_none() => {
+            Err(ApiError::UnsupportedModel {
+                model: model.clone(),
+                allowed: models
+                    .allowed_chat_models()
+                    .into_iter()
+                    .map(str::to_string)
+                    .collect(),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Conversation ids are chosen by clients, so only short, boring ids are accepted.
+pub fn validate_conversation_id(id: &str) -> Result<(), ApiError> {
+    let valid = !id.is_empty()
+        && id.len() <= 128
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(ApiError::InvalidRequest(
+            "conversation_id must be 1-128 letters, digits, '-' or '_'".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+pub fn json_response(status: u16, value: &impl Serialize) -> Result<Response<Body>, Error> {
+    Ok(Response::builder()
+        .status(status)
+        .header("content-type", ResponseFormat::Json.media_type())
+        .body(serde_json::to_string(value)?.into())?)
+}
+
+pub fn html_response(status: u16, html: String) -> Result<Response<Body>, Error> {
+    Ok(Response::builder()
+        .status(status)
+        .header("content-type", ResponseFormat::Html.media_type())
+        .body(html.into())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate_defaults() {
+        assert_eq!(
+            negotiate(None, ResponseFormat::Html),
+            Some(ResponseFormat::Html)
+        );
+        assert_eq!(
+            negotiate(Some("*/*"), ResponseFormat::Json),
+            Some(ResponseFormat::Json)
+        );
+    }
+
+    #[test]
+    fn test_negotiate_prefers_specific_and_higher_q() {
+        assert_eq!(
+            negotiate(Some("application/json"), ResponseFormat::Html),
+            Some(ResponseFormat::Json)
+        );
+        assert_eq!(
+            negotiate(
+                Some("text/html;q=0.5, application/json;q=0.9"),
+                ResponseFormat::Html
+            ),
+            Some(ResponseFormat::Json)
+        );
+        assert_eq!(
+            negotiate(
+                Some("text/html,application/xhtml+xml,*/*;q=0.8"),
+                ResponseFormat::Json
+            ),
+            Some(ResponseFormat::Html)
+        );
+    }
+
+    #[test]
+    fn test_negotiate_event_stream() {
+        assert_eq!(
+            negotiate(
+                Some("text/event-stream, application/json;q=0.9"),
+                ResponseFormat::Json
+            ),
+            Some(ResponseFormat::EventStream)
+        );
+    }
+
+    #[test]
+    fn test_negotiate_not_acceptable() {
+        assert_eq!(negotiate(Some("image/png"), ResponseFormat::Html), None);
+        assert_eq!(
+            negotiate(Some("application/json;q=0"), ResponseFormat::Json),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_ask_request() {
+        let body = br#"{"question": "How long is a game?", "top_k": 3, "filters": {"source": "ticket_to_ride.pdf"}}"#;
+        let request = parse_ask_request(Some("application/json; charset=utf-8"), body).unwrap();
+        assert_eq!(request.question, "How long is a game?");
+        assert_eq!(request.top_k, Some(3));
+        assert_eq!(request.filters["source"], "ticket_to_ride.pdf");
+        assert_eq!(request.model, None);
+        assert_eq!(request.cache, CacheMode::Use);
+
+        let body = br#"{"question": "q", "cache": "bypass"}"#;
+        let request = parse_ask_request(Some("application/json"), body).unwrap();
+        assert_eq!(request.cache, CacheMode::Bypass);
+        assert!(CacheMode::parse("never").is_err());
+    }
+
+    #[test]
+    fn test_parse_ask_request_errors() {
+        assert_eq!(
+            parse_ask_request(Some("text/plain"), b"{}").unwrap_err(),
+            ApiError::UnsupportedMediaType
+        );
+        assert_eq!(
+            parse_ask_request(Some("application/json"), br#"{"question": "  "}"#).unwrap_err(),
+            ApiError::MissingQuestion
+        );
+        assert_eq!(
+            parse_ask_request(Some("application/json"), b"not json")
+                .unwrap_err()
+                .code(),
+            "invalid_request"
+        );
+        assert_eq!(
+            parse_ask_request(Some("application/json"), br#"{"question": "q", "topk": 1}"#)
+                .unwrap_err()
+                .code(),
+            "invalid_request"
+        );
+    }
+
+    #[test]
+    fn test_conversation_id_validation() {
+        let body = br#"{"question": "q", "conversation_id": "3f2a-b_9"}"#;
+        let request = parse_ask_request(Some("application/json"), body).unwrap();
+        assert_eq!(request.conversation_id.as_deref(), Some("3f2a-b_9"));
+
+        let body = br#"{"question": "q", "conversation_id": "../etc"}"#;
+        assert_eq!(
+            parse_ask_request(Some("application/json"), body)
+                .unwrap_err()
+                .code(),
+            "invalid_request"
+        );
+        assert!(validate_conversation_id(&"a".repeat(129)).is_err());
+        assert!(validate_conversation_id("").is_err());
+    }
+
+    #[test]
+    fn test_check_model() {
+        let models = ModelsConfig::default();
+        let mut request = AskRequest {
+            question: "q".to_string(),
+            ..Default::default()
+        };
+        assert!(check_model(&request, &models).is_ok());
+
+        request.model = Some(models.chat_model.clone());
+        assert!(check_model(&request, &models).is_ok());
+
+        request.model = Some("meta.llama3-70b-instruct-v1:0".to_string());
+        let err = check_model(&request, &models).unwrap_err();
+        assert_eq!(err.status(), 400);
+        let body = err.to_json();
+        assert_eq!(body["error"]["code"], "unsupported_model");
+        assert_eq!(
+            body["error"]["allowed_models"][0],
+            models.chat_model.as_str()
+        );
+    }
+
+    #[test]
+    fn test_error_body_shape() {
+        let body = ApiError::MissingQuestion.to_json();
+        assert_eq!(body["error"]["code"], "missing_question");
+        assert!(body["error"]["message"].is_string());
+        assert_eq!(ApiError::Bedrock("x".into()).status(), 502);
+    }
+}
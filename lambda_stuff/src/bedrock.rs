@@ -1,148 +1,816 @@
 // use aws_config::BehaviorVersion;
 use anyhow::Result;
+use aws_sdk_bedrockruntime::error::DisplayErrorContext;
 use aws_sdk_bedrockruntime::Client as BedrockClient;
 use aws_sdk_bedrockruntime::{
-    operation::converse::{ConverseError, ConverseOutput},
-    types::{ContentBlock, ConversationRole, Message},
+    operation::converse_stream::ConverseStreamOutput as ConverseStreamResponse,
+    types::{
+        ContentBlock, ContentBlockDelta, ConversationRole,
+        ConverseStreamOutput as ConverseStreamEvent, Message, SystemContentBlock,
+    },
 };
 //use aws_sdk_s3::Client as S3Client;
 // use aws_smithy_types::Blob;
+use crate::api::{
+    ApiError, AskRequest, AskResponse, CacheMode, CacheStatus, ContextReport, PassageStatus,
+    PassageUsage, ResponseMetadata, Source,
+};
+use crate::cache::CacheKey;
+use crate::session::Turn;
+use crate::sse::{AnswerChunk, AnswerStream};
+use crate::state::AppState;
 use chrono;
-use common::embeddings::create_embeddings;
+use common::config::{Config, ModelParams, RetrievalConfig};
+use common::converse::{
+    get_converse_output_text, inference_config, system_prompt, with_fallback, Answered,
+    BedrockConverseError,
+};
+use common::embeddings::EmbeddingError;
+use common::prompts::{HistoryTurn, Passage, PromptVars};
 use common::vectordb::VectorDb;
+use uuid::Uuid;
 // use lambda_runtime::Error;
-use serde_json::json;
 // use std::env;
 
 // based on examples found here: https://github.com/awsdocs/aws-doc-sdk-examples/blob/main/rustv1/examples/bedrock-runtime/src/bin/converse.rs
 
-#[derive(Debug)]
-struct BedrockConverseError(String);
-impl std::fmt::Display for BedrockConverseError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Can't invoke bedrock model. Reason: {}", self.0)
+// pub struct QueryModel {
+//     pub question_text: Option<String>,
+//     pub answer_text: Option<String>,
+//     // utc_created_at: Datetime<Utc>,
+// }
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The result of retrieval: the prompt that will be sent to the model and where its context came from.
+#[derive(Debug, Clone)]
+pub struct PreparedQuestion {
+    pub conversation_id: String,
+    pub question: String,
+    /// The follow-up question rewritten to stand on its own, when history was used for retrieval.
+    pub standalone_question: Option<String>,
+    pub history: Vec<Turn>,
+    /// The model that answered; differs from `requested_model` when a fallback model was used.
+    pub model: String,
+    pub params: ModelParams,
+    pub requested_model: String,
+    /// Model calls made to get the answer, counting retries and fallbacks.
+    pub attempts: u32,
+    pub prompt_template: String,
+    pub prompt_version: String,
+    pub system_prompt: String,
+    pub prompt: String,
+    pub top_k: usize,
+    /// The passages that made it into the prompt, possibly truncated.
+    pub sources: Vec<Source>,
+    pub context: ContextReport,
+    pub cache: CacheStatus,
+    /// Where the answer is stored in the answer cache, if the question is cacheable.
+    pub cache_key: Option<String>,
+}
+
+/// What `prepare_question` came up with: an answer from the answer cache, or a prompt that
+/// still has to be sent to the model.
+pub enum Prepared {
+    Cached(AskResponse),
+    Fresh(PreparedQuestion),
+}
+
+impl PreparedQuestion {
+    pub fn metadata(&self, input_tokens: i32, output_tokens: i32) -> ResponseMetadata {
+        ResponseMetadata {
+            model: self.model.clone(),
+            inference: self.params.clone(),
+            requested_model: self.requested_model.clone(),
+            attempts: self.attempts,
+            prompt_template: self.prompt_template.clone(),
+            prompt_version: self.prompt_version.clone(),
+            system_prompt: self.system_prompt.clone(),
+            prompt: self.prompt.clone(),
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            top_k: self.top_k,
+            history_turns: self.history.len(),
+            standalone_question: self.standalone_question.clone(),
+            context: self.context.clone(),
+            cache: self.cache,
+            input_tokens,
+            output_tokens,
+            total_tokens: input_tokens + output_tokens,
+        }
     }
+
+    pub fn into_response(
+        self,
+        answer: String,
+        input_tokens: i32,
+        output_tokens: i32,
+    ) -> AskResponse {
+        let metadata = self.metadata(input_tokens, output_tokens);
+        AskResponse {
+            conversation_id: self.conversation_id,
+            question: self.question,
+            answer,
+            sources: self.sources,
+            metadata,
+        }
+    }
+
+    /// The conversation so far followed by the new prompt, as Converse messages.
+    fn messages(&self) -> Result<Vec<Message>, BoxError> {
+        let mut messages = Vec::with_capacity(self.history.len() * 2 + 1);
+        for turn in &self.history {
+            messages.push(text_message(ConversationRole::User, &turn.question)?);
+            messages.push(text_message(ConversationRole::Assistant, &turn.answer)?);
+        }
+        messages.push(text_message(ConversationRole::User, &self.prompt)?);
+        Ok(messages)
+    }
+
+    /// Records which model ended up answering and how many calls it took.
+    fn answered_by<T>(&mut self, answered: &Answered<T>) {
+        self.model = answered.model.clone();
+        self.params = answered.params.clone();
+        self.attempts = answered.attempts;
+    }
+
+    /// The rendered system prompt as the Converse `system` field; omitted when empty.
+    fn system(&self) -> Option<Vec<SystemContentBlock>> {
+        system_prompt(&self.system_prompt)
+    }
+}
+
+/// Template variables for a question, its retrieved sources and the conversation so far.
+fn prompt_vars(question: &str, sources: &[Source], history: &[Turn]) -> PromptVars {
+    PromptVars::new(
+        question,
+        sources
+            .iter()
+            .enumerate()
+            .map(|(i, source)| {
+                Passage::from_chunk(i + 1, &source.text, source.metadata.as_ref(), source.score)
+            })
+            .collect(),
+        history
+            .iter()
+            .map(|turn| HistoryTurn {
+                question: turn.question.clone(),
+                answer: turn.answer.clone(),
+            })
+            .collect(),
+    )
+}
+
+/// Rough token count used for budgeting: about four characters per token for English text.
+/// Deliberately model-agnostic, so treat budgets as approximate.
+fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// Below this many tokens a truncated passage isn't worth sending.
+const MIN_TRUNCATED_TOKENS: usize = 32;
+
+/// Tokens available for passage text once the answer and the rest of the prompt are accounted for.
+/// Room for the answer is the model's `max_tokens` when set, else `retrieval.answer_tokens`.
+fn context_budget(
+    retrieval: &RetrievalConfig,
+    params: &ModelParams,
+    prompt_tokens: usize,
+) -> usize {
+    let answer_tokens = params
+        .max_tokens
+        .map(|t| t as usize)
+        .unwrap_or(retrieval.answer_tokens);
+    let available = retrieval
+        .context_window_tokens
+        .saturating_sub(answer_tokens + prompt_tokens);
+    retrieval.max_context_tokens.min(available)
 }
-impl std::error::Error for BedrockConverseError {}
-impl From<&str> for BedrockConverseError {
-    fn from(value: &str) -> Self {
-        BedrockConverseError(value.to_string())
+
+/// Packs the ranked `sources` into `budget_tokens`, best first. The first passage that doesn't
+/// fit is truncated at a sentence boundary (or dropped if too little room is left) and every
+/// passage after it is dropped, so the prompt never skips over a better-ranked passage.
+fn assemble_context(sources: Vec<Source>, budget_tokens: usize) -> (Vec<Source>, ContextReport) {
+    let mut included = Vec::new();
+    let mut passages = Vec::new();
+    let mut used_tokens = 0;
+    let mut full = false;
+
+    for mut source in sources {
+        let tokens = estimate_tokens(&source.text);
+        let remaining = budget_tokens - used_tokens;
+        let (status, tokens) = if full {
+            (PassageStatus::Dropped, tokens)
+        } else if tokens <= remaining {
+            (PassageStatus::Included, tokens)
+        } else {
+            full = true;
+            let truncated = (remaining >= MIN_TRUNCATED_TOKENS)
+                .then(|| truncate_at_sentence(&source.text, remaining * 4))
+                .flatten();
+            match truncated {
+                Some(text) => {
+                    source.text = text;
+                    (PassageStatus::Truncated, estimate_tokens(&source.text))
+                }
+                None => (PassageStatus::Dropped, tokens),
+            }
+        };
+
+        passages.push(PassageUsage {
+            id: source.id,
+            tokens,
+            status,
+        });
+        if status != PassageStatus::Dropped {
+            used_tokens += tokens;
+            included.push(source);
+        }
     }
+
+    let report = ContextReport {
+        budget_tokens,
+        used_tokens,
+        passages,
+    };
+    (included, report)
 }
-impl From<&ConverseError> for BedrockConverseError {
-    fn from(value: &ConverseError) -> Self {
-        BedrockConverseError::from(match value {
-            ConverseError::ModelTimeoutException(_) => "Model took too long",
-            ConverseError::ModelNotReadyException(_) => "Model is not ready",
-            _ => "Unknown",
+
+/// The longest prefix of `text` of at most `max_chars` characters that ends a sentence.
+/// Falls back to the last word boundary, marked with an ellipsis, when no sentence ends in time.
+fn truncate_at_sentence(text: &str, max_chars: usize) -> Option<String> {
+    let cut = text
+        .char_indices()
+        .nth(max_chars)
+        .map(|(i, _)| i)
+        .unwrap_or(text.len());
+    let head = &text[..cut];
+
+    let sentence_end = head
+        .char_indices()
+        .filter(|&(i, c)| {
+            matches!(c, '.' | '!' | '?')
+                && text[i + c.len_utf8()..]
+                    .chars()
+                    .next()
+                    .is_none_or(char::is_whitespace)
         })
+        .map(|(i, c)| i + c.len_utf8())
+        .next_back();
+    if let Some(end) = sentence_end {
+        return Some(head[..end].to_string());
     }
+
+    let word_end = head.trim_end().rfind(char::is_whitespace)?;
+    let words = head[..word_end].trim_end();
+    (!words.is_empty()).then(|| format!("{}…", words))
 }
 
-fn get_converse_output_text(output: ConverseOutput) -> Result<String, BedrockConverseError> {
-    let message = output
-        .output()
-        .ok_or(BedrockConverseError("No output content".into()))?
-        .as_message()
-        .map_err(|_| BedrockConverseError("Output not a message".into()))?;
+fn text_message(role: ConversationRole, text: &str) -> Result<Message, BoxError> {
+    Ok(Message::builder()
+        .role(role)
+        .content(ContentBlock::Text(text.to_string()))
+        .build()
+        .map_err(|_| "failed to build message")?)
+}
 
-    let text = message
-        .content()
-        .first()
-        .and_then(|content| content.as_text().ok())
-        .ok_or(BedrockConverseError(
-            "No text content found in message".into(),
-        ))?;
+/// Prompt asking the model to turn a follow-up like "and how much does it cost per hour?"
+/// into a question that can be embedded and searched for on its own.
+fn condense_prompt(history: &[Turn], question: &str) -> String {
+    let conversation: Vec<String> = history
+        .iter()
+        .map(|turn| format!("User: {}\nAssistant: {}", turn.question, turn.answer))
+        .collect();
+    format!(
+        "Given the following conversation and a follow-up question, rephrase the follow-up \
+         question to be a standalone question that can be understood without the conversation. \
+         Reply with the standalone question only.\n\n\
+         Conversation:\n{}\n\n\
+         Follow-up question: {}\n\n\
+         Standalone question:",
+        conversation.join("\n"),
+        question
+    )
+}
 
-    Ok(text.to_string())
+async fn condense_question(
+    bedrock_client: &BedrockClient,
+    config: &Config,
+    model_name: &str,
+    history: &[Turn],
+    question: &str,
+) -> Result<String, BoxError> {
+    let message = text_message(ConversationRole::User, &condense_prompt(history, question))?;
+    let answered = with_fallback(config, model_name, |model, _| {
+        let request = bedrock_client
+            .converse()
+            .model_id(model)
+            .messages(message.clone());
+        async move { request.send().await.map_err(BedrockConverseError::from_sdk) }
+    })
+    .await?;
+    let standalone = get_converse_output_text(answered.value)?.trim().to_string();
+    if standalone.is_empty() {
+        return Ok(question.to_string());
+    }
+    Ok(standalone)
 }
 
-// pub struct QueryModel {
-//     pub question_text: Option<String>,
-//     pub answer_text: Option<String>,
-//     // utc_created_at: Datetime<Utc>,
-// }
+/// Reports a failed InvokeModel call as a Bedrock error; other embedding failures, like a
+/// broken embedding cache, stay internal.
+fn embedding_error(e: anyhow::Error) -> BoxError {
+    match e.downcast::<EmbeddingError>() {
+        Ok(e) => Box::new(ApiError::Bedrock(e.to_string())),
+        Err(e) => e.into(),
+    }
+}
+
+/// Loads the conversation history, checks the answer cache, embeds the question, retrieves
+/// similar chunks and builds the prompt.
+async fn prepare_question(
+    request: &AskRequest,
+    state: &AppState,
+    bedrock_client: &BedrockClient,
+) -> Result<Prepared, BoxError> {
+    let config = &state.config;
+    let question = request.question.as_str();
+    let model_name = request
+        .model
+        .as_deref()
+        .unwrap_or(config.models.chat_model.as_str());
+    let params =
+        config
+            .models
+            .chat_model_params(model_name)
+            .ok_or_else(|| ApiError::UnsupportedModel {
+                model: model_name.to_string(),
+                allowed: config
+                    .models
+                    .allowed_chat_models()
+                    .into_iter()
+                    .map(str::to_string)
+                    .collect(),
+            })?;
+    let top_k = request.top_k.unwrap_or(config.retrieval.top_k);
+
+    let conversation_id = request
+        .conversation_id
+        .clone()
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let history = state
+        .sessions
+        .history(&conversation_id, config.conversation.history_turns)?;
+
+    // A HEAD request tells which database is published, so cache hits skip the download.
+    let snapshot = VectorDb::published_version(&state.s3, &config.database).await?;
+
+    // Follow-up questions depend on the conversation, so only first questions are cached.
+    let cache_key = match &state.answers {
+        Some(_) if history.is_empty() => Some(
+            CacheKey {
+                question,
+                model: model_name,
+                prompt_template: &state.prompt.name,
+                prompt_version: &state.prompt.version,
+                snapshot: &snapshot.version,
+                top_k,
+                filters: &request.filters,
+            }
+            .digest(),
+        ),
+        _ => None,
+    };
+    let cache = match (&cache_key, request.cache) {
+        (None, _) => CacheStatus::Skip,
+        (Some(_), CacheMode::Bypass) => CacheStatus::Bypass,
+        (Some(_), CacheMode::Use) => CacheStatus::Miss,
+    };
+    if let (Some(answers), Some(key), CacheStatus::Miss) = (&state.answers, &cache_key, cache) {
+        match answers.get(key, chrono::Utc::now().timestamp()) {
+            Ok(Some(mut response)) => {
+                tracing::info!("Answer cache hit");
+                response.conversation_id = conversation_id;
+                response.question = question.to_string();
+                response.metadata.cache = CacheStatus::Hit;
+                return Ok(Prepared::Cached(response));
+            }
+            Ok(None) => {}
+            Err(e) => tracing::error!("Failed to read answer cache: {:?}", e),
+        }
+    }
+
+    let standalone_question = if !history.is_empty() && config.conversation.condense_questions {
+        let standalone =
+            condense_question(bedrock_client, config, model_name, &history, question).await?;
+        tracing::info!("Condensed follow-up question to: {}", standalone);
+        Some(standalone)
+    } else {
+        None
+    };
+    let retrieval_question = standalone_question.as_deref().unwrap_or(question);
+
+    let question_embeddings = state
+        .embedder
+        .embed(retrieval_question)
+        .await
+        .map_err(embedding_error)?;
 
-// Ask Bedrock a question for the LLM to answer
-pub async fn ask_bedrock(
-    question: &str,
-    model_name: &str,
-    embeddings_model_name: &str,
-) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
-    let config = aws_config::load_from_env().await;
-    let bedrock_client = BedrockClient::new(&config); // bedrock client
-
-    // let embeddings_value = create_embeddings(&question, model_name).await?;
-    // println!(
-    //     "Raw embeddings: {}",
-    //     serde_json::to_string_pretty(&embeddings_value)?
-    // );
-    // // Convert Value to Vec<f32>
-    // let question_embeddings: Vec<f32> = embeddings_value["embedding"] // or whatever the correct field name is
-    //     .as_array()
-    //     .ok_or_else(|| anyhow::anyhow!("Invalid embedding format"))?
-    //     .iter()
-    //     .map(|v| {
-    //         v.as_f64()
-    //             .ok_or_else(|| anyhow::anyhow!("Invalid number in embedding"))
-    //             .map(|f| f as f32)
-    //     })
-    //     .collect::<Result<Vec<f32>, _>>()?;
-
-    let use_local_db = false; // Setting this to false will download the embeddings from S3 and use them locally.
-    let vdb_client = VectorDb::new(use_local_db).await?;
     // Assess similarity of question_embeddings to other embeddings in the database
+    let vdb_client = state.open_database(&snapshot).await?;
+    let hits = vdb_client.search(&question_embeddings, top_k, &request.filters)?;
+    tracing::info!("Retrieved {} similar chunks", hits.len());
+
+    let sources: Vec<Source> = hits
+        .into_iter()
+        .map(|hit| Source {
+            id: hit.id,
+            score: hit.score,
+            text: hit.text,
+            metadata: hit.metadata,
+        })
+        .collect();
+
+    // Whatever the prompt needs besides the passages comes out of the context window first.
+    let bare = state.prompt.render(&prompt_vars(question, &[], &history))?;
+    let prompt_tokens = estimate_tokens(&bare.system)
+        + estimate_tokens(&bare.user)
+        + history
+            .iter()
+            .map(|turn| estimate_tokens(&turn.question) + estimate_tokens(&turn.answer))
+            .sum::<usize>();
+    let budget = context_budget(&config.retrieval, &params, prompt_tokens);
+    let (sources, context) = assemble_context(sources, budget);
+    tracing::info!(
+        "Using {} of {} passages ({} of {} context tokens)",
+        sources.len(),
+        context.passages.len(),
+        context.used_tokens,
+        context.budget_tokens
+    );
+
+    let rendered = state
+        .prompt
+        .render(&prompt_vars(question, &sources, &history))?;
 
-    // let similar_texts = vdb_client.search_similar(&question_embeddings, 5)?; // Top 5.
-    // println!("question: {}", question);
-    // println!("Similar texts: {:?}", similar_texts);
-
-    // TODO: Add similarity hits to the prompt.
-    let prompt = format!("Human: {}\n\nAssistant: ", question);
-    // let prompt = format!(
-    //     "Human: Context ({}), {}\n\nAssistant: ",
-    //     similar_texts.join("\n--\n"),
-    //     question
-    // );
-
-    let prompt_clone = prompt.clone();
-
-    let response_output = bedrock_client
-        .converse()
-        .model_id(model_name)
-        .messages(
-            Message::builder()
-                .role(ConversationRole::User)
-                .content(ContentBlock::Text(prompt_clone))
-                .build()
-                .map_err(|_| "failed to build message")?,
-        )
-        .send()
-        .await?;
-
-    // Create JSON with answer and metadata
+    Ok(Prepared::Fresh(PreparedQuestion {
+        conversation_id,
+        question: question.to_string(),
+        standalone_question,
+        history,
+        model: model_name.to_string(),
+        params,
+        requested_model: model_name.to_string(),
+        attempts: 0,
+        prompt_template: state.prompt.name.clone(),
+        prompt_version: state.prompt.version.clone(),
+        system_prompt: rendered.system,
+        prompt: rendered.user,
+        top_k,
+        sources,
+        context,
+        cache,
+        cache_key,
+    }))
+}
+
+/// Stores a finished turn so follow-up questions can refer back to it.
+/// Failing to store history shouldn't fail the answer, so errors are only logged.
+pub fn record_turn(state: &AppState, response: &AskResponse) {
+    let turn = Turn {
+        question: response.question.clone(),
+        answer: response.answer.clone(),
+        timestamp: chrono::Utc::now().to_rfc3339(),
+    };
+    if let Err(e) = state.sessions.append(&response.conversation_id, &turn) {
+        tracing::error!("Failed to store conversation turn: {:?}", e);
+    }
+}
+
+/// Records the turn and, when the question is cacheable, stores the answer in the answer cache.
+pub fn finish_answer(state: &AppState, cache_key: Option<&str>, response: &AskResponse) {
+    record_turn(state, response);
+    if let (Some(answers), Some(key)) = (&state.answers, cache_key) {
+        if let Err(e) = answers.put(key, response, chrono::Utc::now().timestamp()) {
+            tracing::error!("Failed to store answer in cache: {:?}", e);
+        }
+    }
+}
+
+// Ask Bedrock a question for the LLM to answer
+pub async fn ask_bedrock(request: &AskRequest, state: &AppState) -> Result<AskResponse, BoxError> {
+    let bedrock_client = &state.bedrock;
+    let mut prepared = match prepare_question(request, state, bedrock_client).await? {
+        Prepared::Cached(response) => {
+            record_turn(state, &response);
+            return Ok(response);
+        }
+        Prepared::Fresh(prepared) => prepared,
+    };
+
+    let messages = prepared.messages()?;
+    let system = prepared.system();
+    let answered = with_fallback(&state.config, &prepared.model, |model, params| {
+        let request = bedrock_client
+            .converse()
+            .model_id(model)
+            .set_system(system.clone())
+            .set_inference_config(inference_config(&params))
+            .set_messages(Some(messages.clone()));
+        async move { request.send().await.map_err(BedrockConverseError::from_sdk) }
+    })
+    .await?;
+    prepared.answered_by(&answered);
+    let response_output = answered.value;
+
+    // Create the response with answer and metadata
     // https://docs.aws.amazon.com/bedrock/latest/APIReference/API_runtime_Converse.html
     // https://docs.rs/aws-sdk-bedrockruntime/latest/aws_sdk_bedrockruntime/types/struct.ConverseMetrics.html
-    let response_json = json!({
-            "answer": match &response_output.output {
-                Some(output) => get_converse_output_text(response_output.clone())?,
-                None => return Err("No output content found".into()),
+    let usage = response_output.usage.clone();
+    let answer = get_converse_output_text(response_output)?;
+    let cache_key = prepared.cache_key.clone();
+    let response = prepared.into_response(
+        answer,
+        usage.as_ref().map(|u| u.input_tokens).unwrap_or(0),
+        usage.as_ref().map(|u| u.output_tokens).unwrap_or(0),
+    );
+    finish_answer(state, cache_key.as_deref(), &response);
+    Ok(response)
+}
+
+/// An answer as `ask_bedrock_stream` hands it back.
+// Built once per request and matched on right away, so the size difference doesn't matter.
+#[allow(clippy::large_enum_variant)]
+pub enum StreamedAnswer {
+    /// From the answer cache, to be replayed to the client in one go.
+    Cached(AskResponse),
+    Live(PreparedQuestion, BedrockAnswerStream),
+}
+
+/// Like `ask_bedrock`, but the answer is produced with ConverseStream and handed back as a
+/// stream of chunks instead of being awaited in full. For live answers the caller calls
+/// `finish_answer` once the stream has finished.
+pub async fn ask_bedrock_stream(
+    request: &AskRequest,
+    state: &AppState,
+) -> Result<StreamedAnswer, BoxError> {
+    let bedrock_client = &state.bedrock;
+    let mut prepared = match prepare_question(request, state, bedrock_client).await? {
+        Prepared::Cached(response) => {
+            record_turn(state, &response);
+            return Ok(StreamedAnswer::Cached(response));
+        }
+        Prepared::Fresh(prepared) => prepared,
+    };
+
+    // https://docs.aws.amazon.com/bedrock/latest/APIReference/API_runtime_ConverseStream.html
+    // Retries and fallbacks only cover starting the stream; a stream that fails part way ends
+    // with an error event.
+    let messages = prepared.messages()?;
+    let system = prepared.system();
+    let answered = with_fallback(&state.config, &prepared.model, |model, params| {
+        let request = bedrock_client
+            .converse_stream()
+            .model_id(model)
+            .set_system(system.clone())
+            .set_inference_config(inference_config(&params))
+            .set_messages(Some(messages.clone()));
+        async move { request.send().await.map_err(BedrockConverseError::from_sdk) }
+    })
+    .await?;
+    prepared.answered_by(&answered);
+
+    Ok(StreamedAnswer::Live(
+        prepared,
+        BedrockAnswerStream {
+            output: answered.value,
+        },
+    ))
+}
+
+/// Adapts a ConverseStream event stream to `AnswerStream`.
+pub struct BedrockAnswerStream {
+    output: ConverseStreamResponse,
+}
+
+impl AnswerStream for BedrockAnswerStream {
+    async fn next_chunk(&mut self) -> Result<Option<AnswerChunk>, String> {
+        loop {
+            let event = match self.output.stream.recv().await {
+                Ok(Some(event)) => event,
+                Ok(None) => return Ok(None),
+                Err(e) => return Err(DisplayErrorContext(&e).to_string()),
+            };
+            match event {
+                ConverseStreamEvent::ContentBlockDelta(delta) => {
+                    if let Some(ContentBlockDelta::Text(text)) = delta.delta {
+                        return Ok(Some(AnswerChunk::Text(text)));
+                    }
+                }
+                ConverseStreamEvent::MessageStop(stop) => {
+                    return Ok(Some(AnswerChunk::Stop(
+                        stop.stop_reason.as_str().to_string(),
+                    )));
+                }
+                ConverseStreamEvent::Metadata(metadata) => {
+                    if let Some(usage) = metadata.usage {
+                        return Ok(Some(AnswerChunk::Usage {
+                            input_tokens: usage.input_tokens,
+                            output_tokens: usage.output_tokens,
+                        }));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn turn(question: &str, answer: &str) -> Turn {
+        Turn {
+            question: question.to_string(),
+            answer: answer.to_string(),
+            timestamp: String::new(),
+        }
+    }
+
+    fn prepared(history: Vec<Turn>) -> PreparedQuestion {
+        PreparedQuestion {
+            conversation_id: "c1".to_string(),
+            question: "and how much does it cost per hour?".to_string(),
+            standalone_question: None,
+            history,
+            model: "test-model".to_string(),
+            params: ModelParams::default(),
+            requested_model: "test-model".to_string(),
+            attempts: 1,
+            prompt_template: "default".to_string(),
+            prompt_version: "1".to_string(),
+            system_prompt: String::new(),
+            prompt: "prompt".to_string(),
+            top_k: 5,
+            sources: vec![],
+            context: ContextReport::default(),
+            cache: CacheStatus::Skip,
+            cache_key: None,
+        }
+    }
+
+    #[test]
+    fn test_messages_include_history_in_order() {
+        let history = vec![
+            turn("What does Galaxy Design Agency offer?", "Web design."),
+            turn("Do they do logos?", "Yes."),
+        ];
+        let messages = prepared(history).messages().unwrap();
+        let roles: Vec<&ConversationRole> = messages.iter().map(|m| m.role()).collect();
+        assert_eq!(
+            roles,
+            vec![
+                &ConversationRole::User,
+                &ConversationRole::Assistant,
+                &ConversationRole::User,
+                &ConversationRole::Assistant,
+                &ConversationRole::User,
+            ]
+        );
+        assert_eq!(
+            messages[0].content()[0].as_text().unwrap(),
+            "What does Galaxy Design Agency offer?"
+        );
+        assert_eq!(messages[4].content()[0].as_text().unwrap(), "prompt");
+    }
+
+    #[test]
+    fn test_prompt_vars_number_passages() {
+        let sources = vec![
+            Source {
+                id: 4,
+                score: 0.8,
+                text: "first".to_string(),
+                metadata: Some(serde_json::json!({"source": "a.pdf", "chunk_index": 2})),
             },
-            "metadata": {
-                "model": model_name,
-                "prompt": prompt.to_string(),
-                "timestamp": chrono::Utc::now().to_rfc3339(),
-                "input_tokens": response_output.usage.as_ref().map(|u| u.input_tokens).unwrap_or(0),
-                "output_tokens": response_output.usage.as_ref().map(|u| u.output_tokens).unwrap_or(0),
-                "total_tokens": response_output.usage.as_ref()
-                    .map(|u| u.input_tokens + u.output_tokens)
-                    .unwrap_or(0),
+            Source {
+                id: 9,
+                score: 0.5,
+                text: "second".to_string(),
+                metadata: None,
             },
-            "question": question.to_string(),
-    });
+        ];
+        let vars = prompt_vars("q", &sources, &[turn("earlier", "answer")]);
+        assert_eq!(vars.passages[0].number, 1);
+        assert_eq!(vars.passages[0].source.as_deref(), Some("a.pdf"));
+        assert_eq!(vars.passages[0].chunk_index, Some(2));
+        assert_eq!(vars.passages[1].number, 2);
+        assert_eq!(vars.passages[1].source, None);
+        assert_eq!(vars.history[0].question, "earlier");
+        assert_eq!(vars.date.len(), 10);
+    }
+
+    #[test]
+    fn test_system_prompt_is_omitted_when_empty() {
+        let mut prepared = prepared(vec![]);
+        assert!(prepared.system().is_none());
+        prepared.system_prompt = "Be helpful.".to_string();
+        assert_eq!(prepared.system().unwrap().len(), 1);
+    }
+
+    fn source(id: i64, text: &str) -> Source {
+        Source {
+            id,
+            score: 1.0 / id as f32,
+            text: text.to_string(),
+            metadata: None,
+        }
+    }
+
+    #[test]
+    fn test_assemble_context_packs_by_rank() {
+        let long = "The board shows a map of North America. ".repeat(20);
+        let sources = vec![
+            source(1, &"a".repeat(400)),
+            source(2, &long),
+            source(3, "short"),
+        ];
+        let (included, report) = assemble_context(sources, 150);
 
-    // Convert to string
-    let json_string = serde_json::to_string(&response_json)?;
+        let statuses: Vec<PassageStatus> = report.passages.iter().map(|p| p.status).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                PassageStatus::Included,
+                PassageStatus::Truncated,
+                PassageStatus::Dropped
+            ]
+        );
+        assert_eq!(included.len(), 2);
+        assert!(included[1].text.ends_with("North America."));
+        assert!(report.used_tokens <= report.budget_tokens);
+        assert_eq!(
+            report.used_tokens,
+            report.passages[0].tokens + report.passages[1].tokens
+        );
+    }
+
+    #[test]
+    fn test_assemble_context_drops_when_too_little_room() {
+        let sources = vec![source(1, &"a".repeat(400)), source(2, &"b".repeat(400))];
+        let (included, report) = assemble_context(sources, 110);
+        assert_eq!(included.len(), 1);
+        assert_eq!(report.passages[1].status, PassageStatus::Dropped);
+        assert_eq!(report.passages[1].tokens, 100);
+    }
 
-    Ok(json_string.to_string())
+    #[test]
+    fn test_truncate_at_sentence() {
+        let text = "Each player gets 45 cars. Version 1.5 added routes! Is it fun? Yes";
+        assert_eq!(
+            truncate_at_sentence(text, 40).as_deref(),
+            Some("Each player gets 45 cars.")
+        );
+        assert_eq!(
+            truncate_at_sentence(text, 62).as_deref(),
+            Some("Each player gets 45 cars. Version 1.5 added routes! Is it fun?")
+        );
+        assert_eq!(
+            truncate_at_sentence("no sentence ends here at all", 20).as_deref(),
+            Some("no sentence ends…")
+        );
+        assert_eq!(truncate_at_sentence("unbreakable", 5), None);
+    }
+
+    #[test]
+    fn test_context_budget_leaves_room_for_answer() {
+        let retrieval = RetrievalConfig {
+            max_context_tokens: 4000,
+            context_window_tokens: 5000,
+            answer_tokens: 1000,
+            ..Default::default()
+        };
+        let defaults = ModelParams::default();
+        assert_eq!(context_budget(&retrieval, &defaults, 500), 3500);
+        assert_eq!(context_budget(&retrieval, &defaults, 100), 3900);
+        assert_eq!(context_budget(&retrieval, &defaults, 10_000), 0);
+
+        let long_answers = ModelParams {
+            max_tokens: Some(3000),
+            ..Default::default()
+        };
+        assert_eq!(context_budget(&retrieval, &long_answers, 500), 1500);
+    }
+
+    #[test]
+    fn test_condense_prompt() {
+        let prompt = condense_prompt(
+            &[turn("What does Galaxy Design Agency offer?", "Web design.")],
+            "and how much does it cost per hour?",
+        );
+        assert!(
+            prompt.contains("User: What does Galaxy Design Agency offer?\nAssistant: Web design.")
+        );
+        assert!(prompt.contains("Follow-up question: and how much does it cost per hour?"));
+        assert!(prompt.ends_with("Standalone question:"));
+    }
 }
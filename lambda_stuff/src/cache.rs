@@ -0,0 +1,283 @@
+use crate::api::AskResponse;
+use anyhow::Result;
+use common::config::CacheConfig;
+use lru::LruCache;
+use rusqlite::{Connection, OptionalExtension};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+
+/// Lower-cases, collapses whitespace and drops trailing punctuation, so "How much is the
+/// retainer?" and "how much is the  retainer" share a cache entry.
+pub fn normalize_question(question: &str) -> String {
+    question
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .trim_end_matches(['?', '.', '!'])
+        .trim_end()
+        .to_lowercase()
+}
+
+/// Everything an answer depends on, apart from conversation history (follow-up questions
+/// are never cached).
+#[derive(Debug)]
+pub struct CacheKey<'a> {
+    pub question: &'a str,
+    pub model: &'a str,
+    pub prompt_template: &'a str,
+    pub prompt_version: &'a str,
+    /// See `VectorDb::published_version`; changes whenever the corpus does.
+    pub snapshot: &'a str,
+    pub top_k: usize,
+    pub filters: &'a HashMap<String, Value>,
+}
+
+impl CacheKey<'_> {
+    pub fn digest(&self) -> String {
+        // Filters are sorted so the key doesn't depend on HashMap iteration order.
+        let filters: BTreeMap<&String, &Value> = self.filters.iter().collect();
+        let parts = serde_json::json!([
+            normalize_question(self.question),
+            self.model,
+            self.prompt_template,
+            self.prompt_version,
+            self.snapshot,
+            self.top_k,
+            filters,
+        ]);
+        format!("{:x}", Sha256::digest(parts.to_string().as_bytes()))
+    }
+}
+
+#[derive(Clone)]
+struct Entry {
+    response: AskResponse,
+    stored_at: i64,
+}
+
+/// Answers to recent questions, kept in an in-process LRU and, if `cache.sqlite_path` is set,
+/// in a SQLite table as well. Entries expire after `cache.ttl_seconds`.
+pub struct AnswerCache {
+    memory: Mutex<LruCache<String, Entry>>,
+    sqlite: Option<Mutex<Connection>>,
+    ttl_seconds: i64,
+}
+
+impl AnswerCache {
+    /// `None` when the cache is disabled.
+    pub fn open(config: &CacheConfig) -> Result<Option<Self>> {
+        if !config.enabled {
+            return Ok(None);
+        }
+        let sqlite = if config.sqlite_path.is_empty() {
+            None
+        } else {
+            Some(Connection::open(&config.sqlite_path)?)
+        };
+        Ok(Some(Self::new(config, sqlite)?))
+    }
+
+    fn new(config: &CacheConfig, sqlite: Option<Connection>) -> Result<Self> {
+        if let Some(conn) = &sqlite {
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS answer_cache (
+                    key TEXT PRIMARY KEY,
+                    response TEXT NOT NULL,
+                    stored_at INTEGER NOT NULL
+                )",
+            )?;
+        }
+        let capacity = NonZeroUsize::new(config.capacity).unwrap_or(NonZeroUsize::MIN);
+        Ok(AnswerCache {
+            memory: Mutex::new(LruCache::new(capacity)),
+            sqlite: sqlite.map(Mutex::new),
+            ttl_seconds: config.ttl_seconds as i64,
+        })
+    }
+
+    fn is_fresh(&self, stored_at: i64, now: i64) -> bool {
+        now - stored_at < self.ttl_seconds
+    }
+
+    /// The cached answer for `key`, if there is one younger than the TTL.
+    pub fn get(&self, key: &str, now: i64) -> Result<Option<AskResponse>> {
+        {
+            let mut memory = self.memory.lock().unwrap();
+            match memory.get(key) {
+                Some(entry) if self.is_fresh(entry.stored_at, now) => {
+                    return Ok(Some(entry.response.clone()));
+                }
+                Some(_) => {
+                    memory.pop(key);
+                }
+                None => {}
+            }
+        }
+
+        let Some(sqlite) = &self.sqlite else {
+            return Ok(None);
+        };
+        let row: Option<(String, i64)> = sqlite
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT response, stored_at FROM answer_cache WHERE key = ?1",
+                [key],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        match row {
+            Some((response, stored_at)) if self.is_fresh(stored_at, now) => {
+                let response: AskResponse = serde_json::from_str(&response)?;
+                self.memory.lock().unwrap().put(
+                    key.to_string(),
+                    Entry {
+                        response: response.clone(),
+                        stored_at,
+                    },
+                );
+                Ok(Some(response))
+            }
+            Some(_) => {
+                sqlite
+                    .lock()
+                    .unwrap()
+                    .execute("DELETE FROM answer_cache WHERE key = ?1", [key])?;
+                Ok(None)
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn put(&self, key: &str, response: &AskResponse, now: i64) -> Result<()> {
+        self.memory.lock().unwrap().put(
+            key.to_string(),
+            Entry {
+                response: response.clone(),
+                stored_at: now,
+            },
+        );
+        if let Some(sqlite) = &self.sqlite {
+            sqlite.lock().unwrap().execute(
+                "INSERT OR REPLACE INTO answer_cache (key, response, stored_at) VALUES (?1, ?2, ?3)",
+                rusqlite::params![key, serde_json::to_string(response)?, now],
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::ResponseMetadata;
+
+    fn config(capacity: usize) -> CacheConfig {
+        CacheConfig {
+            enabled: true,
+            capacity,
+            ttl_seconds: 60,
+            sqlite_path: String::new(),
+        }
+    }
+
+    fn response(answer: &str) -> AskResponse {
+        AskResponse {
+            conversation_id: "c1".to_string(),
+            question: "q".to_string(),
+            answer: answer.to_string(),
+            sources: vec![],
+            metadata: ResponseMetadata {
+                model: "m".to_string(),
+                inference: Default::default(),
+                requested_model: "m".to_string(),
+                attempts: 1,
+                prompt_template: "default".to_string(),
+                prompt_version: "1".to_string(),
+                system_prompt: String::new(),
+                prompt: String::new(),
+                timestamp: String::new(),
+                top_k: 5,
+                history_turns: 0,
+                standalone_question: None,
+                context: Default::default(),
+                cache: Default::default(),
+                input_tokens: 1,
+                output_tokens: 2,
+                total_tokens: 3,
+            },
+        }
+    }
+
+    fn key<'a>(
+        question: &'a str,
+        snapshot: &'a str,
+        filters: &'a HashMap<String, Value>,
+    ) -> CacheKey<'a> {
+        CacheKey {
+            question,
+            model: "m",
+            prompt_template: "default",
+            prompt_version: "1",
+            snapshot,
+            top_k: 5,
+            filters,
+        }
+    }
+
+    #[test]
+    fn test_normalize_question() {
+        assert_eq!(
+            normalize_question("  Monthly retainer for Galaxy   Design Agency?? "),
+            "monthly retainer for galaxy design agency"
+        );
+    }
+
+    #[test]
+    fn test_cache_key() {
+        let none = HashMap::new();
+        let filters = HashMap::from([
+            ("source".to_string(), Value::from("a.pdf")),
+            ("chunk_index".to_string(), Value::from(1)),
+        ]);
+        assert_eq!(
+            key("How long is a game?", "10-10", &none).digest(),
+            key("how long is a game", "10-10", &none).digest()
+        );
+        assert_ne!(
+            key("How long is a game?", "10-10", &none).digest(),
+            key("How long is a game?", "11-11", &none).digest()
+        );
+        assert_ne!(
+            key("q", "1-1", &none).digest(),
+            key("q", "1-1", &filters).digest()
+        );
+    }
+
+    #[test]
+    fn test_memory_cache_ttl_and_eviction() {
+        let cache = AnswerCache::new(&config(1), None).unwrap();
+        cache.put("a", &response("first"), 100).unwrap();
+        assert_eq!(cache.get("a", 159).unwrap().unwrap().answer, "first");
+        assert!(cache.get("a", 160).unwrap().is_none());
+
+        cache.put("a", &response("first"), 200).unwrap();
+        cache.put("b", &response("second"), 200).unwrap();
+        assert!(cache.get("a", 200).unwrap().is_none());
+        assert_eq!(cache.get("b", 200).unwrap().unwrap().answer, "second");
+    }
+
+    #[test]
+    fn test_sqlite_cache_survives_memory() {
+        let conn = Connection::open_in_memory().unwrap();
+        let cache = AnswerCache::new(&config(1), Some(conn)).unwrap();
+        cache.put("a", &response("first"), 100).unwrap();
+        cache.put("b", &response("second"), 100).unwrap();
+        // "a" was evicted from the LRU but is still in SQLite.
+        assert_eq!(cache.get("a", 110).unwrap().unwrap().answer, "first");
+        assert!(cache.get("a", 500).unwrap().is_none());
+    }
+}
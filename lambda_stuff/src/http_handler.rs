@@ -1,147 +1,196 @@
-use crate::bedrock::ask_bedrock;
+use crate::api::{
+    check_model, html_response, json_response, negotiate, parse_ask_request,
+    validate_conversation_id, ApiError, AskRequest, CacheMode, ResponseFormat,
+};
+use crate::bedrock::{ask_bedrock, ask_bedrock_stream, finish_answer, BoxError, StreamedAnswer};
+use crate::render::{render_answer_page, render_chat_page};
+use crate::sse::{replay_answer, stream_answer};
+use crate::state::AppState;
 use anyhow::Result;
-use serde_json::json;
+use bytes::Bytes;
+use common::config::Config;
+use common::converse::BedrockConverseError;
+use std::sync::Arc;
 
 //use aws_config::from_env;
 //use aws_sdk_s3::Client;
 // use chrono::{DateTime, Utc};
+use lambda_http::http::Method;
 use lambda_http::{Body, Error, Request, RequestExt, Response};
+use lambda_runtime::streaming::{channel, Body as StreamingBody};
 //use serde_json::{json, Value};
 //use std::env;
 //use std::path::Path;
 //use tokio::fs;
 
-fn clean_text(text: &str) -> String {
-    let new_string: String = text.replace("\n", "<p/>").to_string();
-    new_string
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Route {
+    /// `/`, the chat interface, or the HTML answer page when `question_text` is given.
+    Page,
+    /// `/ask`, the JSON API.
+    Ask,
 }
 
-/// This is the main body for the AWS Lambda function.
+// Function URLs see the raw path, `cargo lambda watch` strips its `/lambda-url/<name>` prefix.
+fn route(path: &str) -> Route {
+    if path.trim_end_matches('/').ends_with("/ask") {
+        Route::Ask
+    } else {
+        Route::Page
+    }
+}
 
-pub(crate) async fn function_handler(event: Request) -> Result<Response<Body>, Error> {
-    tracing::info!("Received event: {:?}", event);
+fn header<'a>(event: &'a Request, name: &str) -> Option<&'a str> {
+    event.headers().get(name).and_then(|v| v.to_str().ok())
+}
 
-    let embeddings_model_name = "amazon.titan-embed-text-v2:0";
-    let model_name = "anthropic.claude-3-5-haiku-20241022-v1:0";
-    let query = event.query_string_parameters();
-    tracing::info!("Query parameters: {:?}", query);
-
-    let result = match query.first("question_text") {
-        Some(question_text) => {
-            tracing::info!("Processing question: {}", question_text);
-            match ask_bedrock(question_text, model_name, embeddings_model_name).await {
-                Ok(response) => {
-                    tracing::info!("Got response from Bedrock");
-                    match serde_json::from_str::<serde_json::Value>(&response) {
-                        Ok(parsed) => {
-                            let html_message = format!(
-                                r#"
-                                <html>
-                                <style>
-                                    .response-container {{
-                                        font-family: Arial, sans-serif;
-                                        margin: 20px;
-                                        padding: 15px;
-                                        border: 1px solid #ddd;
-                                        border-radius: 5px;
-                                    }}
-                                    .question, .answer, .metadata {{
-                                        margin: 10px 0;
-                                    }}
-                                    .metadata ul {{
-                                        list-style-type: none;
-                                        padding-left: 20px;
-                                    }}
-                                    .metadata li {{
-                                        margin: 5px 0;
-                                    }}
-                                </style>
-                                <body>
-                                <div class="response-container">
-                                    <div class="question">
-                                        <strong>Question:</strong> {}
-                                    </div>
-                                    <div class="answer">
-                                        <strong>Answer:</strong> {}
-                                    </div>
-                                    <div class="metadata">
-                                        <strong>Metadata:</strong>
-                                        <ul>
-                                            <li>Input Tokens: {}</li>
-                                            <li>Output Tokens: {}</li>
-                                            <li>Total Tokens: {}</li>
-                                            <li>Model: {}</li>
-                                            <li>Timestamp: {}</li>
-                                        </ul>
-                                    </div>
-                                </div>
-                                </body></html>
-                                "#,
-                                clean_text(parsed["question"].as_str().unwrap_or("")),
-                                clean_text(parsed["answer"].as_str().unwrap_or("")),
-                                parsed["metadata"]["input_tokens"],
-                                parsed["metadata"]["output_tokens"],
-                                parsed["metadata"]["total_tokens"],
-                                clean_text(parsed["metadata"]["model"].as_str().unwrap_or("")),
-                                parsed["metadata"]["timestamp"]
-                            );
-
-                            Ok(Response::builder()
-                                .status(200)
-                                .header("content-type", "text/html")
-                                .body(html_message.into())?)
-                        }
-                        Err(e) => {
-                            tracing::error!("Failed to parse JSON response: {:?}", e);
-                            Ok(Response::builder()
-                                .status(500)
-                                .header("content-type", "application/json")
-                                .body(
-                                    json!({
-                                        "error": "Failed to parse response",
-                                        "details": e.to_string(),
-                                        "raw_response": response
-                                    })
-                                    .to_string()
-                                    .into(),
-                                )?)
-                        }
+enum Action {
+    /// Serve the chat interface.
+    Chat,
+    /// Answer a question in the given format.
+    Ask(AskRequest, ResponseFormat),
+}
+
+/// Works out what is being asked and how the caller wants the answer, and checks that the
+/// configuration allows it.
+fn read_request(event: &Request, config: &Config) -> Result<Action, ApiError> {
+    let action = parse_action(event)?;
+    if let Action::Ask(request, _) = &action {
+        check_model(request, &config.models)?;
+    }
+    Ok(action)
+}
+
+fn parse_action(event: &Request) -> Result<Action, ApiError> {
+    let accept = header(event, "accept");
+    match (event.method(), route(event.uri().path())) {
+        (&Method::POST, Route::Ask) => {
+            let request = parse_ask_request(header(event, "content-type"), event.body().as_ref())?;
+            let format = negotiate(accept, ResponseFormat::Json).ok_or(ApiError::NotAcceptable)?;
+            Ok(Action::Ask(request, format))
+        }
+        (&Method::GET, route) => {
+            let default_format = match route {
+                Route::Page => ResponseFormat::Html,
+                Route::Ask => ResponseFormat::Json,
+            };
+            let format = negotiate(accept, default_format).ok_or(ApiError::NotAcceptable)?;
+
+            let query = event.query_string_parameters();
+            tracing::info!("Query parameters: {:?}", query);
+            match query
+                .first("question_text")
+                .filter(|q| !q.trim().is_empty())
+            {
+                Some(question) => {
+                    let conversation_id = query.first("conversation_id").map(str::to_string);
+                    if let Some(id) = &conversation_id {
+                        validate_conversation_id(id)?;
                     }
+                    let cache = match query.first("cache") {
+                        Some(cache) => CacheMode::parse(cache)?,
+                        None => CacheMode::Use,
+                    };
+                    let request = AskRequest {
+                        question: question.to_string(),
+                        conversation_id,
+                        cache,
+                        ..Default::default()
+                    };
+                    Ok(Action::Ask(request, format))
                 }
-                Err(e) => {
-                    tracing::error!("Error in ask_bedrock: {:?}", e);
-                    Ok(Response::builder()
-                        .status(500)
-                        .header("content-type", "application/json")
-                        .body(
-                            json!({
-                                "error": "Bedrock API error",
-                                "details": e.to_string(),
-                                "question": question_text,
-                                "model": model_name
-                            })
-                            .to_string()
-                            .into(),
-                        )?)
-                }
+                None if route == Route::Page && format == ResponseFormat::Html => Ok(Action::Chat),
+                None => Err(ApiError::MissingQuestion),
             }
         }
-        None => {
-            tracing::info!("No question_text parameter found");
-            Ok(Response::builder()
-                .status(400)
-                .header("content-type", "application/json")
-                .body(
-                    json!({
-                        "error": "Missing question_text parameter",
-                        "message": "Please provide a question_text parameter in your request"
-                    })
-                    .to_string()
-                    .into(),
-                )?)
+        _ => Err(ApiError::MethodNotAllowed),
+    }
+}
+
+/// The API error for a question that couldn't be answered. Errors that are already API errors
+/// pass through, failed model calls are `bedrock_error`, and anything else, like the session
+/// store, the S3 download or SQLite, is `internal_error`.
+fn answer_error(e: BoxError) -> ApiError {
+    if let Some(e) = e.downcast_ref::<ApiError>() {
+        return e.clone();
+    }
+    if let Some(e) = e.downcast_ref::<BedrockConverseError>() {
+        return ApiError::Bedrock(e.to_string());
+    }
+    ApiError::Internal(e.to_string())
+}
+
+fn render_error(e: askama::Error) -> Result<Response<Body>, Error> {
+    tracing::error!("Failed to render page: {:?}", e);
+    ApiError::Internal(e.to_string()).into_response()
+}
+
+async fn answer(state: &AppState, event: &Request) -> Result<Response<Body>, Error> {
+    let (request, format) = match read_request(event, &state.config) {
+        Ok(Action::Ask(request, format)) => (request, format),
+        Ok(Action::Chat) => {
+            return match render_chat_page() {
+                Ok(html) => html_response(200, html),
+                Err(e) => render_error(e),
+            };
+        }
+        Err(e) => {
+            tracing::info!("Rejected request: {}", e);
+            return e.into_response();
         }
     };
 
+    tracing::info!("Processing question: {}", request.question);
+    if format == ResponseFormat::EventStream {
+        // Without Lambda response streaming the events are buffered and sent in one go.
+        return match ask_bedrock_stream(&request, state).await {
+            Ok(StreamedAnswer::Cached(response)) => {
+                let mut events = String::new();
+                replay_answer(&response, &mut events).await?;
+                event_stream_response(events.into())
+            }
+            Ok(StreamedAnswer::Live(prepared, stream)) => {
+                let mut events = String::new();
+                if let Some(response) = stream_answer(&prepared, stream, &mut events).await? {
+                    finish_answer(state, prepared.cache_key.as_deref(), &response);
+                }
+                event_stream_response(events.into())
+            }
+            Err(e) => {
+                tracing::error!("Error in ask_bedrock_stream: {:?}", e);
+                answer_error(e).into_response()
+            }
+        };
+    }
+
+    match ask_bedrock(&request, state).await {
+        Ok(response) => {
+            tracing::info!("Got response from Bedrock");
+            match format {
+                ResponseFormat::Json | ResponseFormat::EventStream => json_response(200, &response),
+                ResponseFormat::Html => match render_answer_page(&response) {
+                    Ok(html) => html_response(200, html),
+                    Err(e) => render_error(e),
+                },
+            }
+        }
+        Err(e) => {
+            tracing::error!("Error in ask_bedrock: {:?}", e);
+            answer_error(e).into_response()
+        }
+    }
+}
+
+/// This is the main body for the AWS Lambda function.
+
+pub(crate) async fn function_handler(
+    state: &AppState,
+    event: Request,
+) -> Result<Response<Body>, Error> {
+    tracing::info!("Received event: {:?}", event);
+
+    let result = answer(state, &event).await;
+
     // Log the final result
     match &result {
         Ok(response) => tracing::info!("Returning response with status: {}", response.status()),
@@ -150,6 +199,73 @@ pub(crate) async fn function_handler(event: Request) -> Result<Response<Body>, E
 
     result
 }
+
+fn event_stream_response<B>(body: B) -> Result<Response<B>, Error> {
+    Ok(Response::builder()
+        .status(200)
+        .header("content-type", ResponseFormat::EventStream.media_type())
+        .header("cache-control", "no-cache")
+        .body(body)?)
+}
+
+/// Entry point when the function URL uses the RESPONSE_STREAM invoke mode.
+/// Answers requested as `text/event-stream` are written to the client as the model produces
+/// them; everything else is answered by `function_handler` and sent as a single chunk.
+pub(crate) async fn streaming_function_handler(
+    state: Arc<AppState>,
+    event: Request,
+) -> Result<Response<StreamingBody>, Error> {
+    if let Ok(Action::Ask(request, ResponseFormat::EventStream)) =
+        read_request(&event, &state.config)
+    {
+        tracing::info!("Streaming answer to question: {}", request.question);
+        match ask_bedrock_stream(&request, &state).await {
+            Ok(StreamedAnswer::Cached(response)) => {
+                let (mut sender, body) = channel();
+                tokio::spawn(async move {
+                    if let Err(e) = replay_answer(&response, &mut sender).await {
+                        tracing::error!("Failed to stream cached answer: {:?}", e);
+                    }
+                });
+                return event_stream_response(body);
+            }
+            Ok(StreamedAnswer::Live(prepared, stream)) => {
+                let (mut sender, body) = channel();
+                // The turn is recorded by the task, once the whole answer has been streamed.
+                let state = state.clone();
+                tokio::spawn(async move {
+                    match stream_answer(&prepared, stream, &mut sender).await {
+                        Ok(Some(response)) => {
+                            finish_answer(&state, prepared.cache_key.as_deref(), &response)
+                        }
+                        Ok(None) => {}
+                        Err(e) => tracing::error!("Failed to stream answer: {:?}", e),
+                    }
+                });
+                return event_stream_response(body);
+            }
+            Err(e) => {
+                tracing::error!("Error in ask_bedrock_stream: {:?}", e);
+                return Ok(into_streaming(answer_error(e).into_response()?));
+            }
+        }
+    }
+
+    Ok(into_streaming(function_handler(&state, event).await?))
+}
+
+fn into_streaming(response: Response<Body>) -> Response<StreamingBody> {
+    let (parts, body) = response.into_parts();
+    let (mut sender, streaming_body) = channel();
+    let bytes = Bytes::copy_from_slice(body.as_ref());
+    // The sender waits until the runtime starts reading the body, so it can't be awaited here.
+    tokio::spawn(async move {
+        if let Err(e) = sender.send_data(bytes).await {
+            tracing::error!("Failed to send response body: {:?}", e);
+        }
+    });
+    Response::from_parts(parts, streaming_body)
+}
 // pub(crate) async fn function_handler(event: Request) -> Result<Response<Body>, Error> {
 //     tracing::info!("Received event: {:?}", event);
 //     // let aws_region = "us-west-2";
@@ -161,7 +277,7 @@ pub(crate) async fn function_handler(event: Request) -> Result<Response<Body>, E
 //     let (_question_text, answer) = match query.first("question_text") {
 //         Some(question_text) => {
 //             tracing::info!("Processing question: {}", question_text);
-//             match ask_bedrock(question_text, model_name, embeddings_model_name).await {
+//             match ask_bedrock(question_text, config).await {
 //                 Ok(response) => (question_text, response),
 //                 Err(e) => {
 //                     tracing::error!("Error in ask_bedrock: {:?}", e);
@@ -258,41 +374,84 @@ pub(crate) async fn function_handler(event: Request) -> Result<Response<Body>, E
 #[cfg(test)]
 mod tests {
     use super::*;
-    use lambda_http::{Request, RequestExt};
-    use std::collections::HashMap;
+    use common::config::SessionStoreKind;
+    use lambda_http::Request;
 
-    #[tokio::test]
-    async fn test_generic_http_handler() {
-        let request = Request::default();
+    async fn test_state() -> AppState {
+        let mut config = Config::default();
+        config.conversation.store = SessionStoreKind::Memory;
+        config.embedding_cache.enabled = false;
+        AppState::new(config).await.unwrap()
+    }
 
-        let response = function_handler(request).await.unwrap();
-        assert_eq!(response.status(), 200);
+    #[test]
+    fn test_answer_error_codes() {
+        let passed: BoxError = Box::new(ApiError::MissingQuestion);
+        assert_eq!(answer_error(passed), ApiError::MissingQuestion);
+        let bedrock: BoxError = Box::new(BedrockConverseError::Throttled("slow down".into()));
+        assert_eq!(answer_error(bedrock).code(), "bedrock_error");
+        let sqlite: BoxError = anyhow::anyhow!("database is locked").into();
+        assert_eq!(answer_error(sqlite).code(), "internal_error");
+    }
 
-        let body_bytes = response.body().to_vec();
-        let body_string = String::from_utf8(body_bytes).unwrap();
+    fn request(method: &str, uri: &str, headers: &[(&str, &str)], body: &str) -> Request {
+        let mut builder = lambda_http::http::Request::builder()
+            .method(method)
+            .uri(uri);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(Body::from(body)).unwrap()
+    }
 
-        assert_eq!(
-            body_string,
-            "Hello world, this is an AWS Lambda HTTP request"
-        );
+    async fn error_code(request: Request) -> (u16, String) {
+        let response = function_handler(&test_state().await, request)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        (
+            response.status().as_u16(),
+            body["error"]["code"].as_str().unwrap().to_string(),
+        )
     }
 
     #[tokio::test]
-    async fn test_http_handler_with_query_string() {
-        let mut query_string_parameters: HashMap<String, String> = HashMap::new();
-        query_string_parameters.insert("name".into(), "lambda_stuff".into());
-
-        let request = Request::default().with_query_string_parameters(query_string_parameters);
-
-        let response = function_handler(request).await.unwrap();
+    async fn test_get_root_serves_chat_page() {
+        let response = function_handler(
+            &test_state().await,
+            request("GET", "/", &[("accept", "text/html")], ""),
+        )
+        .await
+        .unwrap();
         assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["content-type"], "text/html");
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        assert!(body.contains("<form id=\"ask-form\""));
+    }
 
-        let body_bytes = response.body().to_vec();
-        let body_string = String::from_utf8(body_bytes).unwrap();
-
+    #[tokio::test]
+    async fn test_routing_errors() {
+        assert_eq!(
+            error_code(request("GET", "/ask", &[], "")).await,
+            (400, "missing_question".to_string())
+        );
+        assert_eq!(
+            error_code(request("DELETE", "/", &[], "")).await,
+            (405, "method_not_allowed".to_string())
+        );
+        assert_eq!(
+            error_code(request("GET", "/", &[("accept", "image/png")], "")).await,
+            (406, "not_acceptable".to_string())
+        );
         assert_eq!(
-            body_string,
-            "Hello lambda_stuff, this is an AWS Lambda HTTP request"
+            error_code(request(
+                "POST",
+                "/ask",
+                &[("content-type", "text/plain")],
+                "How long is a game?"
+            ))
+            .await,
+            (415, "unsupported_media_type".to_string())
         );
     }
 }
@@ -1,12 +1,38 @@
+mod api;
 mod bedrock;
+mod cache;
 mod http_handler;
+mod render;
+mod session;
+mod sse;
+mod state;
 
-use http_handler::function_handler;
-use lambda_http::{run, service_fn, tracing, Error};
+use common::config::Config;
+use http_handler::{function_handler, streaming_function_handler};
+use lambda_http::{run, run_with_streaming_response, service_fn, tracing, Error, Request};
+use state::AppState;
+use std::sync::Arc;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     tracing::init_default_subscriber();
 
-    run(service_fn(function_handler)).await
+    let config = Config::load(None)?;
+    tracing::info!("Using chat model {}", config.models.chat_model);
+    let state = Arc::new(AppState::new(config).await?);
+
+    if state.config.server.response_streaming {
+        tracing::info!("Lambda response streaming enabled");
+        run_with_streaming_response(service_fn(move |event: Request| {
+            let state = state.clone();
+            async move { streaming_function_handler(state, event).await }
+        }))
+        .await
+    } else {
+        let state = &*state;
+        run(service_fn(move |event: Request| async move {
+            function_handler(state, event).await
+        }))
+        .await
+    }
 }
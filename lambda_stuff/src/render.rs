@@ -0,0 +1,168 @@
+use crate::api::{AskResponse, ResponseMetadata, Source};
+use askama::Template;
+use pulldown_cmark::{html, Options, Parser};
+
+// HTML rendering for the answer page. Everything that comes from the user or the model is
+// either escaped by askama (the template is .html, so auto-escaping is on) or, for the
+// answer itself, rendered from Markdown and then passed through ammonia.
+
+#[derive(Template)]
+#[template(path = "answer.html")]
+struct AnswerPage<'a> {
+    question: &'a str,
+    answer_html: String,
+    sources: Vec<SourceView>,
+    metadata: &'a ResponseMetadata,
+}
+
+struct SourceView {
+    label: String,
+    score: String,
+}
+
+impl SourceView {
+    fn from_source(source: &Source) -> Self {
+        let metadata = source.metadata.as_ref();
+        let document = metadata
+            .and_then(|m| m.get("source"))
+            .and_then(|s| s.as_str());
+        let chunk = metadata
+            .and_then(|m| m.get("chunk_index"))
+            .and_then(|c| c.as_u64());
+        let label = match (document, chunk) {
+            (Some(document), Some(chunk)) => format!("{} #{}", document, chunk),
+            (Some(document), None) => document.to_string(),
+            _ => format!("chunk {}", source.id),
+        };
+        SourceView {
+            label,
+            score: format!("{:.2}", source.score),
+        }
+    }
+}
+
+/// The chat interface served at `/`. Its stylesheet and script are compiled into the binary
+/// and inlined, so the page needs nothing else from the server apart from `POST /ask`.
+#[derive(Template)]
+#[template(path = "chat.html")]
+struct ChatPage {
+    css: &'static str,
+    js: &'static str,
+}
+
+const CHAT_CSS: &str = include_str!("../static/app.css");
+const CHAT_JS: &str = include_str!("../static/app.js");
+
+pub fn render_chat_page() -> Result<String, askama::Error> {
+    ChatPage {
+        css: CHAT_CSS,
+        js: CHAT_JS,
+    }
+    .render()
+}
+
+/// Renders Markdown (as produced by the model) into HTML that is safe to embed in a page.
+pub fn markdown_to_safe_html(markdown: &str) -> String {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_TABLES);
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+
+    let mut unsafe_html = String::new();
+    html::push_html(&mut unsafe_html, Parser::new_ext(markdown, options));
+
+    // Markdown allows raw HTML, so the output has to be sanitized, not just the input.
+    ammonia::clean(&unsafe_html)
+}
+
+pub fn render_answer_page(response: &AskResponse) -> Result<String, askama::Error> {
+    AnswerPage {
+        question: &response.question,
+        answer_html: markdown_to_safe_html(&response.answer),
+        sources: response
+            .sources
+            .iter()
+            .map(SourceView::from_source)
+            .collect(),
+        metadata: &response.metadata,
+    }
+    .render()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn response(question: &str, answer: &str) -> AskResponse {
+        AskResponse {
+            conversation_id: "c1".to_string(),
+            question: question.to_string(),
+            answer: answer.to_string(),
+            sources: vec![Source {
+                id: 7,
+                score: 0.8123,
+                text: "<b>chunk</b>".to_string(),
+                metadata: Some(json!({"source": "<monopoly>.pdf", "chunk_index": 2})),
+            }],
+            metadata: ResponseMetadata {
+                model: "anthropic.claude-3-5-haiku-20241022-v1:0".to_string(),
+                inference: Default::default(),
+                requested_model: "anthropic.claude-3-5-haiku-20241022-v1:0".to_string(),
+                attempts: 1,
+                prompt_template: "default".to_string(),
+                prompt_version: "1".to_string(),
+                system_prompt: String::new(),
+                prompt: String::new(),
+                timestamp: "2025-02-20T00:00:00+00:00".to_string(),
+                top_k: 5,
+                history_turns: 0,
+                standalone_question: None,
+                context: Default::default(),
+                cache: Default::default(),
+                input_tokens: 10,
+                output_tokens: 20,
+                total_tokens: 30,
+            },
+        }
+    }
+
+    #[test]
+    fn test_markdown_is_rendered() {
+        let html = markdown_to_safe_html("The retainer is **$5,000** per month.\n\n- one\n- two");
+        assert!(html.contains("<strong>$5,000</strong>"));
+        assert!(html.contains("<li>one</li>"));
+    }
+
+    #[test]
+    fn test_hostile_question_is_escaped() {
+        let page =
+            render_answer_page(&response("<script>alert('xss')</script>", "An answer")).unwrap();
+        assert!(!page.contains("<script>alert"));
+        assert!(page.contains("&#60;script&#62;"));
+    }
+
+    #[test]
+    fn test_hostile_answer_is_sanitized() {
+        let answer = "Sure! <script>alert(1)</script>\n\n<img src=x onerror=\"alert(2)\">\n\n[click](javascript:alert(3))";
+        let page = render_answer_page(&response("question", answer)).unwrap();
+        assert!(!page.contains("<script>alert"));
+        assert!(!page.contains("onerror"));
+        assert!(!page.contains("javascript:"));
+        assert!(page.contains("Sure!"));
+    }
+
+    #[test]
+    fn test_chat_page_embeds_assets() {
+        let page = render_chat_page().unwrap();
+        assert!(page.contains(r#"id="ask-form""#));
+        assert!(page.contains("function parseFrame"));
+        assert!(page.contains(".ask-form textarea"));
+    }
+
+    #[test]
+    fn test_source_labels_are_escaped() {
+        let page = render_answer_page(&response("question", "answer")).unwrap();
+        assert!(page.contains("&#60;monopoly&#62;.pdf #2"));
+        assert!(page.contains("score 0.81"));
+    }
+}
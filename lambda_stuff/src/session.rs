@@ -0,0 +1,155 @@
+use anyhow::Result;
+use common::config::{ConversationConfig, SessionStoreKind};
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// One question and the answer that was given to it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Turn {
+    pub question: String,
+    pub answer: String,
+    pub timestamp: String,
+}
+
+/// Keeps conversation history between requests, keyed by conversation id.
+pub trait SessionStore: Send + Sync {
+    /// The last `limit` turns of a conversation, oldest first.
+    fn history(&self, conversation_id: &str, limit: usize) -> Result<Vec<Turn>>;
+
+    fn append(&self, conversation_id: &str, turn: &Turn) -> Result<()>;
+}
+
+pub fn open_session_store(config: &ConversationConfig) -> Result<Box<dyn SessionStore>> {
+    Ok(match config.store {
+        SessionStoreKind::Sqlite => Box::new(SqliteSessionStore::open(&config.sqlite_path)?),
+        SessionStoreKind::Memory => Box::new(InMemorySessionStore::default()),
+    })
+}
+
+/// Session store backed by a local SQLite file. On Lambda this lives in `/tmp`, so history
+/// survives between invocations of a warm instance but not across instances.
+pub struct SqliteSessionStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteSessionStore {
+    pub fn open(path: &str) -> Result<Self> {
+        Self::from_connection(Connection::open(path)?)
+    }
+
+    pub fn from_connection(conn: Connection) -> Result<Self> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS conversation_turns (
+                id INTEGER PRIMARY KEY,
+                conversation_id TEXT NOT NULL,
+                question TEXT NOT NULL,
+                answer TEXT NOT NULL,
+                created_at TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS idx_conversation_turns_conversation
+                ON conversation_turns (conversation_id, id);",
+        )?;
+        Ok(SqliteSessionStore {
+            conn: Mutex::new(conn),
+        })
+    }
+}
+
+impl SessionStore for SqliteSessionStore {
+    fn history(&self, conversation_id: &str, limit: usize) -> Result<Vec<Turn>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT question, answer, created_at FROM (
+                SELECT id, question, answer, created_at FROM conversation_turns
+                WHERE conversation_id = ?1
+                ORDER BY id DESC
+                LIMIT ?2
+            ) ORDER BY id ASC",
+        )?;
+        let turns = stmt
+            .query_map(rusqlite::params![conversation_id, limit as i64], |row| {
+                Ok(Turn {
+                    question: row.get(0)?,
+                    answer: row.get(1)?,
+                    timestamp: row.get(2)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<Turn>>>()?;
+        Ok(turns)
+    }
+
+    fn append(&self, conversation_id: &str, turn: &Turn) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO conversation_turns (conversation_id, question, answer, created_at)
+             VALUES (?1, ?2, ?3, ?4)",
+            rusqlite::params![conversation_id, turn.question, turn.answer, turn.timestamp],
+        )?;
+        Ok(())
+    }
+}
+
+/// Session store that only lives as long as the process. Used in tests and when
+/// `conversation.store = "memory"`.
+#[derive(Default)]
+pub struct InMemorySessionStore {
+    turns: Mutex<HashMap<String, Vec<Turn>>>,
+}
+
+impl SessionStore for InMemorySessionStore {
+    fn history(&self, conversation_id: &str, limit: usize) -> Result<Vec<Turn>> {
+        let turns = self.turns.lock().unwrap();
+        let history = turns.get(conversation_id).map(Vec::as_slice).unwrap_or(&[]);
+        Ok(history[history.len().saturating_sub(limit)..].to_vec())
+    }
+
+    fn append(&self, conversation_id: &str, turn: &Turn) -> Result<()> {
+        self.turns
+            .lock()
+            .unwrap()
+            .entry(conversation_id.to_string())
+            .or_default()
+            .push(turn.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn turn(n: usize) -> Turn {
+        Turn {
+            question: format!("question {}", n),
+            answer: format!("answer {}", n),
+            timestamp: format!("2025-02-20T00:00:0{}+00:00", n),
+        }
+    }
+
+    fn check_store(store: &dyn SessionStore) {
+        assert!(store.history("a", 5).unwrap().is_empty());
+        for n in 1..=4 {
+            store.append("a", &turn(n)).unwrap();
+        }
+        store.append("b", &turn(9)).unwrap();
+
+        let history = store.history("a", 2).unwrap();
+        assert_eq!(history, vec![turn(3), turn(4)]);
+        assert_eq!(store.history("a", 10).unwrap().len(), 4);
+        assert_eq!(store.history("b", 10).unwrap(), vec![turn(9)]);
+        assert!(store.history("a", 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_in_memory_store() {
+        check_store(&InMemorySessionStore::default());
+    }
+
+    #[test]
+    fn test_sqlite_store() {
+        let store =
+            SqliteSessionStore::from_connection(Connection::open_in_memory().unwrap()).unwrap();
+        check_store(&store);
+    }
+}
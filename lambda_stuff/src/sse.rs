@@ -0,0 +1,304 @@
+use crate::api::{ApiError, AskResponse, ResponseMetadata, Source};
+use crate::bedrock::PreparedQuestion;
+use bytes::Bytes;
+use lambda_http::Error;
+use serde::Serialize;
+use serde_json::json;
+
+// Server-Sent Events for streamed answers. A stream is always framed as:
+//
+//   event: sources   data: [Source, ...]            (once, before any text)
+//   event: delta     data: {"text": "..."}          (zero or more)
+//   event: done      data: DoneEvent                (once, on success)
+//   event: error     data: {"error": {...}}         (instead of `done` if the model stream fails)
+
+/// A piece of a streamed model answer.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AnswerChunk {
+    Text(String),
+    Usage {
+        input_tokens: i32,
+        output_tokens: i32,
+    },
+    Stop(String),
+}
+
+/// Source of answer chunks; ConverseStream in production, a canned list in tests.
+pub(crate) trait AnswerStream {
+    async fn next_chunk(&mut self) -> Result<Option<AnswerChunk>, String>;
+}
+
+/// Where SSE frames are written: a buffer for regular responses, or the Lambda response stream.
+pub(crate) trait SseSink {
+    async fn send(&mut self, frame: String) -> Result<(), Error>;
+}
+
+impl SseSink for String {
+    async fn send(&mut self, frame: String) -> Result<(), Error> {
+        self.push_str(&frame);
+        Ok(())
+    }
+}
+
+impl SseSink for lambda_runtime::streaming::Sender {
+    async fn send(&mut self, frame: String) -> Result<(), Error> {
+        self.send_data(Bytes::from(frame)).await.map_err(Into::into)
+    }
+}
+
+/// Points at a retrieved chunk the answer was grounded on.
+#[derive(Debug, Clone, Serialize)]
+pub struct Citation {
+    pub id: i64,
+    pub source: Option<String>,
+    pub chunk_index: Option<u64>,
+    /// The outline path of the chunk's section, when the document had bookmarks.
+    pub heading: Option<String>,
+    pub score: f32,
+}
+
+impl From<&Source> for Citation {
+    fn from(source: &Source) -> Self {
+        let metadata = source.metadata.as_ref();
+        Citation {
+            id: source.id,
+            source: metadata
+                .and_then(|m| m.get("source"))
+                .and_then(|s| s.as_str())
+                .map(str::to_string),
+            chunk_index: metadata
+                .and_then(|m| m.get("chunk_index"))
+                .and_then(|c| c.as_u64()),
+            heading: metadata
+                .and_then(|m| m.get("heading"))
+                .and_then(|h| h.as_str())
+                .map(str::to_string),
+            score: source.score,
+        }
+    }
+}
+
+/// Payload of the final `done` event.
+#[derive(Debug, Clone, Serialize)]
+pub struct DoneEvent {
+    pub conversation_id: String,
+    pub answer: String,
+    pub stop_reason: Option<String>,
+    pub citations: Vec<Citation>,
+    pub metadata: ResponseMetadata,
+}
+
+pub fn frame(event: &str, data: &impl Serialize) -> String {
+    // serde_json never emits raw newlines, so the payload always fits on one `data:` line.
+    let data = serde_json::to_string(data).unwrap_or_else(|_| "null".to_string());
+    format!("event: {}\ndata: {}\n\n", event, data)
+}
+
+/// Forwards `stream` to `sink` as SSE frames, finishing with a `done` (or `error`) event.
+/// Returns the complete response, or `None` if the model stream failed.
+/// Only failures to write to the sink are returned as errors.
+pub(crate) async fn stream_answer<S, K>(
+    prepared: &PreparedQuestion,
+    mut stream: S,
+    sink: &mut K,
+) -> Result<Option<AskResponse>, Error>
+where
+    S: AnswerStream,
+    K: SseSink,
+{
+    sink.send(frame("sources", &prepared.sources)).await?;
+
+    let mut answer = String::new();
+    let mut stop_reason = None;
+    let (mut input_tokens, mut output_tokens) = (0, 0);
+    loop {
+        match stream.next_chunk().await {
+            Ok(Some(AnswerChunk::Text(text))) => {
+                answer.push_str(&text);
+                sink.send(frame("delta", &json!({ "text": text }))).await?;
+            }
+            Ok(Some(AnswerChunk::Usage {
+                input_tokens: input,
+                output_tokens: output,
+            })) => {
+                input_tokens = input;
+                output_tokens = output;
+            }
+            Ok(Some(AnswerChunk::Stop(reason))) => stop_reason = Some(reason),
+            Ok(None) => break,
+            Err(e) => {
+                tracing::error!("Answer stream failed: {}", e);
+                sink.send(frame("error", &ApiError::Bedrock(e).to_json()))
+                    .await?;
+                return Ok(None);
+            }
+        }
+    }
+
+    let response = prepared
+        .clone()
+        .into_response(answer, input_tokens, output_tokens);
+    let done = DoneEvent {
+        conversation_id: response.conversation_id.clone(),
+        answer: response.answer.clone(),
+        stop_reason,
+        citations: response.sources.iter().map(Citation::from).collect(),
+        metadata: response.metadata.clone(),
+    };
+    sink.send(frame("done", &done)).await?;
+    Ok(Some(response))
+}
+
+/// Sends an answer that is already complete, such as one from the answer cache, with the
+/// same framing as a streamed one: its sources, the whole answer as a single delta, then `done`.
+pub(crate) async fn replay_answer<K: SseSink>(
+    response: &AskResponse,
+    sink: &mut K,
+) -> Result<(), Error> {
+    sink.send(frame("sources", &response.sources)).await?;
+    sink.send(frame("delta", &json!({ "text": response.answer })))
+        .await?;
+    let done = DoneEvent {
+        conversation_id: response.conversation_id.clone(),
+        answer: response.answer.clone(),
+        stop_reason: None,
+        citations: response.sources.iter().map(Citation::from).collect(),
+        metadata: response.metadata.clone(),
+    };
+    sink.send(frame("done", &done)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+    use std::collections::VecDeque;
+
+    struct MockAnswerStream {
+        chunks: VecDeque<Result<AnswerChunk, String>>,
+    }
+
+    impl MockAnswerStream {
+        fn new(chunks: Vec<Result<AnswerChunk, String>>) -> Self {
+            MockAnswerStream {
+                chunks: chunks.into(),
+            }
+        }
+    }
+
+    impl AnswerStream for MockAnswerStream {
+        async fn next_chunk(&mut self) -> Result<Option<AnswerChunk>, String> {
+            self.chunks.pop_front().transpose()
+        }
+    }
+
+    fn prepared() -> PreparedQuestion {
+        PreparedQuestion {
+            conversation_id: "c1".to_string(),
+            question: "How many train cars does each player get?".to_string(),
+            standalone_question: None,
+            history: vec![],
+            model: "test-model".to_string(),
+            params: Default::default(),
+            requested_model: "test-model".to_string(),
+            attempts: 1,
+            prompt_template: "default".to_string(),
+            prompt_version: "1".to_string(),
+            system_prompt: String::new(),
+            prompt: "prompt".to_string(),
+            top_k: 5,
+            sources: vec![Source {
+                id: 3,
+                score: 0.9,
+                text: "Each player takes a set of 45 Colored Train Cars".to_string(),
+                metadata: Some(json!({"source": "ticket_to_ride.pdf", "chunk_index": 1})),
+            }],
+            context: Default::default(),
+            cache: Default::default(),
+            cache_key: None,
+        }
+    }
+
+    fn parse_frames(body: &str) -> Vec<(String, Value)> {
+        assert!(body.ends_with("\n\n"));
+        body.trim_end()
+            .split("\n\n")
+            .map(|frame| {
+                let mut lines = frame.lines();
+                let event = lines.next().unwrap().strip_prefix("event: ").unwrap();
+                let data = lines.next().unwrap().strip_prefix("data: ").unwrap();
+                assert!(lines.next().is_none());
+                (event.to_string(), serde_json::from_str(data).unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_frame_format() {
+        assert_eq!(
+            frame("delta", &json!({"text": "line one\nline two"})),
+            "event: delta\ndata: {\"text\":\"line one\\nline two\"}\n\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stream_answer_framing() {
+        let stream = MockAnswerStream::new(vec![
+            Ok(AnswerChunk::Text("Each player ".to_string())),
+            Ok(AnswerChunk::Text("gets 45.".to_string())),
+            Ok(AnswerChunk::Stop("end_turn".to_string())),
+            Ok(AnswerChunk::Usage {
+                input_tokens: 100,
+                output_tokens: 7,
+            }),
+        ]);
+        let mut body = String::new();
+        let response = stream_answer(&prepared(), stream, &mut body).await.unwrap();
+        assert_eq!(response.unwrap().answer, "Each player gets 45.");
+
+        let frames = parse_frames(&body);
+        let events: Vec<&str> = frames.iter().map(|(e, _)| e.as_str()).collect();
+        assert_eq!(events, vec!["sources", "delta", "delta", "done"]);
+        assert_eq!(frames[0].1[0]["id"], 3);
+        assert_eq!(frames[1].1["text"], "Each player ");
+
+        let done = &frames[3].1;
+        assert_eq!(done["answer"], "Each player gets 45.");
+        assert_eq!(done["conversation_id"], "c1");
+        assert_eq!(done["stop_reason"], "end_turn");
+        assert_eq!(done["metadata"]["input_tokens"], 100);
+        assert_eq!(done["metadata"]["total_tokens"], 107);
+        assert_eq!(done["citations"][0]["source"], "ticket_to_ride.pdf");
+        assert_eq!(done["citations"][0]["chunk_index"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_stream_error_ends_with_error_event() {
+        let stream = MockAnswerStream::new(vec![
+            Ok(AnswerChunk::Text("Partial".to_string())),
+            Err("ThrottlingException".to_string()),
+        ]);
+        let mut body = String::new();
+        let response = stream_answer(&prepared(), stream, &mut body).await.unwrap();
+        assert!(response.is_none());
+
+        let frames = parse_frames(&body);
+        let (event, data) = frames.last().unwrap();
+        assert_eq!(event, "error");
+        assert_eq!(data["error"]["code"], "bedrock_error");
+        assert!(!frames.iter().any(|(e, _)| e == "done"));
+    }
+
+    #[tokio::test]
+    async fn test_replay_answer_matches_stream_framing() {
+        let response = prepared().into_response("Each player gets 45.".to_string(), 100, 7);
+        let mut body = String::new();
+        replay_answer(&response, &mut body).await.unwrap();
+
+        let frames = parse_frames(&body);
+        let events: Vec<&str> = frames.iter().map(|(e, _)| e.as_str()).collect();
+        assert_eq!(events, vec!["sources", "delta", "done"]);
+        assert_eq!(frames[1].1["text"], "Each player gets 45.");
+        assert_eq!(frames[2].1["metadata"]["total_tokens"], 107);
+    }
+}
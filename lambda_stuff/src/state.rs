@@ -0,0 +1,74 @@
+use crate::cache::AnswerCache;
+use crate::session::{open_session_store, SessionStore};
+use anyhow::{Context, Result};
+use aws_sdk_bedrockruntime::Client as BedrockClient;
+use aws_sdk_s3::Client as S3Client;
+use common::config::Config;
+use common::embeddings::Embedder;
+use common::prompts::PromptTemplate;
+use common::vectordb::{PublishedSnapshot, VectorDb};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Everything the handlers share across invocations of a warm Lambda instance.
+pub struct AppState {
+    pub config: Config,
+    pub sessions: Box<dyn SessionStore>,
+    /// Loaded once at startup so a broken template fails the cold start, not every request.
+    pub prompt: PromptTemplate,
+    /// `None` when `cache.enabled` is off.
+    pub answers: Option<AnswerCache>,
+    pub embedder: Embedder,
+    /// Shared by every Converse call; it holds the connection pool and the credentials.
+    pub bedrock: BedrockClient,
+    /// Reads the published database's version on every request, and downloads it when new.
+    pub s3: S3Client,
+    /// Snapshot version of the database downloaded to `database.local_path`, if any.
+    local_snapshot: Mutex<Option<String>>,
+}
+
+impl AppState {
+    pub async fn new(config: Config) -> Result<Self> {
+        let sessions = open_session_store(&config.conversation)?;
+        let prompt =
+            PromptTemplate::load(Path::new(&config.prompts.dir), &config.prompts.template)?;
+        let answers = AnswerCache::open(&config.cache)?;
+        let aws_config = aws_config::load_from_env().await;
+        let bedrock = BedrockClient::new(&aws_config);
+        let s3 = S3Client::new(&aws_config);
+        let embedder = Embedder::with_client(&config, bedrock.clone())?;
+        Ok(AppState {
+            config,
+            sessions,
+            prompt,
+            answers,
+            embedder,
+            bedrock,
+            s3,
+            local_snapshot: Mutex::new(None),
+        })
+    }
+
+    /// Opens the published database `snapshot`. It is only downloaded when the local copy is
+    /// of another version, so a warm instance fetches each publish once, and the download
+    /// must match the snapshot's ETag, so a publish in between is never recorded under it.
+    pub async fn open_database(&self, snapshot: &PublishedSnapshot) -> Result<VectorDb> {
+        let database = &self.config.database;
+        let current =
+            self.local_snapshot.lock().unwrap().as_deref() == Some(snapshot.version.as_str());
+        if !current || !Path::new(&database.local_path).exists() {
+            tracing::info!("Downloading embeddings database {}", snapshot.version);
+            VectorDb::download_from_s3(
+                &self.s3,
+                &database.local_path,
+                &database.s3_bucket,
+                &database.s3_key,
+                snapshot.e_tag.as_deref(),
+            )
+            .await
+            .context("Failed to download database from S3")?;
+            *self.local_snapshot.lock().unwrap() = Some(snapshot.version.clone());
+        }
+        VectorDb::open_local(database)
+    }
+}
@@ -0,0 +1,227 @@
+// Chat page for ragtime. Talks to POST <base>/ask and renders each answer as it arrives.
+// The server may answer with a text/event-stream (delta/sources/done/error events) or with
+// a single JSON document; both are handled. All text is inserted with textContent.
+// The conversation id returned by the server is sent back with each question, so follow-ups
+// are answered with the earlier turns as context; "New chat" forgets it.
+(function () {
+    "use strict";
+
+    // Works both at the function URL root and behind `cargo lambda watch`'s /lambda-url/<name> prefix.
+    const base = window.location.pathname.replace(/\/?$/, "/");
+    const askUrl = base + "ask";
+
+    const history = document.getElementById("history");
+    const form = document.getElementById("ask-form");
+    const input = document.getElementById("question");
+    const send = document.getElementById("send");
+    const newChat = document.getElementById("new-chat");
+
+    // Conversation kept in the page: [{question, answer, sources, metadata}]
+    const turns = [];
+    let conversationId = null;
+
+    function element(tag, className, text) {
+        const el = document.createElement(tag);
+        if (className) {
+            el.className = className;
+        }
+        if (text !== undefined) {
+            el.textContent = text;
+        }
+        return el;
+    }
+
+    function sourceLabel(source) {
+        const meta = source.metadata || {};
+        let label = meta.source || "chunk " + source.id;
+        if (meta.chunk_index !== undefined) {
+            label += " #" + meta.chunk_index;
+        }
+        return label + " (score " + Number(source.score).toFixed(2) + ")";
+    }
+
+    function addTurn(question) {
+        const turn = { question: question, answer: "", sources: [], metadata: null };
+        turns.push(turn);
+
+        const view = element("article", "turn");
+        view.appendChild(element("div", "question", question));
+        const answer = element("div", "answer pending");
+        view.appendChild(answer);
+        const sources = element("div", "sources");
+        view.appendChild(sources);
+        const meta = element("div", "meta");
+        view.appendChild(meta);
+        history.appendChild(view);
+        view.scrollIntoView({ behavior: "smooth", block: "end" });
+
+        return {
+            appendAnswer: function (text) {
+                turn.answer += text;
+                answer.textContent = turn.answer;
+            },
+            setAnswer: function (text) {
+                turn.answer = text;
+                answer.textContent = text;
+            },
+            setSources: function (list) {
+                turn.sources = list || [];
+                sources.textContent = "";
+                if (turn.sources.length === 0) {
+                    return;
+                }
+                sources.appendChild(element("strong", null, "Sources"));
+                turn.sources.forEach(function (source) {
+                    const details = element("details");
+                    details.appendChild(element("summary", null, sourceLabel(source)));
+                    details.appendChild(element("p", null, source.text));
+                    sources.appendChild(details);
+                });
+            },
+            finish: function (metadata) {
+                turn.metadata = metadata || null;
+                answer.classList.remove("pending");
+                if (metadata) {
+                    meta.textContent = metadata.model + " · " + metadata.total_tokens + " tokens";
+                }
+            },
+            fail: function (message) {
+                answer.classList.remove("pending");
+                answer.classList.add("error");
+                answer.textContent = message;
+            },
+        };
+    }
+
+    function errorMessage(body, status) {
+        if (body && body.error && body.error.message) {
+            return body.error.message;
+        }
+        return "Request failed with status " + status;
+    }
+
+    // Parses one SSE frame ("event: x\ndata: {...}") into {event, data}.
+    function parseFrame(frame) {
+        let event = "message";
+        const data = [];
+        frame.split("\n").forEach(function (line) {
+            if (line.startsWith("event:")) {
+                event = line.slice(6).trim();
+            } else if (line.startsWith("data:")) {
+                data.push(line.slice(5).replace(/^ /, ""));
+            }
+        });
+        if (data.length === 0) {
+            return null;
+        }
+        return { event: event, data: JSON.parse(data.join("\n")) };
+    }
+
+    function handleEvent(view, message) {
+        switch (message.event) {
+            case "sources":
+                view.setSources(message.data);
+                break;
+            case "delta":
+                view.appendAnswer(message.data.text);
+                break;
+            case "done":
+                if (message.data.conversation_id) {
+                    conversationId = message.data.conversation_id;
+                }
+                if (message.data.sources) {
+                    view.setSources(message.data.sources);
+                }
+                view.finish(message.data.metadata);
+                break;
+            case "error":
+                view.fail(errorMessage(message.data, ""));
+                break;
+        }
+    }
+
+    async function readStream(response, view) {
+        const reader = response.body.getReader();
+        const decoder = new TextDecoder();
+        let buffer = "";
+        for (;;) {
+            const chunk = await reader.read();
+            if (chunk.done) {
+                break;
+            }
+            buffer += decoder.decode(chunk.value, { stream: true });
+            let boundary;
+            while ((boundary = buffer.indexOf("\n\n")) >= 0) {
+                const message = parseFrame(buffer.slice(0, boundary));
+                buffer = buffer.slice(boundary + 2);
+                if (message) {
+                    handleEvent(view, message);
+                }
+            }
+        }
+        view.finish(turns[turns.length - 1].metadata);
+    }
+
+    async function ask(question) {
+        const view = addTurn(question);
+        const request = { question: question };
+        if (conversationId) {
+            request.conversation_id = conversationId;
+        }
+        try {
+            const response = await fetch(askUrl, {
+                method: "POST",
+                headers: {
+                    "content-type": "application/json",
+                    accept: "text/event-stream, application/json;q=0.9",
+                },
+                body: JSON.stringify(request),
+            });
+            const contentType = response.headers.get("content-type") || "";
+            if (response.ok && contentType.startsWith("text/event-stream") && response.body) {
+                await readStream(response, view);
+                return;
+            }
+            const body = await response.json().catch(function () {
+                return null;
+            });
+            if (!response.ok) {
+                view.fail(errorMessage(body, response.status));
+                return;
+            }
+            conversationId = body.conversation_id || conversationId;
+            view.setAnswer(body.answer);
+            view.setSources(body.sources);
+            view.finish(body.metadata);
+        } catch (err) {
+            view.fail("Could not reach the server: " + err.message);
+        }
+    }
+
+    form.addEventListener("submit", async function (event) {
+        event.preventDefault();
+        const question = input.value.trim();
+        if (!question) {
+            return;
+        }
+        input.value = "";
+        send.disabled = true;
+        await ask(question);
+        send.disabled = false;
+        input.focus();
+    });
+
+    newChat.addEventListener("click", function () {
+        conversationId = null;
+        turns.length = 0;
+        history.textContent = "";
+        input.focus();
+    });
+
+    input.addEventListener("keydown", function (event) {
+        if (event.key === "Enter" && !event.shiftKey) {
+            event.preventDefault();
+            form.requestSubmit();
+        }
+    });
+})();
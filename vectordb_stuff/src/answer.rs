@@ -0,0 +1,51 @@
+use anyhow::{Context, Result};
+use aws_sdk_bedrockruntime::types::{ContentBlock, ConversationRole, Message};
+use aws_sdk_bedrockruntime::Client as BedrockClient;
+use common::config::Config;
+use common::converse::{
+    get_converse_output_text, inference_config, system_prompt, with_fallback, BedrockConverseError,
+};
+use common::prompts::{Passage, PromptTemplate, PromptVars};
+use common::vectordb::SearchHit;
+use std::path::Path;
+
+/// Answers `question` from `hits` with the configured chat model and prompt template, the way
+/// the Lambda would for a first question, retries and fallback models included. There is no
+/// history or context budget.
+pub async fn generate(config: &Config, question: &str, hits: &[SearchHit]) -> Result<String> {
+    let template = PromptTemplate::load(Path::new(&config.prompts.dir), &config.prompts.template)?;
+    let prompt = template.render(&prompt_vars(question, hits))?;
+    let model = &config.models.chat_model;
+
+    let aws_config = aws_config::load_from_env().await;
+    let client = BedrockClient::new(&aws_config);
+    let message = Message::builder()
+        .role(ConversationRole::User)
+        .content(ContentBlock::Text(prompt.user))
+        .build()?;
+    let system = system_prompt(&prompt.system);
+    let answered = with_fallback(config, model, |model, params| {
+        let request = client
+            .converse()
+            .model_id(model)
+            .set_system(system.clone())
+            .set_inference_config(inference_config(&params))
+            .messages(message.clone());
+        async move { request.send().await.map_err(BedrockConverseError::from_sdk) }
+    })
+    .await
+    .with_context(|| format!("Converse call to {} failed", model))?;
+    if &answered.model != model {
+        tracing::warn!("{} failed; answered with {}", model, answered.model);
+    }
+    Ok(get_converse_output_text(answered.value)?)
+}
+
+fn prompt_vars(question: &str, hits: &[SearchHit]) -> PromptVars {
+    let passages = hits
+        .iter()
+        .enumerate()
+        .map(|(i, hit)| Passage::from_chunk(i + 1, &hit.text, hit.metadata.as_ref(), hit.score))
+        .collect();
+    PromptVars::new(question, passages, Vec::new())
+}
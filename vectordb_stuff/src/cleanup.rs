@@ -0,0 +1,237 @@
+//! Cleanup of the text `pdf_extract` returns, before it is chunked.
+
+use common::config::ExtractionConfig;
+use regex::{Captures, Regex};
+use std::collections::{HashMap, HashSet};
+use unicode_normalization::UnicodeNormalization;
+
+/// Non-blank lines at the top and at the bottom of a page that may be a header or footer.
+const EDGE_LINES: usize = 2;
+
+/// Fewest pages a line must be repeated on to count as a header or footer.
+const MIN_REPEATS: usize = 3;
+
+/// Cleans each page as configured and joins them into one text, pages separated by a blank line.
+pub fn clean_pages(pages: &[String], extraction: &ExtractionConfig) -> String {
+    let mut pages: Vec<String> = if extraction.normalize_unicode {
+        pages.iter().map(|p| normalize_unicode(p)).collect()
+    } else {
+        pages.to_vec()
+    };
+    if extraction.strip_headers_footers {
+        pages = strip_headers_footers(&pages);
+    }
+    let mut text = pages.join("\n\n");
+    if extraction.repair_hyphenation {
+        text = repair_hyphenation(&text);
+    }
+    if extraction.collapse_whitespace {
+        text = collapse_whitespace(&text);
+    }
+    text
+}
+
+/// NFKC, which also splits ligatures such as "ﬁ" and "ﬀ" into letters. Soft hyphens go too.
+fn normalize_unicode(text: &str) -> String {
+    text.nfkc().filter(|&c| c != '\u{ad}').collect()
+}
+
+/// Removes the lines found among the first or last `EDGE_LINES` lines of at least half the
+/// pages (and at least `MIN_REPEATS` of them). Lines are compared with digits masked, so
+/// "Page 2" and "Page 3" match.
+fn strip_headers_footers(pages: &[String]) -> Vec<String> {
+    let edges: Vec<Vec<usize>> = pages.iter().map(|p| edge_lines(p)).collect();
+    let mut repeats: HashMap<String, usize> = HashMap::new();
+    for (page, edges) in pages.iter().zip(&edges) {
+        let lines: Vec<&str> = page.lines().collect();
+        let keys: HashSet<String> = edges.iter().map(|&i| line_key(lines[i])).collect();
+        for key in keys {
+            *repeats.entry(key).or_default() += 1;
+        }
+    }
+    let needed = MIN_REPEATS.max(pages.len().div_ceil(2));
+    let repeated: HashSet<&String> = repeats
+        .iter()
+        .filter(|(_, &count)| count >= needed)
+        .map(|(key, _)| key)
+        .collect();
+    if repeated.is_empty() {
+        return pages.to_vec();
+    }
+    for key in &repeated {
+        tracing::debug!("Removing header/footer line {:?}", key);
+    }
+
+    pages
+        .iter()
+        .zip(&edges)
+        .map(|(page, edges)| {
+            page.lines()
+                .enumerate()
+                .filter(|(i, line)| !(edges.contains(i) && repeated.contains(&line_key(line))))
+                .map(|(_, line)| line)
+                .collect::<Vec<_>>()
+                .join("\n")
+        })
+        .collect()
+}
+
+/// Positions of the first and last `EDGE_LINES` non-blank lines of `page`.
+fn edge_lines(page: &str) -> Vec<usize> {
+    let filled: Vec<usize> = page
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, _)| i)
+        .collect();
+    let mut edges: Vec<usize> = filled.iter().take(EDGE_LINES).copied().collect();
+    edges.extend(filled.iter().rev().take(EDGE_LINES));
+    edges.sort_unstable();
+    edges.dedup();
+    edges
+}
+
+fn line_key(line: &str) -> String {
+    let mut key = String::new();
+    for word in line.split_whitespace() {
+        if !key.is_empty() {
+            key.push(' ');
+        }
+        let mut last_digit = false;
+        for c in word.chars() {
+            if c.is_ascii_digit() {
+                if !last_digit {
+                    key.push('#');
+                }
+            } else {
+                key.push(c);
+            }
+            last_digit = c.is_ascii_digit();
+        }
+    }
+    key
+}
+
+/// Joins words split by a hyphen at the end of a line. "excur-" + "sion" becomes "excursion",
+/// but the hyphen stays when the second half is a word found elsewhere in the text and the
+/// joined word isn't, as with "color-" + "group".
+fn repair_hyphenation(text: &str) -> String {
+    let hyphenated = Regex::new(r"(\p{L}+)-[ \t]*\r?\n[ \t]*(\p{Ll}+)").unwrap();
+    let elsewhere = hyphenated.replace_all(text, " ");
+    let words: HashSet<String> = Regex::new(r"\p{L}+")
+        .unwrap()
+        .find_iter(&elsewhere)
+        .map(|m| m.as_str().to_lowercase())
+        .collect();
+    hyphenated
+        .replace_all(text, |caps: &Captures| {
+            let (head, tail) = (&caps[1], &caps[2]);
+            let joined = format!("{}{}", head, tail);
+            if !words.contains(&joined.to_lowercase()) && words.contains(&tail.to_lowercase()) {
+                format!("{}-{}", head, tail)
+            } else {
+                joined
+            }
+        })
+        .into_owned()
+}
+
+/// Collapses runs of spaces and tabs to one space, trims every line and keeps at most one
+/// blank line between paragraphs.
+fn collapse_whitespace(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut blank_lines = 0;
+    for line in text.lines() {
+        let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        if line.is_empty() {
+            blank_lines += 1;
+            continue;
+        }
+        if !out.is_empty() {
+            out.push_str(if blank_lines > 0 { "\n\n" } else { "\n" });
+        }
+        out.push_str(&line);
+        blank_lines = 0;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pages extracted from the bundled PDFs by `pdf_extract`, separated by form feeds, and the
+    /// text `clean_pages` should make of them.
+    const FIXTURES: [(&str, &str); 2] = [
+        (
+            include_str!("../fixtures/galaxy-design-client-guide.pages.txt"),
+            include_str!("../fixtures/galaxy-design-client-guide.clean.txt"),
+        ),
+        (
+            include_str!("../fixtures/ticket_to_ride.pages.txt"),
+            include_str!("../fixtures/ticket_to_ride.clean.txt"),
+        ),
+    ];
+
+    fn pages(fixture: &str) -> Vec<String> {
+        fixture.split('\x0c').map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_clean_pages_fixtures() {
+        for (before, after) in FIXTURES {
+            assert_eq!(
+                clean_pages(&pages(before), &ExtractionConfig::default()),
+                after.trim_end()
+            );
+        }
+    }
+
+    #[test]
+    fn test_headers_and_page_numbers_are_stripped() {
+        let (before, _) = FIXTURES[0];
+        let cleaned = clean_pages(&pages(before), &ExtractionConfig::default());
+        assert!(!cleaned.contains("Client Guide 2024"));
+        assert!(!cleaned.lines().any(|l| l.trim().parse::<u32>().is_ok()));
+        assert!(cleaned.contains("flexible scheduling options"));
+
+        let (before, _) = FIXTURES[1];
+        let cleaned = clean_pages(&pages(before), &ExtractionConfig::default());
+        assert!(!cleaned.contains("[T2R] rules EN reprint"));
+        assert!(cleaned.contains("a more modest excursion and wager"));
+    }
+
+    #[test]
+    fn test_cleanup_can_be_turned_off() {
+        let (before, _) = FIXTURES[0];
+        let extraction = ExtractionConfig {
+            strip_headers_footers: false,
+            repair_hyphenation: false,
+            normalize_unicode: false,
+            collapse_whitespace: false,
+            ..ExtractionConfig::default()
+        };
+        assert_eq!(
+            clean_pages(&pages(before), &extraction),
+            pages(before).join("\n\n")
+        );
+    }
+
+    #[test]
+    fn test_repair_hyphenation() {
+        let text = "Any complete color-\ngroup. A modest excur-\nsion, one-\n half price.\n\
+                    Each group pays half.";
+        assert_eq!(
+            repair_hyphenation(text),
+            "Any complete color-group. A modest excursion, one-half price.\nEach group pays half."
+        );
+    }
+
+    #[test]
+    fn test_line_key_masks_numbers() {
+        assert_eq!(
+            line_key(" [T2R] rules  06/03/15 Page12 "),
+            "[T#R] rules #/#/# Page#"
+        );
+    }
+}
@@ -1,4 +1,8 @@
-use clap::{CommandFactory, Parser};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use common::config::{Chunker, DedupMode};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
 
 #[derive(Parser)]
 #[command(author,
@@ -6,20 +10,341 @@ use clap::{CommandFactory, Parser};
      about = "Set up the vector database for the RAG project.",
      long_about = None)]
 pub struct Cli {
-    /// Clear the database before proceeding
+    /// Path to a TOML config file (defaults to $RAGTIME_CONFIG, then ./ragtime.toml)
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Embeddings database to work on (overrides database.local_path)
+    #[arg(long, global = true, value_name = "PATH")]
+    pub collection: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Extract, chunk and embed PDFs, then publish the database to S3
+    Ingest(IngestArgs),
+    /// Search the local database for the chunks closest to a question
+    Query(QueryArgs),
+    /// Summarize what the database holds
+    Stats(FormatArgs),
+    /// List the ingested documents and their status
+    ListDocs(FormatArgs),
+    /// Remove a document's chunks, or every chunk matching a metadata filter
+    DeleteDoc(DeleteDocArgs),
+    /// Re-load one PDF, swapping its chunks in a single transaction
+    ReplaceDoc(ReplaceDocArgs),
+    /// Change a chunk's metadata without re-embedding it
+    UpdateChunk(UpdateChunkArgs),
+    /// Write every chunk with its embedding and metadata to a file
+    Export(ExportArgs),
+    /// Add chunks written by `export` to the database, without calling Bedrock
+    Import(ImportArgs),
+    /// Upload the local database to S3
+    Publish(PublishArgs),
+    /// Check the database for interrupted documents and inconsistent rows
+    Verify,
+    /// Print a shell completion script
+    Completions { shell: Shell },
+    /// Inspect the configuration
+    #[command(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(Args)]
+pub struct IngestArgs {
+    /// PDF files or directories of PDFs (defaults to ingestion.pdf_dir)
+    pub paths: Vec<PathBuf>,
+
+    /// How to split the extracted text (overrides chunking.chunker)
+    #[arg(long, value_enum)]
+    pub chunker: Option<ChunkerArg>,
+
+    /// Maximum chunk length in bytes (overrides chunking.chunk_size)
+    #[arg(long)]
+    pub chunk_size: Option<usize>,
+
+    /// Bytes shared by neighbouring chunks (overrides chunking.chunk_overlap)
+    #[arg(long)]
+    pub chunk_overlap: Option<usize>,
+
+    /// Bedrock embeddings model (overrides models.embeddings_model)
+    #[arg(long)]
+    pub model: Option<String>,
+
+    /// Which repeated chunks to leave out (overrides dedup.mode)
+    #[arg(long, value_enum)]
+    pub dedup: Option<DedupArg>,
+
+    /// Password for encrypted PDFs (overrides $RAGTIME_PDF_PASSWORD)
+    #[arg(long, value_name = "PASSWORD")]
+    pub pdf_password: Option<String>,
+
+    /// Remove every document from the database first
+    #[arg(long, conflicts_with = "resume")]
+    pub clear: bool,
+
+    /// Skip documents that were loaded completely and continue interrupted ones from their
+    /// last committed chunk
+    #[arg(long)]
+    pub resume: bool,
+
+    /// Add chunks even though the database holds ones embedded with a different model
+    #[arg(long)]
+    pub force: bool,
+
+    /// Leave the database local instead of uploading it to S3 afterwards
+    #[arg(long)]
+    pub no_publish: bool,
+}
+
+#[derive(Args)]
+pub struct QueryArgs {
+    /// The question to search for
+    pub question: String,
+
+    /// Number of chunks to show (overrides retrieval.top_k)
+    #[arg(long, short = 'k')]
+    pub top_k: Option<usize>,
+
+    /// Bedrock embeddings model; must match the one the database was built with
+    #[arg(long)]
+    pub model: Option<String>,
+
+    /// Only search chunks whose metadata has this value, e.g. `source=monopoly.pdf`.
+    /// The value is read as JSON when it parses, so `chunk_index=3` matches a number.
+    /// May be repeated.
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_filter)]
+    pub filter: Vec<(String, Value)>,
+
+    /// Also answer the question from the hits, as the Lambda would
+    #[arg(long)]
+    pub answer: bool,
+
+    /// Chat model for --answer (overrides models.chat_model)
+    #[arg(long, requires = "answer")]
+    pub chat_model: Option<String>,
+
+    #[command(flatten)]
+    pub output: FormatArgs,
+}
+
+fn parse_filter(filter: &str) -> Result<(String, Value), String> {
+    let (key, value) = filter
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=VALUE, got {:?}", filter))?;
+    let value = serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()));
+    Ok((key.to_string(), value))
+}
+
+#[derive(Args)]
+pub struct FormatArgs {
+    #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+    pub format: OutputFormat,
+}
+
+#[derive(Args)]
+#[group(required = true, multiple = false)]
+pub struct DeleteDocArgs {
+    /// Source name of the document, as shown by `list-docs`
+    pub source: Option<String>,
+
+    /// Remove the chunks whose metadata has this value instead, e.g. `chunk_index=0`.
+    /// May be repeated; a chunk must match all of them.
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_filter)]
+    pub filter: Vec<(String, Value)>,
+
+    /// Remove every document and chunk
+    #[arg(long)]
+    pub all: bool,
+}
+
+#[derive(Args)]
+pub struct ReplaceDocArgs {
+    /// The PDF to load; it replaces the document with the same file name
+    pub path: PathBuf,
+
+    /// Bedrock embeddings model (overrides models.embeddings_model)
+    #[arg(long)]
+    pub model: Option<String>,
+
+    /// Password for an encrypted PDF (overrides $RAGTIME_PDF_PASSWORD)
+    #[arg(long, value_name = "PASSWORD")]
+    pub pdf_password: Option<String>,
+
+    /// Load it even though the database holds chunks embedded with a different model
+    #[arg(long)]
+    pub force: bool,
+}
+
+#[derive(Args)]
+pub struct UpdateChunkArgs {
+    /// Chunk id, as shown by `query --format json` or `export`
+    pub id: i64,
+
+    /// Set a metadata key; the value is read as JSON when it parses. May be repeated.
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_filter, required_unless_present = "unset")]
+    pub set: Vec<(String, Value)>,
+
+    /// Remove a metadata key. May be repeated.
+    #[arg(long, value_name = "KEY")]
+    pub unset: Vec<String>,
+}
+
+#[derive(Args)]
+pub struct ExportArgs {
+    /// File to write, or `-` for stdout
+    #[arg(long, short, default_value = "-")]
+    pub output: PathBuf,
+
+    /// Defaults to parquet for `.parquet` files and jsonl otherwise
+    #[arg(long, value_enum)]
+    pub format: Option<DataFormat>,
+}
+
+#[derive(Args)]
+pub struct ImportArgs {
+    /// File written by `export`
+    pub input: PathBuf,
+
+    /// Defaults to parquet for `.parquet` files and jsonl otherwise
+    #[arg(long, value_enum)]
+    pub format: Option<DataFormat>,
+
+    /// Import chunks embedded with a different model or dimensions than the database's
     #[arg(long)]
-    pub clear_database: bool,
+    pub force: bool,
+}
 
-    /// Load documents into the database from the local `pdfs` directory
+#[derive(Args)]
+pub struct PublishArgs {
+    /// Upload even if `verify` finds problems
     #[arg(long)]
-    pub load_documents: bool,
+    pub force: bool,
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Print the effective settings after the file and environment overrides are applied
+    Show,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ChunkerArg {
+    Recursive,
+    Sentences,
+}
+
+impl From<ChunkerArg> for Chunker {
+    fn from(arg: ChunkerArg) -> Self {
+        match arg {
+            ChunkerArg::Recursive => Chunker::Recursive,
+            ChunkerArg::Sentences => Chunker::Sentences,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum DedupArg {
+    Off,
+    Skip,
+    Link,
+}
+
+impl From<DedupArg> for DedupMode {
+    fn from(arg: DedupArg) -> Self {
+        match arg {
+            DedupArg::Off => DedupMode::Off,
+            DedupArg::Skip => DedupMode::Skip,
+            DedupArg::Link => DedupMode::Link,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Table,
+    Json,
+}
+
+/// File formats for `export` and `import`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+pub enum DataFormat {
+    /// One JSON object per chunk and line
+    Jsonl,
+    /// Apache Parquet, with the embedding as a list of floats
+    Parquet,
+}
+
+impl DataFormat {
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("parquet") => DataFormat::Parquet,
+            _ => DataFormat::Jsonl,
+        }
+    }
 }
 
 pub fn parse_args() -> Cli {
-    let cli = Cli::parse();
-    if !cli.clear_database && !cli.load_documents {
-        Cli::command().print_help().unwrap();
-        std::process::exit(1);
+    Cli::parse()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_cli_definition() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_parse_ingest() {
+        let cli = Cli::try_parse_from([
+            "vectordb_stuff",
+            "--collection",
+            "/tmp/test.db",
+            "ingest",
+            "pdfs/monopoly.pdf",
+            "--chunker",
+            "sentences",
+            "--resume",
+            "--pdf-password",
+            "secret",
+        ])
+        .unwrap();
+        assert_eq!(cli.collection, Some(PathBuf::from("/tmp/test.db")));
+        match cli.command {
+            Command::Ingest(args) => {
+                assert_eq!(args.paths, vec![PathBuf::from("pdfs/monopoly.pdf")]);
+                assert!(matches!(args.chunker, Some(ChunkerArg::Sentences)));
+                assert!(args.resume);
+                assert_eq!(args.pdf_password.as_deref(), Some("secret"));
+            }
+            _ => panic!("expected ingest"),
+        }
+        assert!(Cli::try_parse_from(["vectordb_stuff", "ingest", "--clear", "--resume"]).is_err());
+        assert!(Cli::try_parse_from(["vectordb_stuff", "delete-doc"]).is_err());
+        assert!(Cli::try_parse_from(["vectordb_stuff", "delete-doc", "a.pdf", "--all"]).is_err());
+        assert!(Cli::try_parse_from(["vectordb_stuff", "update-chunk", "7"]).is_err());
+        assert!(
+            Cli::try_parse_from(["vectordb_stuff", "update-chunk", "7", "--unset", "page"]).is_ok()
+        );
+    }
+
+    #[test]
+    fn test_parse_filter() {
+        assert_eq!(
+            parse_filter("source=monopoly.pdf").unwrap(),
+            ("source".to_string(), Value::from("monopoly.pdf"))
+        );
+        assert_eq!(
+            parse_filter("chunk_index=3").unwrap(),
+            ("chunk_index".to_string(), Value::from(3))
+        );
+        assert!(parse_filter("source").is_err());
     }
-    cli
 }
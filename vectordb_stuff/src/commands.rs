@@ -0,0 +1,440 @@
+use crate::cli::{
+    Cli, DataFormat, DeleteDocArgs, ExportArgs, FormatArgs, ImportArgs, IngestArgs, OutputFormat,
+    PublishArgs, QueryArgs, ReplaceDocArgs, UpdateChunkArgs,
+};
+use crate::{answer, export, ingest};
+use anyhow::{bail, Context, Result};
+use clap::CommandFactory;
+use clap_complete::Shell;
+use common::config::Config;
+use common::embeddings::Embedder;
+use common::vectordb::VectorDb;
+use indicatif::MultiProgress;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::path::Path;
+
+/// Opens the local database for commands that only make sense once something was ingested.
+fn open_existing(config: &Config) -> Result<VectorDb> {
+    if !Path::new(&config.database.local_path).exists() {
+        bail!(
+            "No database at {}; run `ingest` first or pass --collection",
+            config.database.local_path
+        );
+    }
+    let vdb_client = VectorDb::open_local(&config.database)?;
+    vdb_client.create_embeddings_table()?;
+    Ok(vdb_client)
+}
+
+pub async fn ingest(mut config: Config, args: IngestArgs, progress: &MultiProgress) -> Result<()> {
+    if let Some(chunker) = args.chunker {
+        config.chunking.chunker = chunker.into();
+    }
+    if let Some(chunk_size) = args.chunk_size {
+        config.chunking.chunk_size = chunk_size;
+    }
+    if let Some(chunk_overlap) = args.chunk_overlap {
+        config.chunking.chunk_overlap = chunk_overlap;
+    }
+    if let Some(model) = args.model {
+        config.models.embeddings_model = model;
+    }
+    if let Some(dedup) = args.dedup {
+        config.dedup.mode = dedup.into();
+    }
+    if let Some(password) = args.pdf_password {
+        config.extraction.password = Some(password);
+    }
+    config.validate()?;
+
+    let pdf_filenames = ingest::resolve_inputs(&args.paths, &config.ingestion.pdf_dir)?;
+    let vdb_client = if args.no_publish {
+        VectorDb::open_local(&config.database)?
+    } else {
+        let use_local_db = true;
+        VectorDb::new(&config.database, use_local_db).await?
+    };
+    if args.clear {
+        vdb_client.drop_embeddings_table()?;
+    }
+
+    let summary = ingest::load_documents(
+        &config,
+        &vdb_client,
+        &pdf_filenames,
+        args.resume,
+        args.force,
+        progress,
+    )
+    .await?;
+    println!("{}", serde_json::to_string_pretty(&summary)?);
+    // Quarantined files fail the same way every time, so they don't hold back publishing.
+    let retryable = summary.documents_failed - summary.quarantined.len();
+    if retryable > 0 {
+        bail!(
+            "{} documents failed to load; run again with --resume to continue",
+            retryable
+        );
+    }
+    for path in &summary.quarantined {
+        tracing::warn!("Quarantined {}: it can't be read until it is fixed", path);
+    }
+    if !args.no_publish {
+        // Copy the embeddings database to S3
+        vdb_client.push_to_s3().await?;
+    }
+    Ok(())
+}
+
+pub async fn query(mut config: Config, args: QueryArgs) -> Result<()> {
+    if let Some(model) = args.model {
+        config.models.embeddings_model = model;
+    }
+    if let Some(chat_model) = args.chat_model {
+        config.models.chat_model = chat_model;
+    }
+    let top_k = args.top_k.unwrap_or(config.retrieval.top_k);
+    let filters: HashMap<String, Value> = args.filter.into_iter().collect();
+    let vdb_client = open_existing(&config)?;
+    if let Some(stored) = vdb_client.meta("embeddings_model")? {
+        if stored != config.models.embeddings_model {
+            bail!(
+                "The database was embedded with {}, not {}; its chunks can only be \
+                 searched with the same model (pass --model {})",
+                stored,
+                config.models.embeddings_model,
+                stored
+            );
+        }
+    }
+    let embedder = Embedder::new(&config).await?;
+    let embedding = embedder.embed(&args.question).await?;
+    let dimensions = vdb_client.dimensions()?;
+    if !dimensions.is_empty() && !dimensions.contains(&embedding.len()) {
+        bail!(
+            "{} makes vectors of {} dimensions, but the database's have {:?}",
+            config.models.embeddings_model,
+            embedding.len(),
+            dimensions
+        );
+    }
+    let hits = vdb_client.search(&embedding, top_k, &filters)?;
+    let answer = if args.answer {
+        Some(answer::generate(&config, &args.question, &hits).await?)
+    } else {
+        None
+    };
+
+    match args.output.format {
+        OutputFormat::Json => {
+            let hits: Vec<_> = hits
+                .iter()
+                .enumerate()
+                .map(|(i, hit)| {
+                    json!({
+                        "rank": i + 1,
+                        "id": hit.id,
+                        "score": hit.score,
+                        "metadata": hit.metadata,
+                        "text": hit.text,
+                    })
+                })
+                .collect();
+            let output = json!({
+                "question": args.question,
+                "hits": hits,
+                "answer": answer,
+            });
+            println!("{}", serde_json::to_string_pretty(&output)?);
+        }
+        OutputFormat::Table => {
+            println!(
+                "{:>4}  {:>6}  {:<30}  {:>5}  TEXT",
+                "RANK", "SCORE", "SOURCE", "CHUNK"
+            );
+            for (i, hit) in hits.iter().enumerate() {
+                let metadata = hit.metadata.as_ref();
+                let source = metadata
+                    .and_then(|m| m.get("source"))
+                    .and_then(|s| s.as_str())
+                    .unwrap_or("-");
+                let chunk_index = metadata
+                    .and_then(|m| m.get("chunk_index"))
+                    .map(|c| c.to_string())
+                    .unwrap_or_else(|| "-".to_string());
+                println!(
+                    "{:>4}  {:>6.3}  {:<30}  {:>5}  {}",
+                    i + 1,
+                    hit.score,
+                    source,
+                    chunk_index,
+                    snippet(&hit.text, 80)
+                );
+            }
+            if let Some(answer) = answer {
+                println!("\n{}", answer);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The start of `text` on one line, at most `max_chars` characters long.
+fn snippet(text: &str, max_chars: usize) -> String {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if text.chars().count() <= max_chars {
+        return text;
+    }
+    let mut snippet: String = text.chars().take(max_chars - 1).collect();
+    snippet.push('…');
+    snippet
+}
+
+pub fn stats(config: &Config, args: FormatArgs) -> Result<()> {
+    let stats = open_existing(config)?.stats()?;
+    if args.format == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+        return Ok(());
+    }
+
+    let or_unknown = |value: &Option<String>| value.clone().unwrap_or_else(|| "unknown".into());
+    let dimensions: Vec<String> = stats.dimensions.iter().map(|d| d.to_string()).collect();
+    println!("database          {}", config.database.local_path);
+    println!("file bytes        {}", stats.file_bytes);
+    println!("schema version    {}", or_unknown(&stats.schema_version));
+    println!("embeddings model  {}", or_unknown(&stats.embeddings_model));
+    println!("dimensions        {}", dimensions.join(", "));
+    println!("chunks            {}", stats.chunks);
+    println!("characters        {}", stats.characters);
+    println!(
+        "average chunk     {:.0} characters",
+        stats.average_chunk_characters
+    );
+    println!("empty chunks      {}", stats.empty_chunks);
+    println!("duplicate chunks  {}", stats.duplicate_chunks);
+    if stats.indexes.is_empty() {
+        println!("indexes           none (searches scan every chunk)");
+    } else {
+        println!("indexes           {}", stats.indexes.join(", "));
+    }
+
+    println!("\nchunk length (characters)");
+    let widest = stats
+        .chunk_length_histogram
+        .iter()
+        .map(|b| b.chunks)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    for bucket in &stats.chunk_length_histogram {
+        let range = match bucket.max {
+            Some(max) => format!("{}-{}", bucket.min, max - 1),
+            None => format!("{}+", bucket.min),
+        };
+        println!(
+            "{:>10}  {:>6}  {}",
+            range,
+            bucket.chunks,
+            "#".repeat(bucket.chunks * 40 / widest)
+        );
+    }
+
+    println!(
+        "\n{:<40}  {:<11}  {:>6}  {:>10}",
+        "SOURCE", "STATUS", "CHUNKS", "CHARACTERS"
+    );
+    for document in &stats.documents {
+        println!(
+            "{:<40}  {:<11}  {:>6}  {:>10}",
+            document.source.as_deref().unwrap_or("(no source)"),
+            document.status.map(|s| s.as_str()).unwrap_or("-"),
+            document.chunks,
+            document.characters
+        );
+        if document.chunks == 0 {
+            tracing::warn!(
+                "{} has no chunks; its text may not have extracted (scanned PDF?)",
+                document.source.as_deref().unwrap_or_default()
+            );
+        }
+    }
+    Ok(())
+}
+
+pub fn list_docs(config: &Config, args: FormatArgs) -> Result<()> {
+    let documents = open_existing(config)?.list_documents()?;
+    match args.format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&documents)?),
+        OutputFormat::Table => {
+            println!(
+                "{:<40}  {:<11}  {:>13}  {:>5}  {:<32}  TITLE",
+                "SOURCE", "STATUS", "CHUNKS", "PAGES", "UPDATED"
+            );
+            for document in documents {
+                println!(
+                    "{:<40}  {:<11}  {:>13}  {:>5}  {:<32}  {}",
+                    document.source,
+                    document.status.as_str(),
+                    format!("{}/{}", document.chunks_stored, document.chunk_count),
+                    document
+                        .page_count
+                        .map_or_else(|| "-".to_string(), |p| p.to_string()),
+                    document.updated_at,
+                    document.title.as_deref().unwrap_or("-")
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn delete_doc(config: &Config, args: DeleteDocArgs) -> Result<()> {
+    let vdb_client = open_existing(config)?;
+    if args.all {
+        return vdb_client.drop_embeddings_table();
+    }
+    if !args.filter.is_empty() {
+        let filters: HashMap<String, Value> = args.filter.into_iter().collect();
+        let deleted = vdb_client.delete_matching(&filters)?;
+        tracing::info!("Removed {} matching chunks", deleted);
+        return Ok(());
+    }
+    let source = args.source.unwrap_or_default();
+    // Imported chunks have a source but no journal entry.
+    let journaled = vdb_client.document_progress(&source)?.is_some();
+    let deleted = vdb_client.delete_document(&source)?;
+    if deleted == 0 && !journaled {
+        bail!("No document named {}; see `list-docs`", source);
+    }
+    tracing::info!("Removed {} chunks of {}", deleted, source);
+    Ok(())
+}
+
+pub async fn replace_doc(
+    mut config: Config,
+    args: ReplaceDocArgs,
+    progress: &MultiProgress,
+) -> Result<()> {
+    if let Some(model) = args.model {
+        config.models.embeddings_model = model;
+    }
+    if let Some(password) = args.pdf_password {
+        config.extraction.password = Some(password);
+    }
+    if !args.path.is_file() {
+        bail!("No such file: {}", args.path.display());
+    }
+    let vdb_client = VectorDb::open_local(&config.database)?;
+    ingest::replace_document(
+        &config,
+        &vdb_client,
+        &args.path.to_string_lossy(),
+        args.force,
+        progress,
+    )
+    .await?;
+    Ok(())
+}
+
+pub fn update_chunk(config: &Config, args: UpdateChunkArgs) -> Result<()> {
+    let vdb_client = open_existing(config)?;
+    let set: serde_json::Map<String, Value> = args.set.into_iter().collect();
+    let metadata = vdb_client.update_metadata(args.id, &set, &args.unset)?;
+    println!("{}", metadata);
+    Ok(())
+}
+
+pub fn export(config: &Config, args: ExportArgs) -> Result<()> {
+    let vdb_client = open_existing(config)?;
+    let to_stdout = args.output == Path::new("-");
+    let format = args
+        .format
+        .unwrap_or_else(|| DataFormat::from_path(&args.output));
+    let create = || {
+        File::create(&args.output)
+            .with_context(|| format!("Failed to create {}", args.output.display()))
+    };
+    let count = match format {
+        DataFormat::Jsonl if to_stdout => {
+            export::write_jsonl(&vdb_client, BufWriter::new(io::stdout().lock()))?
+        }
+        DataFormat::Jsonl => export::write_jsonl(&vdb_client, BufWriter::new(create()?))?,
+        DataFormat::Parquet if to_stdout => {
+            bail!("Parquet can't be written to stdout; pass --output FILE")
+        }
+        DataFormat::Parquet => export::write_parquet(&vdb_client, create()?)?,
+    };
+    tracing::info!("Exported {} chunks", count);
+    Ok(())
+}
+
+pub fn import(config: &Config, args: ImportArgs) -> Result<()> {
+    let file = File::open(&args.input)
+        .with_context(|| format!("Failed to open {}", args.input.display()))?;
+    let chunks = match args
+        .format
+        .unwrap_or_else(|| DataFormat::from_path(&args.input))
+    {
+        DataFormat::Jsonl => export::read_jsonl(BufReader::new(file))?,
+        DataFormat::Parquet => export::read_parquet(file)?,
+    };
+    let vdb_client = VectorDb::open_local(&config.database)?;
+    vdb_client.create_embeddings_table()?;
+    let count = export::import_chunks(&vdb_client, chunks, args.force)?;
+    tracing::info!(
+        "Imported {} chunks into {}",
+        count,
+        config.database.local_path
+    );
+    Ok(())
+}
+
+pub async fn publish(config: &Config, args: PublishArgs) -> Result<()> {
+    let vdb_client = open_existing(config)?;
+    if config.database.s3_bucket.is_empty() {
+        bail!("S3 bucket not configured: set database.s3_bucket or S3_BUCKET_NAME");
+    }
+    let problems = vdb_client.verify()?;
+    if !problems.is_empty() && !args.force {
+        for problem in &problems {
+            tracing::error!("{}", problem);
+        }
+        bail!(
+            "Not publishing: `verify` found {} problems (use --force to publish anyway)",
+            problems.len()
+        );
+    }
+    vdb_client.push_to_s3().await
+}
+
+pub fn verify(config: &Config) -> Result<()> {
+    let problems = open_existing(config)?.verify()?;
+    if problems.is_empty() {
+        println!("OK: {}", config.database.local_path);
+        return Ok(());
+    }
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    bail!("{} problems found", problems.len());
+}
+
+pub fn completions(shell: Shell) {
+    let mut command = Cli::command();
+    let name = command.get_name().to_string();
+    clap_complete::generate(shell, &mut command, name, &mut io::stdout());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snippet() {
+        assert_eq!(snippet("Roll  the\ndice.", 20), "Roll the dice.");
+        assert_eq!(snippet("Roll the dice.", 8), "Roll th…");
+    }
+}
@@ -0,0 +1,365 @@
+//! Finds chunks that repeat earlier ones, like the copyright footer on every page of a rulebook.
+//!
+//! Exact duplicates are found by hashing the normalized text: lowercase words with punctuation
+//! and spacing dropped. Near-duplicates are found with MinHash signatures over word trigrams,
+//! bucketed with locality-sensitive hashing so each chunk is only compared with likely matches.
+
+use crate::pdftools::Chunk;
+use common::config::DedupMode;
+use common::vectordb::VectorDb;
+use serde_json::{json, Value};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+
+/// MinHash values per chunk, split into `BANDS` bands of `ROWS` values for bucketing.
+const BANDS: usize = 16;
+const ROWS: usize = 4;
+const SIGNATURE_LEN: usize = BANDS * ROWS;
+
+/// Words per shingle.
+const SHINGLE_WORDS: usize = 3;
+
+/// Chunks seen so far, from the database and from the documents loaded in this run.
+pub struct Deduplicator {
+    similarity: f64,
+    seen: Vec<Seen>,
+    exact: HashMap<u64, Vec<usize>>,
+    bands: HashMap<(usize, u64), Vec<usize>>,
+}
+
+struct Seen {
+    source: String,
+    chunk_index: usize,
+    signature: Vec<u64>,
+    /// False once the document is reloaded, failed or replaced.
+    live: bool,
+}
+
+/// The earlier chunk a chunk repeats.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Original {
+    pub source: String,
+    pub chunk_index: usize,
+    pub exact: bool,
+}
+
+/// What is left of one document's chunks after dedup.
+#[derive(Debug, Default)]
+pub struct UniqueChunks {
+    pub chunks: Vec<Chunk>,
+    /// Each kept chunk's position among the document's chunks, its `chunk_index`.
+    pub indexes: Vec<usize>,
+    pub exact_duplicates: usize,
+    pub near_duplicates: usize,
+    /// The chunks left out, keyed by the source and chunk index of the chunk each repeats.
+    pub links: BTreeMap<(String, usize), Vec<Value>>,
+}
+
+impl Deduplicator {
+    /// `similarity` is the estimated Jaccard similarity of two chunks' word trigrams at which
+    /// they count as near-duplicates; 1.0 only matches exact duplicates.
+    pub fn new(similarity: f64) -> Self {
+        Deduplicator {
+            similarity,
+            seen: Vec::new(),
+            exact: HashMap::new(),
+            bands: HashMap::new(),
+        }
+    }
+
+    /// Starts from every stored chunk that has a source and chunk index in its metadata.
+    /// Only needed to match chunks across documents; repeats within a document are found
+    /// among the chunks of this run.
+    pub fn from_database(vdb_client: &VectorDb, similarity: f64) -> anyhow::Result<Self> {
+        let mut dedup = Deduplicator::new(similarity);
+        vdb_client.for_each_embedding(|row| {
+            let metadata = row.metadata.as_ref();
+            let source = metadata
+                .and_then(|m| m.get("source"))
+                .and_then(|s| s.as_str());
+            let chunk_index = metadata
+                .and_then(|m| m.get("chunk_index"))
+                .and_then(|c| c.as_u64());
+            if let (Some(source), Some(chunk_index)) = (source, chunk_index) {
+                if dedup.find(&row.text, None).is_none() {
+                    dedup.insert(source, chunk_index as usize, &row.text);
+                }
+            }
+            Ok(())
+        })?;
+        Ok(dedup)
+    }
+
+    /// Stops matching against `source`'s chunks, because it is being reloaded or failed.
+    pub fn forget(&mut self, source: &str) {
+        for seen in self.seen.iter_mut().filter(|s| s.source == source) {
+            seen.live = false;
+        }
+    }
+
+    /// Returns the earlier chunk `text` repeats, or records it as `source`'s chunk
+    /// `chunk_index` and returns `None`. Without `across_documents`, only `source`'s own
+    /// chunks are matched.
+    pub fn check(
+        &mut self,
+        source: &str,
+        chunk_index: usize,
+        text: &str,
+        across_documents: bool,
+    ) -> Option<Original> {
+        let original = self.find(text, Some(source).filter(|_| !across_documents));
+        if original.is_none() {
+            self.insert(source, chunk_index, text);
+        }
+        original
+    }
+
+    /// Matches `text` against the live chunks, of `only_source` if given.
+    fn find(&self, text: &str, only_source: Option<&str>) -> Option<Original> {
+        let normalized = normalize(text);
+        let candidate = |i: usize| {
+            self.seen[i].live && only_source.is_none_or(|source| self.seen[i].source == source)
+        };
+        let original = |i: usize, exact: bool| Original {
+            source: self.seen[i].source.clone(),
+            chunk_index: self.seen[i].chunk_index,
+            exact,
+        };
+        if let Some(&i) = self
+            .exact
+            .get(&hash(&normalized))
+            .and_then(|ids| ids.iter().find(|&&i| candidate(i)))
+        {
+            return Some(original(i, true));
+        }
+        if self.similarity >= 1.0 {
+            return None;
+        }
+
+        let signature = signature(&normalized);
+        let mut candidates: Vec<usize> = signature
+            .chunks(ROWS)
+            .enumerate()
+            .filter_map(|(band, rows)| self.bands.get(&(band, hash(rows))))
+            .flatten()
+            .copied()
+            .filter(|&i| candidate(i))
+            .collect();
+        candidates.sort_unstable();
+        candidates.dedup();
+        candidates
+            .into_iter()
+            .find(|&i| estimated_similarity(&signature, &self.seen[i].signature) >= self.similarity)
+            .map(|i| original(i, false))
+    }
+
+    fn insert(&mut self, source: &str, chunk_index: usize, text: &str) {
+        let normalized = normalize(text);
+        let i = self.seen.len();
+        self.exact.entry(hash(&normalized)).or_default().push(i);
+        let signature = if self.similarity < 1.0 {
+            signature(&normalized)
+        } else {
+            Vec::new()
+        };
+        for (band, rows) in signature.chunks(ROWS).enumerate() {
+            self.bands.entry((band, hash(rows))).or_default().push(i);
+        }
+        self.seen.push(Seen {
+            source: source.to_string(),
+            chunk_index,
+            signature,
+            live: true,
+        });
+    }
+}
+
+/// Drops the chunks of `source` that repeat an earlier chunk of the same document, and with
+/// `Link` also those that repeat another document's chunk. `source`'s chunks from earlier
+/// runs don't count. With `Off`, or without a deduplicator, every chunk is kept.
+pub fn unique_chunks(
+    dedup: Option<&mut Deduplicator>,
+    mode: DedupMode,
+    source: &str,
+    chunks: Vec<Chunk>,
+) -> UniqueChunks {
+    let Some(dedup) = dedup.filter(|_| mode != DedupMode::Off) else {
+        return UniqueChunks {
+            indexes: (0..chunks.len()).collect(),
+            chunks,
+            ..UniqueChunks::default()
+        };
+    };
+    dedup.forget(source);
+    let mut unique = UniqueChunks::default();
+    for (chunk_index, chunk) in chunks.into_iter().enumerate() {
+        match dedup.check(source, chunk_index, &chunk.text, mode == DedupMode::Link) {
+            None => {
+                unique.chunks.push(chunk);
+                unique.indexes.push(chunk_index);
+            }
+            Some(original) => {
+                if original.exact {
+                    unique.exact_duplicates += 1;
+                } else {
+                    unique.near_duplicates += 1;
+                }
+                if mode == DedupMode::Link {
+                    unique
+                        .links
+                        .entry((original.source, original.chunk_index))
+                        .or_default()
+                        .push(json!({"source": source, "chunk_index": chunk_index}));
+                }
+            }
+        }
+    }
+    unique
+}
+
+/// Records the chunks left out in the metadata of the chunks they repeat.
+pub fn apply_links(
+    vdb_client: &VectorDb,
+    links: &BTreeMap<(String, usize), Vec<Value>>,
+) -> anyhow::Result<()> {
+    for ((source, chunk_index), duplicates) in links {
+        if !vdb_client.link_duplicates(source, *chunk_index, duplicates)? {
+            tracing::warn!(
+                "Can't link duplicates to chunk {} of {}: it is no longer stored",
+                chunk_index,
+                source
+            );
+        }
+    }
+    Ok(())
+}
+
+fn normalize(text: &str) -> String {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn hash<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// MinHash signature of the word trigrams of `normalized` text.
+fn signature(normalized: &str) -> Vec<u64> {
+    let words: Vec<&str> = normalized.split(' ').collect();
+    let shingles: Vec<u64> = words
+        .windows(SHINGLE_WORDS.min(words.len()))
+        .map(hash)
+        .collect();
+    (0..SIGNATURE_LEN as u64)
+        .map(|seed| {
+            shingles
+                .iter()
+                .map(|&shingle| mix(shingle ^ seed.wrapping_mul(0x9e37_79b9_7f4a_7c15)))
+                .min()
+                .unwrap_or(0)
+        })
+        .collect()
+}
+
+/// The splitmix64 finalizer, used as a family of hash functions by varying the input.
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+fn estimated_similarity(a: &[u64], b: &[u64]) -> f64 {
+    let equal = a.iter().zip(b).filter(|(x, y)| x == y).count();
+    equal as f64 / SIGNATURE_LEN as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULE: &str = "On your turn, roll both dice and move your token clockwise around the \
+                        board the number of spaces shown, then buy the property you land on or \
+                        put it up for auction among the other players at the table.";
+
+    #[test]
+    fn test_exact_duplicates_ignore_case_and_punctuation() {
+        let mut dedup = Deduplicator::new(0.9);
+        assert_eq!(
+            dedup.check("a.pdf", 0, "© 2004 Hasbro. All rights reserved.", true),
+            None
+        );
+        assert_eq!(
+            dedup.check("b.pdf", 5, "© 2004 HASBRO, all rights\nreserved", true),
+            Some(Original {
+                source: "a.pdf".to_string(),
+                chunk_index: 0,
+                exact: true
+            })
+        );
+    }
+
+    #[test]
+    fn test_near_duplicates() {
+        let mut dedup = Deduplicator::new(0.7);
+        assert_eq!(dedup.check("a.pdf", 0, RULE, false), None);
+        let edited = RULE.replace("among the other players at the table", "among the players");
+        let original = dedup.check("a.pdf", 1, &edited, false).unwrap();
+        assert!(!original.exact);
+        assert_eq!(original.chunk_index, 0);
+        assert_eq!(
+            dedup.check("a.pdf", 2, "Each player starts with 45 trains.", false),
+            None
+        );
+
+        let mut exact_only = Deduplicator::new(1.0);
+        exact_only.check("a.pdf", 0, RULE, false);
+        assert_eq!(exact_only.check("a.pdf", 1, &edited, false), None);
+    }
+
+    #[test]
+    fn test_unique_chunks_link_and_forget() {
+        let mut dedup = Deduplicator::new(0.9);
+        let chunks = |texts: &[&str]| {
+            texts
+                .iter()
+                .map(|t| Chunk::new(t.to_string()))
+                .collect::<Vec<_>>()
+        };
+        let unique = unique_chunks(
+            Some(&mut dedup),
+            DedupMode::Link,
+            "a.pdf",
+            chunks(&[RULE, "Continued on next page", "Continued on next page."]),
+        );
+        assert_eq!(unique.indexes, vec![0, 1]);
+        assert_eq!(unique.exact_duplicates, 1);
+        assert_eq!(
+            unique.links[&("a.pdf".to_string(), 1)],
+            vec![json!({"source": "a.pdf", "chunk_index": 2})]
+        );
+
+        // Reloading a document doesn't match it against its own earlier chunks.
+        let unique = unique_chunks(Some(&mut dedup), DedupMode::Skip, "a.pdf", chunks(&[RULE]));
+        assert_eq!(unique.indexes, vec![0]);
+        assert!(unique.links.is_empty());
+
+        // Skipping only looks within the document; linking looks across documents.
+        let unique = unique_chunks(Some(&mut dedup), DedupMode::Skip, "b.pdf", chunks(&[RULE]));
+        assert_eq!(unique.indexes, vec![0]);
+        let unique = unique_chunks(Some(&mut dedup), DedupMode::Link, "c.pdf", chunks(&[RULE]));
+        assert!(unique.indexes.is_empty());
+        assert_eq!(
+            unique.links[&("a.pdf".to_string(), 0)],
+            vec![json!({"source": "c.pdf", "chunk_index": 0})]
+        );
+
+        let unique = unique_chunks(Some(&mut dedup), DedupMode::Off, "d.pdf", chunks(&[RULE]));
+        assert_eq!(unique.indexes, vec![0]);
+    }
+}
@@ -0,0 +1,503 @@
+use anyhow::{anyhow, bail, Context, Result};
+use arrow_array::builder::{Float32Builder, ListBuilder};
+use arrow_array::cast::AsArray;
+use arrow_array::types::{Float32Type, Int64Type};
+use arrow_array::{Array, ArrayRef, Int64Array, RecordBatch, StringArray};
+use arrow_cast::cast;
+use arrow_schema::{DataType, Field, Schema};
+use common::vectordb::{NewEmbedding, VectorDb};
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::ArrowWriter;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{BufRead, Write};
+use std::sync::Arc;
+
+/// Rows per record batch in Parquet exports.
+const PARQUET_BATCH_ROWS: usize = 1024;
+
+/// One chunk in an export file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExportedChunk {
+    /// Row id in the exported database. Imported chunks get new ids.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<i64>,
+    pub text: String,
+    pub embedding: Vec<f32>,
+    #[serde(default)]
+    pub metadata: Option<Value>,
+    /// Embeddings model the vector was made with, if the database recorded it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+}
+
+/// Every chunk in the database, in id order, tagged with the database's embeddings model.
+fn exported_chunks<F>(vdb_client: &VectorDb, mut f: F) -> Result<usize>
+where
+    F: FnMut(ExportedChunk) -> Result<()>,
+{
+    let model = vdb_client.meta("embeddings_model")?;
+    let mut count = 0;
+    vdb_client.for_each_embedding(|row| {
+        count += 1;
+        f(ExportedChunk {
+            id: Some(row.id),
+            text: row.text,
+            embedding: row.embedding,
+            metadata: row.metadata,
+            model: model.clone(),
+        })
+    })?;
+    Ok(count)
+}
+
+/// Writes every chunk as one JSON object per line. Returns the number written.
+pub fn write_jsonl<W: Write>(vdb_client: &VectorDb, mut writer: W) -> Result<usize> {
+    let count = exported_chunks(vdb_client, |chunk| {
+        serde_json::to_writer(&mut writer, &chunk)?;
+        writer.write_all(b"\n")?;
+        Ok(())
+    })?;
+    writer.flush()?;
+    Ok(count)
+}
+
+pub fn read_jsonl<R: BufRead>(reader: R) -> Result<Vec<ExportedChunk>> {
+    let mut chunks = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let chunk: ExportedChunk = serde_json::from_str(&line)
+            .with_context(|| format!("Invalid chunk on line {}", i + 1))?;
+        chunks.push(chunk);
+    }
+    Ok(chunks)
+}
+
+/// Columns of a Parquet export. `metadata` holds the chunk's metadata as a JSON string.
+fn parquet_schema() -> Arc<Schema> {
+    Arc::new(Schema::new(vec![
+        Field::new("id", DataType::Int64, false),
+        Field::new("text", DataType::Utf8, false),
+        Field::new("embedding", embedding_type(), false),
+        Field::new("metadata", DataType::Utf8, true),
+        Field::new("model", DataType::Utf8, true),
+    ]))
+}
+
+fn embedding_type() -> DataType {
+    DataType::List(Arc::new(Field::new("item", DataType::Float32, true)))
+}
+
+/// Writes every chunk to a Parquet file, for loading into pandas, polars or DuckDB.
+/// Returns the number written.
+pub fn write_parquet<W: Write + Send>(vdb_client: &VectorDb, writer: W) -> Result<usize> {
+    let schema = parquet_schema();
+    let mut parquet = ArrowWriter::try_new(writer, schema.clone(), None)?;
+    let mut pending = Vec::with_capacity(PARQUET_BATCH_ROWS);
+    let count = exported_chunks(vdb_client, |chunk| {
+        pending.push(chunk);
+        if pending.len() == PARQUET_BATCH_ROWS {
+            parquet.write(&record_batch(&schema, &pending)?)?;
+            pending.clear();
+        }
+        Ok(())
+    })?;
+    if !pending.is_empty() {
+        parquet.write(&record_batch(&schema, &pending)?)?;
+    }
+    parquet.close()?;
+    Ok(count)
+}
+
+fn record_batch(schema: &Arc<Schema>, chunks: &[ExportedChunk]) -> Result<RecordBatch> {
+    let mut embeddings = ListBuilder::new(Float32Builder::new());
+    for chunk in chunks {
+        embeddings.values().append_slice(&chunk.embedding);
+        embeddings.append(true);
+    }
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(Int64Array::from_iter_values(
+            chunks.iter().map(|c| c.id.unwrap_or_default()),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            chunks.iter().map(|c| c.text.as_str()),
+        )),
+        Arc::new(embeddings.finish()),
+        Arc::new(StringArray::from_iter(
+            chunks
+                .iter()
+                .map(|c| c.metadata.as_ref().map(|m| m.to_string())),
+        )),
+        Arc::new(StringArray::from_iter(
+            chunks.iter().map(|c| c.model.as_deref()),
+        )),
+    ];
+    Ok(RecordBatch::try_new(schema.clone(), columns)?)
+}
+
+/// Column `name` of `batch` cast to `to`, so files written by polars or pyarrow, which often
+/// use large strings and lists or doubles, read like ours.
+fn column_as(batch: &RecordBatch, name: &str, to: &DataType) -> Result<Option<ArrayRef>> {
+    batch
+        .column_by_name(name)
+        .map(|column| {
+            cast(column, to).with_context(|| {
+                format!(
+                    "The {} column is {} and can't be read as {}",
+                    name,
+                    column.data_type(),
+                    to
+                )
+            })
+        })
+        .transpose()
+}
+
+pub fn read_parquet(file: File) -> Result<Vec<ExportedChunk>> {
+    let reader = ParquetRecordBatchReaderBuilder::try_new(file)?.build()?;
+    let mut chunks = Vec::new();
+    for batch in reader {
+        let batch = batch?;
+        let required = |name: &str, to: &DataType| {
+            column_as(&batch, name, to)?
+                .ok_or_else(|| anyhow!("Parquet file has no {:?} column", name))
+        };
+        let id_column = column_as(&batch, "id", &DataType::Int64)?;
+        let text_column = required("text", &DataType::Utf8)?;
+        let embedding_column = required("embedding", &embedding_type())?;
+        let metadata_column = column_as(&batch, "metadata", &DataType::Utf8)?;
+        let model_column = column_as(&batch, "model", &DataType::Utf8)?;
+        // After the casts above these are the types asked for.
+        let ids = id_column.as_ref().map(|c| c.as_primitive::<Int64Type>());
+        let texts = text_column.as_string::<i32>();
+        let embeddings = embedding_column.as_list::<i32>();
+        let metadata = metadata_column.as_ref().map(|c| c.as_string::<i32>());
+        let models = model_column.as_ref().map(|c| c.as_string::<i32>());
+
+        for row in 0..batch.num_rows() {
+            // Counted across batches, for the error messages.
+            let row_number = chunks.len();
+            if ids.is_some_and(|ids| ids.is_null(row)) {
+                bail!("Row {} has no id", row_number);
+            }
+            if texts.is_null(row) {
+                bail!("Row {} has no text", row_number);
+            }
+            if embeddings.is_null(row) {
+                bail!("Row {} has no embedding", row_number);
+            }
+            let embedding = embeddings.value(row);
+            let embedding = embedding.as_primitive::<Float32Type>();
+            if embedding.null_count() > 0 {
+                bail!("Row {} has a null in its embedding", row_number);
+            }
+            let metadata = match metadata.filter(|m| m.is_valid(row)) {
+                Some(m) => Some(
+                    serde_json::from_str(m.value(row))
+                        .with_context(|| format!("Invalid metadata in row {}", row_number))?,
+                ),
+                None => None,
+            };
+            chunks.push(ExportedChunk {
+                id: ids.map(|ids| ids.value(row)),
+                text: texts.value(row).to_string(),
+                embedding: embedding.values().to_vec(),
+                metadata,
+                model: models
+                    .filter(|m| m.is_valid(row))
+                    .map(|m| m.value(row).to_string()),
+            });
+        }
+    }
+    Ok(chunks)
+}
+
+/// Adds `chunks` to the database in one transaction. Unless `force` is set, refuses chunks
+/// embedded with a different model than the database's, or with vectors of another length,
+/// since they can't be compared. Returns the number inserted.
+pub fn import_chunks(
+    vdb_client: &VectorDb,
+    chunks: Vec<ExportedChunk>,
+    force: bool,
+) -> Result<usize> {
+    let models: BTreeSet<&str> = chunks.iter().filter_map(|c| c.model.as_deref()).collect();
+    let existing = vdb_client.meta("embeddings_model")?;
+    if !force {
+        if models.len() > 1 {
+            bail!(
+                "The file mixes embeddings models: {} (use --force to import anyway)",
+                models.into_iter().collect::<Vec<_>>().join(", ")
+            );
+        }
+        if let (Some(existing), Some(model)) = (&existing, models.first()) {
+            if existing != model && vdb_client.count_embeddings()? > 0 {
+                bail!(
+                    "The database holds {} embeddings but the file has {} ones \
+                     (use --force to import anyway)",
+                    existing,
+                    model
+                );
+            }
+        }
+        let dimensions: BTreeSet<usize> = chunks.iter().map(|c| c.embedding.len()).collect();
+        let stored: BTreeSet<usize> = vdb_client.dimensions()?.into_iter().collect();
+        if dimensions.len() > 1 || (!stored.is_empty() && !dimensions.is_subset(&stored)) {
+            let list = |d: &BTreeSet<usize>| {
+                d.iter()
+                    .map(usize::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+            bail!(
+                "The file has vectors of {} dimensions but the database has {} \
+                 (use --force to import anyway)",
+                list(&dimensions),
+                if stored.is_empty() {
+                    "none".to_string()
+                } else {
+                    list(&stored)
+                }
+            );
+        }
+    }
+    if let Some(model) = models.first() {
+        if existing.is_none() || vdb_client.count_embeddings()? == 0 {
+            vdb_client.set_meta("embeddings_model", model)?;
+        }
+    }
+
+    let rows: Vec<NewEmbedding> = chunks
+        .into_iter()
+        .map(|chunk| NewEmbedding {
+            text: chunk.text,
+            embedding: chunk.embedding,
+            metadata: chunk.metadata,
+        })
+        .collect();
+    Ok(vdb_client.insert_embeddings(&rows)?.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::config::DatabaseConfig;
+    use serde_json::json;
+    use std::io::BufReader;
+
+    fn temp_db(dir: &tempfile::TempDir, name: &str) -> VectorDb {
+        let config = DatabaseConfig {
+            local_path: dir.path().join(name).to_string_lossy().to_string(),
+            ..DatabaseConfig::default()
+        };
+        let vdb_client = VectorDb::open_local(&config).unwrap();
+        vdb_client.create_embeddings_table().unwrap();
+        vdb_client
+    }
+
+    fn seeded_db(dir: &tempfile::TempDir) -> VectorDb {
+        let vdb_client = temp_db(dir, "source.db");
+        vdb_client.set_meta("embeddings_model", "titan").unwrap();
+        vdb_client
+            .insert_embedding("north", &[1.0, 0.0], Some(&json!({"source": "a.pdf"})))
+            .unwrap();
+        vdb_client
+            .insert_embedding("east", &[0.0, -0.5], None)
+            .unwrap();
+        vdb_client
+    }
+
+    fn exported(vdb_client: &VectorDb) -> Vec<ExportedChunk> {
+        let mut chunks = Vec::new();
+        exported_chunks(vdb_client, |c| {
+            chunks.push(c);
+            Ok(())
+        })
+        .unwrap();
+        chunks
+    }
+
+    #[test]
+    fn test_jsonl_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = seeded_db(&dir);
+        let mut buffer = Vec::new();
+        assert_eq!(write_jsonl(&source, &mut buffer).unwrap(), 2);
+
+        let chunks = read_jsonl(BufReader::new(buffer.as_slice())).unwrap();
+        assert_eq!(chunks, exported(&source));
+        assert_eq!(chunks[0].model.as_deref(), Some("titan"));
+
+        let target = temp_db(&dir, "target.db");
+        assert_eq!(import_chunks(&target, chunks, false).unwrap(), 2);
+        assert_eq!(exported(&target), exported(&source));
+    }
+
+    #[test]
+    fn test_parquet_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = seeded_db(&dir);
+        let path = dir.path().join("chunks.parquet");
+        assert_eq!(
+            write_parquet(&source, File::create(&path).unwrap()).unwrap(),
+            2
+        );
+        let chunks = read_parquet(File::open(&path).unwrap()).unwrap();
+        assert_eq!(chunks, exported(&source));
+    }
+
+    #[test]
+    fn test_parquet_with_large_types() {
+        // How polars and pyarrow write the same table: large strings and lists, and doubles.
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("text", DataType::LargeUtf8, false),
+            Field::new(
+                "embedding",
+                DataType::LargeList(Arc::new(Field::new("item", DataType::Float64, true))),
+                false,
+            ),
+            Field::new("metadata", DataType::LargeUtf8, true),
+            Field::new("model", DataType::LargeUtf8, true),
+        ]));
+        let mut embeddings = arrow_array::builder::LargeListBuilder::new(
+            arrow_array::builder::Float64Builder::new(),
+        );
+        embeddings.values().append_slice(&[1.0, 0.0]);
+        embeddings.append(true);
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(arrow_array::LargeStringArray::from(vec!["north"])),
+            Arc::new(embeddings.finish()),
+            Arc::new(arrow_array::LargeStringArray::from(vec![Some(
+                r#"{"source": "a.pdf"}"#,
+            )])),
+            Arc::new(arrow_array::LargeStringArray::from(vec![Some("titan")])),
+        ];
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("polars.parquet");
+        let mut writer =
+            ArrowWriter::try_new(File::create(&path).unwrap(), schema.clone(), None).unwrap();
+        writer
+            .write(&RecordBatch::try_new(schema, columns).unwrap())
+            .unwrap();
+        writer.close().unwrap();
+
+        let chunks = read_parquet(File::open(&path).unwrap()).unwrap();
+        assert_eq!(
+            chunks,
+            vec![ExportedChunk {
+                id: None,
+                text: "north".to_string(),
+                embedding: vec![1.0, 0.0],
+                metadata: Some(json!({"source": "a.pdf"})),
+                model: Some("titan".to_string()),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parquet_nulls_are_errors() {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int64, true),
+            Field::new("text", DataType::Utf8, true),
+            Field::new("embedding", embedding_type(), true),
+        ]));
+        let read = |ids: Vec<Option<i64>>,
+                    texts: Vec<Option<&str>>,
+                    vectors: &[Option<Vec<Option<f32>>>]| {
+            let mut embeddings = ListBuilder::new(Float32Builder::new());
+            for vector in vectors {
+                match vector {
+                    Some(vector) => {
+                        embeddings.values().extend(vector.iter().copied());
+                        embeddings.append(true);
+                    }
+                    None => embeddings.append(false),
+                }
+            }
+            let columns: Vec<ArrayRef> = vec![
+                Arc::new(Int64Array::from(ids)),
+                Arc::new(StringArray::from(texts)),
+                Arc::new(embeddings.finish()),
+            ];
+            let mut file = tempfile::tempfile().unwrap();
+            let mut writer = ArrowWriter::try_new(&mut file, schema.clone(), None).unwrap();
+            writer
+                .write(&RecordBatch::try_new(schema.clone(), columns).unwrap())
+                .unwrap();
+            writer.close().unwrap();
+            read_parquet(file).map_err(|e| e.to_string())
+        };
+        let vector = Some(vec![Some(1.0), Some(0.0)]);
+
+        assert_eq!(
+            read(
+                vec![Some(1), Some(2)],
+                vec![Some("north"), Some("east")],
+                &[vector.clone(), vector.clone()]
+            )
+            .unwrap()
+            .len(),
+            2
+        );
+        assert_eq!(
+            read(
+                vec![Some(1), None],
+                vec![Some("north"), Some("east")],
+                &[vector.clone(), vector.clone()]
+            ),
+            Err("Row 1 has no id".to_string())
+        );
+        assert_eq!(
+            read(
+                vec![Some(1), Some(2)],
+                vec![Some("north"), None],
+                &[vector.clone(), vector.clone()]
+            ),
+            Err("Row 1 has no text".to_string())
+        );
+        assert_eq!(
+            read(
+                vec![Some(1), Some(2)],
+                vec![Some("north"), Some("east")],
+                &[None, vector.clone()]
+            ),
+            Err("Row 0 has no embedding".to_string())
+        );
+        assert_eq!(
+            read(
+                vec![Some(1), Some(2)],
+                vec![Some("north"), Some("east")],
+                &[vector.clone(), Some(vec![Some(1.0), None])]
+            ),
+            Err("Row 1 has a null in its embedding".to_string())
+        );
+    }
+
+    #[test]
+    fn test_import_refuses_other_model() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = seeded_db(&dir);
+        let mut chunks = exported(&source);
+        chunks[0].model = Some("cohere".to_string());
+        assert!(import_chunks(&source, chunks.clone(), false).is_err());
+        assert_eq!(source.count_embeddings().unwrap(), 2);
+        assert_eq!(import_chunks(&source, chunks, true).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_import_refuses_other_dimensions() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = seeded_db(&dir);
+        let mut chunks = exported(&source);
+        chunks[1].embedding = vec![0.0, 1.0, 0.0];
+        assert!(import_chunks(&source, chunks.clone(), false).is_err());
+        chunks.remove(0);
+        assert!(import_chunks(&source, chunks.clone(), false).is_err());
+        assert_eq!(source.count_embeddings().unwrap(), 2);
+        assert_eq!(import_chunks(&source, chunks, true).unwrap(), 1);
+    }
+}
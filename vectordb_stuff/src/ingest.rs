@@ -0,0 +1,674 @@
+use crate::dedup::{self, Deduplicator, UniqueChunks};
+use crate::pdftools::{extract_text_from_pdf, get_pdf_filenames, UnreadablePdf};
+use anyhow::{bail, Context, Result};
+use common::config::{Config, DedupMode, IngestionConfig};
+use common::embeddings::Embedder;
+use common::vectordb::{document_fingerprint, DocumentStatus, NewEmbedding, VectorDb};
+use futures::stream::{self, Stream, StreamExt};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use serde::Serialize;
+use serde_json::json;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// Totals for one `ingest` run, printed to stdout as JSON when it finishes.
+#[derive(Debug, Default, Serialize)]
+pub struct IngestSummary {
+    pub documents_processed: usize,
+    /// Already loaded completely, with `--resume`.
+    pub documents_skipped: usize,
+    pub documents_failed: usize,
+    pub failures: Vec<FailedDocument>,
+    /// Paths of the failed files that can't be read at all, because they are encrypted or
+    /// damaged. Unlike other failures, loading them again won't help until they are fixed.
+    pub quarantined: Vec<String>,
+    /// Documents that loaded but may be missing text, like scans without a text layer.
+    pub warnings: Vec<DocumentWarning>,
+    /// Chunks in the processed documents.
+    pub chunks: usize,
+    /// Chunks inserted by this run; fewer than `chunks` when documents were resumed or had
+    /// duplicates.
+    pub chunks_inserted: usize,
+    /// Chunks left out because their normalized text matches a chunk already stored.
+    pub exact_duplicates: usize,
+    /// Chunks left out because they are nearly the same as a chunk already stored.
+    pub near_duplicates: usize,
+    /// Characters in the inserted chunks.
+    pub characters: usize,
+    /// InvokeModel calls, including retries.
+    pub embedding_calls: u64,
+    pub embedding_cache_hits: u64,
+    pub embedding_cache_misses: u64,
+    /// Tokens sent to the embeddings model, estimated at four characters each.
+    pub estimated_tokens: u64,
+    pub estimated_cost_usd: f64,
+    pub elapsed_seconds: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FailedDocument {
+    pub source: String,
+    pub error: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DocumentWarning {
+    pub source: String,
+    pub warning: String,
+}
+
+/// Turns chunk text into a vector. `Embedder` calls Bedrock; the tests use stubs.
+trait Embed {
+    fn embed(&self, text: &str) -> impl Future<Output = Result<Vec<f32>>>;
+}
+
+impl Embed for Embedder {
+    fn embed(&self, text: &str) -> impl Future<Output = Result<Vec<f32>>> {
+        Embedder::embed(self, text)
+    }
+}
+
+enum Loaded {
+    Skipped,
+    Inserted {
+        chunks: usize,
+        inserted: usize,
+        characters: usize,
+        exact_duplicates: usize,
+        near_duplicates: usize,
+        warnings: Vec<String>,
+    },
+}
+
+/// The PDFs to load: files are taken as given and directories are scanned for `*.pdf`.
+/// Without any paths, `default_dir` is scanned.
+pub fn resolve_inputs(paths: &[PathBuf], default_dir: &str) -> Result<Vec<String>> {
+    if paths.is_empty() {
+        return Ok(get_pdf_filenames(default_dir.to_string()));
+    }
+    let mut pdf_filenames = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            pdf_filenames.extend(get_pdf_filenames(path.to_string_lossy().to_string()));
+        } else if path.is_file() {
+            pdf_filenames.push(path.to_string_lossy().to_string());
+        } else {
+            bail!("No such file or directory: {}", path.display());
+        }
+    }
+    Ok(pdf_filenames)
+}
+
+/// Loads `pdf_filenames` into the database. A document that fails is recorded in the summary
+/// and the journal, and the run carries on with the next one.
+pub async fn load_documents(
+    config: &Config,
+    vdb_client: &VectorDb,
+    pdf_filenames: &[String],
+    resume: bool,
+    force: bool,
+    progress: &MultiProgress,
+) -> Result<IngestSummary> {
+    let started = Instant::now();
+    let embedder = Embedder::new(config).await?.with_rate_limit(
+        config.ingestion.requests_per_second,
+        config.ingestion.concurrency,
+    );
+    let mut summary = load_with(
+        &embedder,
+        config,
+        vdb_client,
+        pdf_filenames,
+        resume,
+        force,
+        progress,
+    )
+    .await?;
+
+    let usage = embedder.usage();
+    let cache = embedder.cache_stats().unwrap_or_default();
+    summary.embedding_calls = usage.calls;
+    summary.embedding_cache_hits = cache.hits;
+    summary.embedding_cache_misses = cache.misses;
+    summary.estimated_tokens = usage.characters.div_ceil(4);
+    summary.estimated_cost_usd =
+        summary.estimated_tokens as f64 / 1000.0 * config.ingestion.embedding_cost_per_1k_tokens;
+    summary.elapsed_seconds = started.elapsed().as_secs_f64();
+    Ok(summary)
+}
+
+/// `load_documents` with any embedder; the embedding usage and timing are left to the caller.
+async fn load_with<E: Embed>(
+    embedder: &E,
+    config: &Config,
+    vdb_client: &VectorDb,
+    pdf_filenames: &[String],
+    resume: bool,
+    force: bool,
+    progress: &MultiProgress,
+) -> Result<IngestSummary> {
+    tracing::info!("Loading {} documents", pdf_filenames.len());
+    vdb_client.create_embeddings_table()?;
+    check_embeddings_model(vdb_client, &config.models.embeddings_model, force)?;
+    let mut dedup = new_deduplicator(config, vdb_client)?;
+
+    let documents = progress.add(ProgressBar::new(pdf_filenames.len() as u64));
+    documents.set_style(bar_style("documents"));
+
+    let mut summary = IngestSummary::default();
+    for pdf_filepath in pdf_filenames {
+        let source = source_name(pdf_filepath);
+        documents.set_message(source.clone());
+
+        match load_document(
+            embedder,
+            vdb_client,
+            config,
+            dedup.as_mut(),
+            resume,
+            pdf_filepath,
+            progress,
+        )
+        .await
+        {
+            Ok(Loaded::Skipped) => summary.documents_skipped += 1,
+            Ok(Loaded::Inserted {
+                chunks,
+                inserted,
+                characters,
+                exact_duplicates,
+                near_duplicates,
+                warnings,
+            }) => {
+                summary.documents_processed += 1;
+                summary.chunks += chunks;
+                summary.chunks_inserted += inserted;
+                summary.characters += characters;
+                summary.exact_duplicates += exact_duplicates;
+                summary.near_duplicates += near_duplicates;
+                summary
+                    .warnings
+                    .extend(warnings.into_iter().map(|warning| DocumentWarning {
+                        source: source.clone(),
+                        warning,
+                    }));
+            }
+            Err(e) => {
+                tracing::error!("Failed to load {}: {:#}", source, e);
+                if let Some(dedup) = dedup.as_mut() {
+                    dedup.forget(&source);
+                }
+                summary.documents_failed += 1;
+                if e.downcast_ref::<UnreadablePdf>().is_some() {
+                    summary.quarantined.push(pdf_filepath.clone());
+                }
+                summary.failures.push(FailedDocument {
+                    source,
+                    error: format!("{:#}", e),
+                });
+            }
+        }
+        documents.inc(1);
+    }
+    documents.finish_and_clear();
+    Ok(summary)
+}
+
+/// Records `model` as the one the database's vectors are made with. Chunks embedded with
+/// another model live in a different vector space, so while any are stored this refuses
+/// unless `force`, and even then leaves the record alone.
+fn check_embeddings_model(vdb_client: &VectorDb, model: &str, force: bool) -> Result<()> {
+    match vdb_client.meta("embeddings_model")? {
+        Some(previous) if previous != model && vdb_client.count_embeddings()? > 0 => {
+            if !force {
+                bail!(
+                    "The database holds chunks embedded with {}, not {} \
+                     (use --clear to start over, or --force to mix them)",
+                    previous,
+                    model
+                );
+            }
+            tracing::warn!(
+                "Mixing chunks embedded with {} into a database of {} ones \
+                 makes searches unreliable",
+                model,
+                previous
+            );
+        }
+        _ => vdb_client.set_meta("embeddings_model", model)?,
+    }
+    Ok(())
+}
+
+/// Matches new chunks against each other, and with `link` against the ones already stored,
+/// unless dedup is off.
+fn new_deduplicator(config: &Config, vdb_client: &VectorDb) -> Result<Option<Deduplicator>> {
+    match config.dedup.mode {
+        DedupMode::Off => Ok(None),
+        DedupMode::Skip => Ok(Some(Deduplicator::new(config.dedup.similarity))),
+        DedupMode::Link => Ok(Some(Deduplicator::from_database(
+            vdb_client,
+            config.dedup.similarity,
+        )?)),
+    }
+}
+
+async fn load_document<E: Embed>(
+    embedder: &E,
+    vdb_client: &VectorDb,
+    config: &Config,
+    dedup: Option<&mut Deduplicator>,
+    resume: bool,
+    pdf_filepath: &str,
+    progress: &MultiProgress,
+) -> Result<Loaded> {
+    let source = &source_name(pdf_filepath);
+    let parsed_pdf = extract_text_from_pdf(pdf_filepath, &config.chunking, &config.extraction)
+        .with_context(|| format!("Failed to extract text from {}", pdf_filepath))?;
+    let total_chunks = parsed_pdf.chunks.len();
+    let unique = dedup::unique_chunks(dedup, config.dedup.mode, source, parsed_pdf.chunks);
+    let chunks = &unique.chunks;
+    tracing::debug!(
+        "Split {} into {} chunks, {} of them repeats",
+        source,
+        total_chunks,
+        total_chunks - chunks.len()
+    );
+
+    let fingerprint = document_fingerprint(chunks);
+    let start = match vdb_client.document_progress(source)? {
+        Some(p) if resume && p.fingerprint == fingerprint => match p.status {
+            DocumentStatus::Done => {
+                tracing::info!("Skipping {}: already loaded", source);
+                return Ok(Loaded::Skipped);
+            }
+            _ => {
+                tracing::info!(
+                    "Resuming {} at chunk {}/{}",
+                    source,
+                    p.committed_chunks,
+                    p.chunk_count
+                );
+                p.committed_chunks
+            }
+        },
+        _ => {
+            vdb_client.start_document(source, &fingerprint, chunks.len())?;
+            0
+        }
+    };
+    vdb_client.save_document_metadata(source, &parsed_pdf.metadata)?;
+
+    let bar = progress.add(ProgressBar::new(chunks.len() as u64));
+    bar.set_style(bar_style("chunks"));
+    bar.set_message(source.to_string());
+    bar.set_position(start as u64);
+    let result = embed_and_insert(
+        embedder,
+        vdb_client,
+        &config.ingestion,
+        source,
+        &unique,
+        start,
+        &bar,
+    )
+    .await;
+    bar.finish_and_clear();
+
+    if let Err(e) = result {
+        vdb_client.fail_document(source, &format!("{:#}", e))?;
+        return Err(e);
+    }
+    vdb_client.finish_document(source)?;
+    dedup::apply_links(vdb_client, &unique.links)?;
+    tracing::info!("Inserted {} chunks from {}", chunks.len() - start, source);
+    Ok(Loaded::Inserted {
+        chunks: total_chunks,
+        inserted: chunks.len() - start,
+        characters: chunks[start..].iter().map(|c| c.text.chars().count()).sum(),
+        exact_duplicates: unique.exact_duplicates,
+        near_duplicates: unique.near_duplicates,
+        warnings: parsed_pdf.warnings,
+    })
+}
+
+fn bar_style(unit: &str) -> ProgressStyle {
+    ProgressStyle::with_template(&format!(
+        "[{{elapsed_precise}}] {{bar:30}} {{pos}}/{{len}} {} {{msg}}",
+        unit
+    ))
+    .expect("progress template is valid")
+}
+
+/// Embeds the unique chunks from `start` on with up to `ingestion.concurrency` requests in
+/// flight and inserts them in order, `ingestion.batch_size` chunks per transaction. Each
+/// transaction also moves the document's journal entry forward, so a failed run can be resumed.
+async fn embed_and_insert<E: Embed>(
+    embedder: &E,
+    vdb_client: &VectorDb,
+    ingestion: &IngestionConfig,
+    source: &str,
+    unique: &UniqueChunks,
+    start: usize,
+    bar: &ProgressBar,
+) -> Result<()> {
+    let mut embedded = embed_chunks(embedder, ingestion.concurrency, source, unique, start);
+    let mut batch = Vec::with_capacity(ingestion.batch_size);
+    let mut committed = start;
+    while let Some(row) = embedded.next().await {
+        batch.push(row?);
+        bar.inc(1);
+        if batch.len() == ingestion.batch_size {
+            committed += batch.len();
+            vdb_client.commit_chunks(source, &batch, committed)?;
+            batch.clear();
+        }
+    }
+    if !batch.is_empty() {
+        committed += batch.len();
+        vdb_client.commit_chunks(source, &batch, committed)?;
+    }
+    Ok(())
+}
+
+/// Embeds the unique chunks from `start` on with up to `concurrency` requests in flight,
+/// yielding the rows in chunk order.
+fn embed_chunks<'a, E: Embed>(
+    embedder: &'a E,
+    concurrency: usize,
+    source: &'a str,
+    unique: &'a UniqueChunks,
+    start: usize,
+) -> impl Stream<Item = Result<NewEmbedding>> + 'a {
+    stream::iter(unique.indexes.iter().zip(&unique.chunks).skip(start))
+        .map(move |(&chunk_index, chunk)| async move {
+            let embedding = embedder.embed(&chunk.text).await?;
+            let mut metadata = json!({
+                "source": source,
+                "chunk_index": chunk_index,
+            });
+            metadata
+                .as_object_mut()
+                .expect("metadata is an object")
+                .extend(chunk.metadata.clone());
+            Ok(NewEmbedding {
+                text: chunk.text.clone(),
+                embedding,
+                metadata: Some(metadata),
+            })
+        })
+        .buffered(concurrency)
+}
+
+/// Re-extracts and re-embeds one PDF, then swaps its chunks in a single transaction, so
+/// searches never see it half loaded. Returns the number of chunks stored.
+pub async fn replace_document(
+    config: &Config,
+    vdb_client: &VectorDb,
+    pdf_filepath: &str,
+    force: bool,
+    progress: &MultiProgress,
+) -> Result<usize> {
+    let embedder = Embedder::new(config).await?.with_rate_limit(
+        config.ingestion.requests_per_second,
+        config.ingestion.concurrency,
+    );
+    vdb_client.create_embeddings_table()?;
+    check_embeddings_model(vdb_client, &config.models.embeddings_model, force)?;
+    let source = source_name(pdf_filepath);
+    let parsed_pdf = extract_text_from_pdf(pdf_filepath, &config.chunking, &config.extraction)
+        .with_context(|| format!("Failed to extract text from {}", pdf_filepath))?;
+    let mut dedup = new_deduplicator(config, vdb_client)?;
+    let unique = dedup::unique_chunks(
+        dedup.as_mut(),
+        config.dedup.mode,
+        &source,
+        parsed_pdf.chunks,
+    );
+    let chunks = &unique.chunks;
+
+    let bar = progress.add(ProgressBar::new(chunks.len() as u64));
+    bar.set_style(bar_style("chunks"));
+    bar.set_message(source.clone());
+    let mut rows = Vec::with_capacity(chunks.len());
+    let mut embedded = embed_chunks(&embedder, config.ingestion.concurrency, &source, &unique, 0);
+    while let Some(row) = embedded.next().await {
+        rows.push(row?);
+        bar.inc(1);
+    }
+    bar.finish_and_clear();
+
+    vdb_client.replace_document(&source, &document_fingerprint(chunks), &rows)?;
+    vdb_client.save_document_metadata(&source, &parsed_pdf.metadata)?;
+    dedup::apply_links(vdb_client, &unique.links)?;
+    tracing::info!(
+        "Replaced {} with {} chunks, leaving out {} repeats",
+        source,
+        rows.len(),
+        unique.exact_duplicates + unique.near_duplicates
+    );
+    Ok(rows.len())
+}
+
+/// The name a PDF is stored under: its file name, without the directory.
+fn source_name(pdf_filepath: &str) -> String {
+    Path::new(pdf_filepath)
+        .file_name()
+        .map(|f| f.to_string_lossy().to_string())
+        .unwrap_or_else(|| pdf_filepath.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::config::DatabaseConfig;
+    use indicatif::ProgressDrawTarget;
+
+    fn temp_db(dir: &tempfile::TempDir) -> VectorDb {
+        let config = DatabaseConfig {
+            local_path: dir.path().join("test.db").to_string_lossy().to_string(),
+            ..DatabaseConfig::default()
+        };
+        let vdb_client = VectorDb::open_local(&config).unwrap();
+        vdb_client.create_embeddings_table().unwrap();
+        vdb_client
+    }
+
+    #[test]
+    fn test_embeddings_model_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let vdb_client = temp_db(&dir);
+        check_embeddings_model(&vdb_client, "titan-v2", false).unwrap();
+        // An empty database takes whichever model comes first.
+        check_embeddings_model(&vdb_client, "titan-v1", false).unwrap();
+        assert_eq!(
+            vdb_client.meta("embeddings_model").unwrap().as_deref(),
+            Some("titan-v1")
+        );
+
+        vdb_client
+            .insert_embedding("chunk", &[1.0, 0.0], None)
+            .unwrap();
+        let error = check_embeddings_model(&vdb_client, "titan-v2", false).unwrap_err();
+        assert!(error.to_string().contains("embedded with titan-v1"));
+        check_embeddings_model(&vdb_client, "titan-v2", true).unwrap();
+        assert_eq!(
+            vdb_client.meta("embeddings_model").unwrap().as_deref(),
+            Some("titan-v1")
+        );
+    }
+
+    /// Embeds a text as its length, failing on `fail_on`. Longer texts answer sooner, so
+    /// chunks finish out of order; `finished` records the order.
+    #[derive(Default)]
+    struct StubEmbedder {
+        fail_on: Option<&'static str>,
+        finished: std::sync::Mutex<Vec<usize>>,
+    }
+
+    impl Embed for StubEmbedder {
+        async fn embed(&self, text: &str) -> Result<Vec<f32>> {
+            let delay = 20u64.saturating_sub(text.len() as u64);
+            tokio::time::sleep(std::time::Duration::from_millis(delay)).await;
+            if self.fail_on == Some(text) {
+                bail!("stub failure");
+            }
+            self.finished.lock().unwrap().push(text.len());
+            Ok(vec![text.len() as f32, 1.0])
+        }
+    }
+
+    /// Chunks `x`, `xx`, ... for the given chunk indexes, so the text's length is the index + 1.
+    fn unique_chunks(indexes: &[usize]) -> UniqueChunks {
+        UniqueChunks {
+            chunks: indexes
+                .iter()
+                .map(|i| crate::pdftools::Chunk {
+                    text: "x".repeat(i + 1),
+                    metadata: Default::default(),
+                })
+                .collect(),
+            indexes: indexes.to_vec(),
+            ..UniqueChunks::default()
+        }
+    }
+
+    fn stored_chunks(vdb_client: &VectorDb) -> Vec<(usize, usize, f32)> {
+        let mut stored = Vec::new();
+        vdb_client
+            .for_each_embedding(|row| {
+                let metadata = row.metadata.unwrap();
+                stored.push((
+                    metadata["chunk_index"].as_u64().unwrap() as usize,
+                    row.text.len(),
+                    row.embedding[0],
+                ));
+                Ok(())
+            })
+            .unwrap();
+        stored
+    }
+
+    #[tokio::test]
+    async fn test_embed_and_insert_keeps_chunk_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let vdb_client = temp_db(&dir);
+        let ingestion = IngestionConfig {
+            concurrency: 4,
+            batch_size: 2,
+            ..IngestionConfig::default()
+        };
+        let unique = unique_chunks(&[0, 2, 3, 5, 6]);
+        vdb_client.start_document("a.pdf", "fp", 5).unwrap();
+        let embedder = StubEmbedder::default();
+        embed_and_insert(
+            &embedder,
+            &vdb_client,
+            &ingestion,
+            "a.pdf",
+            &unique,
+            0,
+            &ProgressBar::hidden(),
+        )
+        .await
+        .unwrap();
+
+        let finished = embedder.finished.lock().unwrap().clone();
+        assert_ne!(finished, vec![1, 3, 4, 6, 7]);
+        assert_eq!(
+            stored_chunks(&vdb_client),
+            vec![
+                (0, 1, 1.0),
+                (2, 3, 3.0),
+                (3, 4, 4.0),
+                (5, 6, 6.0),
+                (6, 7, 7.0)
+            ]
+        );
+        let progress = vdb_client.document_progress("a.pdf").unwrap().unwrap();
+        assert_eq!(progress.committed_chunks, 5);
+    }
+
+    #[tokio::test]
+    async fn test_failure_leaves_journal_at_last_batch() {
+        let dir = tempfile::tempdir().unwrap();
+        let vdb_client = temp_db(&dir);
+        let ingestion = IngestionConfig {
+            concurrency: 4,
+            batch_size: 2,
+            ..IngestionConfig::default()
+        };
+        let unique = unique_chunks(&[0, 2, 3, 5, 6]);
+        vdb_client.start_document("a.pdf", "fp", 5).unwrap();
+        // The fourth chunk fails, after the first batch of two is committed.
+        let failing = StubEmbedder {
+            fail_on: Some("xxxxxx"),
+            ..StubEmbedder::default()
+        };
+        let bar = ProgressBar::hidden();
+        let result =
+            embed_and_insert(&failing, &vdb_client, &ingestion, "a.pdf", &unique, 0, &bar).await;
+        assert!(result.is_err());
+        let progress = vdb_client.document_progress("a.pdf").unwrap().unwrap();
+        assert_eq!(progress.committed_chunks, 2);
+        assert_eq!(stored_chunks(&vdb_client), vec![(0, 1, 1.0), (2, 3, 3.0)]);
+
+        // Resuming from the journal stores the rest, each under its own chunk index.
+        let embedder = StubEmbedder::default();
+        embed_and_insert(
+            &embedder,
+            &vdb_client,
+            &ingestion,
+            "a.pdf",
+            &unique,
+            2,
+            &bar,
+        )
+        .await
+        .unwrap();
+        let indexes: Vec<usize> = stored_chunks(&vdb_client).iter().map(|c| c.0).collect();
+        assert_eq!(indexes, vec![0, 2, 3, 5, 6]);
+        assert!(stored_chunks(&vdb_client)
+            .iter()
+            .all(|&(index, len, embedding)| len == index + 1 && embedding == len as f32));
+    }
+
+    #[tokio::test]
+    async fn test_one_corrupt_file_doesnt_stop_the_batch() {
+        let dir = tempfile::tempdir().unwrap();
+        let vdb_client = temp_db(&dir);
+        let broken = dir.path().join("broken.pdf");
+        std::fs::write(&broken, b"%PDF-1.5 and then nothing").unwrap();
+        let pdf_filenames = vec![
+            concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/outline.pdf").to_string(),
+            broken.to_string_lossy().to_string(),
+            concat!(env!("CARGO_MANIFEST_DIR"), "/pdfs/ticket_to_ride.pdf").to_string(),
+        ];
+        let progress = MultiProgress::with_draw_target(ProgressDrawTarget::hidden());
+        let summary = load_with(
+            &StubEmbedder::default(),
+            &Config::default(),
+            &vdb_client,
+            &pdf_filenames,
+            false,
+            false,
+            &progress,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(summary.documents_processed, 2);
+        assert_eq!(summary.documents_failed, 1);
+        assert_eq!(summary.failures[0].source, "broken.pdf");
+        assert_eq!(summary.quarantined, vec![pdf_filenames[1].clone()]);
+        assert_eq!(
+            vdb_client.count_embeddings().unwrap() as usize,
+            summary.chunks_inserted
+        );
+        for source in ["outline.pdf", "ticket_to_ride.pdf"] {
+            let progress = vdb_client.document_progress(source).unwrap().unwrap();
+            assert_eq!(progress.status, DocumentStatus::Done);
+        }
+    }
+}
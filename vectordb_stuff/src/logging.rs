@@ -0,0 +1,56 @@
+use indicatif::MultiProgress;
+use std::io::{self, IsTerminal, Write};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::EnvFilter;
+
+/// Sends `tracing` output to stderr, filtered by `RUST_LOG` (`info` when unset), without
+/// tearing the progress bars drawn by `progress`.
+pub fn init(progress: &MultiProgress) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_target(false)
+        .with_ansi(io::stderr().is_terminal())
+        .with_writer(ProgressWriter(progress.clone()))
+        .init();
+}
+
+#[derive(Clone)]
+struct ProgressWriter(MultiProgress);
+
+impl<'a> MakeWriter<'a> for ProgressWriter {
+    type Writer = ProgressLine;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        ProgressLine {
+            progress: self.0.clone(),
+            buffer: Vec::new(),
+        }
+    }
+}
+
+/// Collects one log event and prints it with the progress bars cleared out of the way.
+struct ProgressLine {
+    progress: MultiProgress,
+    buffer: Vec<u8>,
+}
+
+impl Write for ProgressLine {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for ProgressLine {
+    fn drop(&mut self) {
+        let buffer = std::mem::take(&mut self.buffer);
+        self.progress.suspend(|| {
+            let _ = io::stderr().write_all(&buffer);
+        });
+    }
+}
@@ -1,74 +1,55 @@
+mod answer;
+mod cleanup;
 mod cli;
+mod commands;
+mod dedup;
+mod export;
+mod ingest;
+mod logging;
+mod outline;
 mod pdftools;
+mod tables;
 
 use anyhow::Result;
-use common::embeddings::create_embeddings;
-use common::vectordb::VectorDb;
-use pdftools::{extract_text_from_pdf, get_pdf_filenames};
+use cli::{Command, ConfigCommand};
+use common::config::Config;
+use indicatif::MultiProgress;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = cli::parse_args();
-    let use_local_db = true;
-    let vdb_client = VectorDb::new(use_local_db).await?;
-
-    // Mode 1:
-    //   Step 1: Reset the vector database.  --clear_database
-    if cli.clear_database {
-        println!("Clearing local database...");
-        vdb_client.drop_embeddings_table()?;
+    if let Command::Completions { shell } = cli.command {
+        commands::completions(shell);
+        return Ok(());
     }
 
-    // Mode 2: --load_documents
-    //  Step 1: Put any PDFs you want the model to reference in the `pdfs` directory.
-    //  Step 2: Parse the PDF documents from pdfs directory
-    //  Step 3: Add PDF documents to the vector database
-    //  Step 4: Ready to search for similar documents and use the lambda.
-    if cli.load_documents {
-        println!("Loading documents into local database...");
-        let pdf_dir = "pdfs".to_string();
-        let embeddings_model_name = "amazon.titan-embed-text-v2:0".to_string();
-        let pdf_filenames = get_pdf_filenames(pdf_dir);
-        let mut parsed_pdf_files = Vec::new();
-        vdb_client.create_embeddings_table()?;
-
-        for (i, pdf_filepath) in pdf_filenames.iter().enumerate() {
-            println!(
-                "Processing {}/{}: {}",
-                i + 1,
-                pdf_filenames.len(),
-                pdf_filepath
-            );
-            let parsed_pdf = extract_text_from_pdf(pdf_filepath.as_str())?;
-            parsed_pdf_files.push(parsed_pdf.clone());
-
-            // parsed_pdf.chunks
-            // parsed_pdf.filename
-            // parsed_pdf.contents
-
-            println!("Preparing to add documents to vector database...");
-            for chunk in &parsed_pdf.chunks {
-                let embeddings = create_embeddings(chunk, &embeddings_model_name).await?;
-
-                let embedding_vec: Vec<f32> = embeddings
-                    .get("embedding") // Get the "embedding" field from the response object
-                    .and_then(|e| e.as_array())
-                    .ok_or_else(|| anyhow::anyhow!("Embeddings field not found or not an array"))?
-                    .iter()
-                    .map(|v| v.as_f64().unwrap_or_default() as f32)
-                    .collect();
-
-                // TODO: Create a record with the filename, chunk, and embedding
-                println!(
-                    "Inserting embedding {:?} for {} into database...",
-                    embedding_vec, chunk
-                );
-                vdb_client.insert_embedding(&chunk, &embedding_vec, None)?;
-            } // end for loop that creates embeddings from text chunks and inserts into db
-        } // end for loop pdf filenames
-          // Copy the embeddings database to S3
-        vdb_client.push_to_s3().await?;
+    let progress = MultiProgress::new();
+    logging::init(&progress);
+    let mut config = Config::load(cli.config.as_deref())?;
+    if let Some(collection) = &cli.collection {
+        config.database.local_path = collection.to_string_lossy().to_string();
     }
 
-    Ok(())
+    match cli.command {
+        Command::Ingest(args) => commands::ingest(config, args, &progress).await,
+        Command::Query(args) => commands::query(config, args).await,
+        Command::Stats(args) => commands::stats(&config, args),
+        Command::ListDocs(args) => commands::list_docs(&config, args),
+        Command::DeleteDoc(args) => commands::delete_doc(&config, args),
+        Command::ReplaceDoc(args) => commands::replace_doc(config, args, &progress).await,
+        Command::UpdateChunk(args) => commands::update_chunk(&config, args),
+        Command::Export(args) => commands::export(&config, args),
+        Command::Import(args) => commands::import(&config, args),
+        Command::Publish(args) => commands::publish(&config, args).await,
+        Command::Verify => commands::verify(&config),
+        Command::Completions { .. } => unreachable!("handled above"),
+        Command::Config(ConfigCommand::Show) => {
+            match &config.source {
+                Some(path) => println!("# Loaded from {}", path.display()),
+                None => println!("# No config file found, using defaults"),
+            }
+            print!("{}", config.to_toml()?);
+            Ok(())
+        }
+    }
 }
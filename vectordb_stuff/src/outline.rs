@@ -0,0 +1,327 @@
+//! What a PDF says about itself, and which section of its outline each chunk is in.
+//!
+//! Outline entries only point at pages, so each is placed in the cleaned text where its title
+//! appears on (or near) its page, and at the start of the page when the title isn't found.
+//! Chunks are placed by their first words, and tables by their page.
+
+use crate::pdftools::Chunk;
+use chrono::{FixedOffset, NaiveDate, TimeZone, Utc};
+use common::vectordb::{DocumentMetadata, OutlineEntry};
+use pdf_extract::{Document, Object};
+use regex::Regex;
+use serde_json::Value;
+
+/// Separates the titles in a heading, as in "Rules › Scoring".
+const HEADING_SEPARATOR: &str = " › ";
+/// Words from the start of a chunk used to find it in the text.
+const CHUNK_KEY_WORDS: usize = 8;
+/// How many words before or after its page an outline title may be found.
+const PAGE_SLACK: usize = 50;
+
+/// The title, author and creation date from the info dictionary, the page count and the
+/// outline. Missing or unreadable entries are left out.
+pub fn read_metadata(doc: &Document) -> DocumentMetadata {
+    let info = doc
+        .trailer
+        .get(b"Info")
+        .ok()
+        .and_then(|info| resolve(doc, info))
+        .and_then(|info| info.as_dict().ok());
+    let field = |key: &[u8]| {
+        info.and_then(|d| d.get(key).ok())
+            .and_then(|value| resolve(doc, value))
+            .and_then(text_string)
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+    };
+    // Most PDFs have no outline, which get_toc reports as an error.
+    let outline = match doc.get_toc() {
+        Ok(toc) => toc
+            .toc
+            .into_iter()
+            .map(|entry| OutlineEntry {
+                level: entry.level,
+                title: entry.title.trim().to_string(),
+                page: entry.page,
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    DocumentMetadata {
+        title: field(b"Title"),
+        author: field(b"Author"),
+        creation_date: field(b"CreationDate").and_then(|date| pdf_date(&date)),
+        page_count: doc.get_pages().len(),
+        outline,
+    }
+}
+
+fn resolve<'a>(doc: &'a Document, object: &'a Object) -> Option<&'a Object> {
+    match object {
+        Object::Reference(id) => doc.get_object(*id).ok(),
+        other => Some(other),
+    }
+}
+
+/// Decodes a PDF text string: UTF-16BE or UTF-8 with a byte order mark, PDFDocEncoding
+/// (read as Latin-1) otherwise.
+fn text_string(object: &Object) -> Option<String> {
+    let Object::String(bytes, _) = object else {
+        return None;
+    };
+    Some(match bytes.as_slice() {
+        [0xfe, 0xff, rest @ ..] => {
+            let units: Vec<u16> = rest
+                .chunks_exact(2)
+                .map(|b| u16::from_be_bytes([b[0], b[1]]))
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        [0xef, 0xbb, 0xbf, rest @ ..] => String::from_utf8_lossy(rest).into_owned(),
+        _ => bytes.iter().map(|&b| b as char).collect(),
+    })
+}
+
+/// Converts a PDF date like `D:20150309101651-07'00'` to RFC 3339. Dates without a time zone
+/// are returned without an offset.
+fn pdf_date(date: &str) -> Option<String> {
+    let pattern = Regex::new(
+        r"^(?:D:)?(\d{4})(\d{2})?(\d{2})?(\d{2})?(\d{2})?(\d{2})?(?:(Z)|([+-])(\d{2})'?(?:(\d{2})'?)?)?",
+    )
+    .unwrap();
+    let caps = pattern.captures(date.trim())?;
+    let number = |i: usize, default: u32| {
+        caps.get(i)
+            .map_or(Some(default), |m| m.as_str().parse().ok())
+    };
+    let naive = NaiveDate::from_ymd_opt(number(1, 0)? as i32, number(2, 1)?, number(3, 1)?)?
+        .and_hms_opt(number(4, 0)?, number(5, 0)?, number(6, 0)?)?;
+    if caps.get(7).is_some() {
+        return Some(Utc.from_utc_datetime(&naive).to_rfc3339());
+    }
+    match caps.get(8) {
+        Some(sign) => {
+            let seconds = (number(9, 0)? * 3600 + number(10, 0)? * 60) as i32;
+            let sign = if sign.as_str() == "-" { -1 } else { 1 };
+            let offset = FixedOffset::east_opt(sign * seconds)?;
+            Some(offset.from_local_datetime(&naive).single()?.to_rfc3339())
+        }
+        None => Some(naive.format("%Y-%m-%dT%H:%M:%S").to_string()),
+    }
+}
+
+/// Adds `"heading"` to the metadata of each chunk that comes after the first outline entry:
+/// the titles from the top of the outline down to the section the chunk starts in. `text` is
+/// the cleaned text the chunks were split from and `pages` the pages before cleanup.
+pub fn attach_headings(
+    chunks: &mut [Chunk],
+    text: &str,
+    pages: &[String],
+    outline: &[OutlineEntry],
+) {
+    if outline.is_empty() {
+        return;
+    }
+    let words: Vec<String> = text.split_whitespace().map(word_key).collect();
+    let page_starts = page_starts(pages, words.len());
+    let page_start = |page: usize| {
+        page_starts
+            .get(page.saturating_sub(1))
+            .copied()
+            .unwrap_or(words.len())
+    };
+
+    let mut sections: Vec<(usize, String)> = Vec::new();
+    let mut path: Vec<&str> = Vec::new();
+    let mut position = 0;
+    for entry in outline {
+        path.truncate(entry.level.saturating_sub(1));
+        path.push(&entry.title);
+        let title: Vec<String> = entry.title.split_whitespace().map(word_key).collect();
+        let from = page_start(entry.page)
+            .saturating_sub(PAGE_SLACK)
+            .max(position);
+        let to = page_start(entry.page + 1) + PAGE_SLACK;
+        position = find_words(&words, &title, from, to)
+            .unwrap_or_else(|| page_start(entry.page).max(position));
+        sections.push((position, path.join(HEADING_SEPARATOR)));
+    }
+
+    let mut position = 0;
+    for chunk in chunks {
+        let start = match chunk.metadata.get("page").and_then(Value::as_u64) {
+            Some(page) => page_start(page as usize),
+            None => {
+                let key: Vec<String> = chunk
+                    .text
+                    .split_whitespace()
+                    .take(CHUNK_KEY_WORDS)
+                    .map(word_key)
+                    .collect();
+                if let Some(found) = find_words(&words, &key, position, words.len()) {
+                    position = found;
+                }
+                position
+            }
+        };
+        if let Some((_, heading)) = sections.iter().rev().find(|(at, _)| *at <= start) {
+            chunk
+                .metadata
+                .insert("heading".to_string(), Value::from(heading.as_str()));
+        }
+    }
+}
+
+/// Lowercase letters and digits, so words compare the same before and after cleanup.
+fn word_key(word: &str) -> String {
+    word.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// The first position from `from` to `to` where `needle` starts in `words`.
+fn find_words(words: &[String], needle: &[String], from: usize, to: usize) -> Option<usize> {
+    if needle.is_empty() || needle.len() > words.len() {
+        return None;
+    }
+    let last = to.min(words.len() - needle.len());
+    (from..=last).find(|&i| words[i..i + needle.len()] == *needle)
+}
+
+/// Where each page starts among the `total_words` words of the cleaned text, estimated from
+/// the pages' word counts before cleanup.
+fn page_starts(pages: &[String], total_words: usize) -> Vec<usize> {
+    let counts: Vec<usize> = pages.iter().map(|p| p.split_whitespace().count()).collect();
+    let raw_total: usize = counts.iter().sum();
+    let scale = if raw_total == 0 {
+        0.0
+    } else {
+        total_words as f64 / raw_total as f64
+    };
+    let mut starts = Vec::with_capacity(pages.len());
+    let mut before = 0;
+    for count in counts {
+        starts.push((before as f64 * scale).round() as usize);
+        before += count;
+    }
+    starts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pdftools::extract_text_from_pdf;
+    use common::config::{ChunkingConfig, ExtractionConfig};
+
+    fn entry(level: usize, title: &str, page: usize) -> OutlineEntry {
+        OutlineEntry {
+            level,
+            title: title.to_string(),
+            page,
+        }
+    }
+
+    #[test]
+    fn test_attach_headings() {
+        let pages = vec![
+            "Ticket to Ride\nSetup\nEach player takes 45 trains.".to_string(),
+            "Scoring\nLonger routes score more.\nLongest Route\nThe bonus is 10 points."
+                .to_string(),
+        ];
+        let text = pages.join("\n\n");
+        let mut chunks: Vec<Chunk> = [
+            "Ticket to Ride",
+            "Setup Each player takes 45 trains.",
+            "Longer routes score more.",
+            "The bonus is 10 points.",
+        ]
+        .iter()
+        .map(|t| Chunk::new(t.to_string()))
+        .collect();
+        chunks.push(Chunk::table("| Length | Points |".to_string(), 2));
+        let outline = [
+            entry(1, "Setup", 1),
+            entry(1, "Scoring", 2),
+            entry(2, "Longest Route", 2),
+        ];
+        attach_headings(&mut chunks, &text, &pages, &outline);
+
+        let headings: Vec<Option<&str>> = chunks
+            .iter()
+            .map(|c| c.metadata.get("heading").and_then(Value::as_str))
+            .collect();
+        assert_eq!(
+            headings,
+            vec![
+                None,
+                Some("Setup"),
+                Some("Scoring"),
+                Some("Scoring › Longest Route"),
+                Some("Scoring"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pdf_date() {
+        assert_eq!(
+            pdf_date("D:20150309101651-07'00'").as_deref(),
+            Some("2015-03-09T10:16:51-07:00")
+        );
+        assert_eq!(
+            pdf_date("D:20240506102837Z").as_deref(),
+            Some("2024-05-06T10:28:37+00:00")
+        );
+        assert_eq!(pdf_date("D:2007").as_deref(), Some("2007-01-01T00:00:00"));
+        assert_eq!(pdf_date("yesterday"), None);
+    }
+
+    #[test]
+    fn test_read_metadata() {
+        let pdf = concat!(env!("CARGO_MANIFEST_DIR"), "/pdfs/ticket_to_ride.pdf");
+        let doc = Document::load(pdf).unwrap();
+        let metadata = read_metadata(&doc);
+        assert_eq!(
+            metadata.title.as_deref(),
+            Some("[T2R] rules EN reprint 2015_TTR2 rules US")
+        );
+        assert_eq!(metadata.author.as_deref(), Some("cyrille"));
+        assert_eq!(
+            metadata.creation_date.as_deref(),
+            Some("2015-03-09T10:16:51-07:00")
+        );
+        assert_eq!(metadata.page_count, 4);
+        assert!(metadata.outline.is_empty());
+    }
+
+    #[test]
+    fn test_outline_fixture() {
+        // Two pages with bookmarks "Setup", "Scoring" and, under it, "Longest Route".
+        let pdf = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/outline.pdf");
+        let chunking = ChunkingConfig {
+            chunk_size: 60,
+            chunk_overlap: 0,
+            ..ChunkingConfig::default()
+        };
+        let parsed = extract_text_from_pdf(pdf, &chunking, &ExtractionConfig::default()).unwrap();
+        assert_eq!(parsed.metadata.title.as_deref(), Some("Game Rules"));
+        assert_eq!(
+            parsed.metadata.outline,
+            vec![
+                entry(1, "Setup", 1),
+                entry(1, "Scoring", 2),
+                entry(2, "Longest Route", 2)
+            ]
+        );
+        let last = parsed.chunks.last().unwrap();
+        assert_eq!(
+            last.text,
+            "The player with the longest route gets 10 points."
+        );
+        assert_eq!(
+            last.metadata.get("heading"),
+            Some(&Value::from("Scoring › Longest Route"))
+        );
+    }
+}
@@ -1,9 +1,17 @@
 // use anyhow::{Context, Result};
 
+use crate::cleanup::clean_pages;
+use crate::outline;
+use crate::tables::{self, RunCollector, Table};
+use common::config::{Chunker, ChunkingConfig, ExtractionConfig};
+use common::vectordb::DocumentMetadata;
 use pdf_extract;
+use pdf_extract::{Document, MediaBox, OutputDev, OutputError, PlainTextOutput, Transform};
 use regex::Regex;
+use serde_json::{Map, Value};
+use std::fmt;
 use std::fs;
-use std::io::{self};
+use std::panic::{self, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
 
 pub fn get_pdf_filenames(directory: String) -> Vec<String> {
@@ -34,8 +42,66 @@ pub fn get_pdf_filenames(directory: String) -> Vec<String> {
 pub struct ParsedPdf {
     pub filename: String,
     pub contents: String,
-    pub chunks: Vec<String>,
+    pub chunks: Vec<Chunk>,
+    pub metadata: DocumentMetadata,
+    /// Problems that didn't stop the document from loading, like pages that couldn't be read.
+    pub warnings: Vec<String>,
 }
+
+/// Why a PDF can't be read at all. Loading it again won't help until the file is fixed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum UnreadablePdf {
+    /// Encrypted, and neither the empty password nor `extraction.password` opens it.
+    Encrypted,
+    /// Not a PDF lopdf can parse, or pdf_extract failed on every page.
+    Corrupt(String),
+}
+
+impl fmt::Display for UnreadablePdf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnreadablePdf::Encrypted => write!(
+                f,
+                "the PDF is encrypted; set RAGTIME_PDF_PASSWORD or pass --pdf-password"
+            ),
+            UnreadablePdf::Corrupt(reason) => write!(f, "the PDF is damaged: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for UnreadablePdf {}
+
+/// A piece of a document to embed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub text: String,
+    /// Stored in the chunk's metadata next to its source and chunk index.
+    pub metadata: Map<String, Value>,
+}
+
+impl Chunk {
+    pub fn new(text: String) -> Self {
+        Chunk {
+            text,
+            metadata: Map::new(),
+        }
+    }
+
+    /// A table in Markdown, tagged `"kind": "table"` with the page it was found on.
+    pub fn table(text: String, page: u32) -> Self {
+        let mut metadata = Map::new();
+        metadata.insert("kind".to_string(), Value::from("table"));
+        metadata.insert("page".to_string(), Value::from(page));
+        Chunk { text, metadata }
+    }
+}
+
+impl AsRef<str> for Chunk {
+    fn as_ref(&self) -> &str {
+        &self.text
+    }
+}
+
 // Text Processing
 pub fn split_text_into_sentences(text: &str) -> Vec<String> {
     let text = text
@@ -47,14 +113,21 @@ pub fn split_text_into_sentences(text: &str) -> Vec<String> {
 
     let re = Regex::new(r"[.!?][\s]+").unwrap();
 
-    let sentences: Vec<String> = re
-        .split(&text)
+    // Split after the punctuation, so each sentence keeps its full stop.
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    for m in re.find_iter(&text) {
+        sentences.push(&text[start..m.start() + 1]);
+        start = m.end();
+    }
+    sentences.push(&text[start..]);
+
+    sentences
+        .into_iter()
         .map(|s| s.trim())
         .filter(|s| !s.is_empty())
         .map(|s| s.to_string())
-        .collect();
-
-    sentences
+        .collect()
 }
 
 pub struct TextSplitter {
@@ -112,24 +185,396 @@ impl TextSplitter {
     }
 }
 
-pub fn extract_text_from_pdf(file_path: &str) -> io::Result<ParsedPdf> {
+/// Groups consecutive sentences into chunks of at most `chunk_size` bytes. Each chunk starts
+/// with the trailing sentences of the previous one that fit in `chunk_overlap` bytes.
+/// A sentence longer than `chunk_size` becomes a chunk of its own.
+pub fn pack_sentences(
+    sentences: &[String],
+    chunk_size: usize,
+    chunk_overlap: usize,
+) -> Vec<String> {
+    // Length of `sentences` joined with spaces.
+    fn joined_len(sentences: &[&str]) -> usize {
+        sentences
+            .iter()
+            .map(|s| s.len() + 1)
+            .sum::<usize>()
+            .saturating_sub(1)
+    }
+
+    let mut chunks = Vec::new();
+    let mut current: Vec<&str> = Vec::new();
+    for sentence in sentences {
+        if !current.is_empty() && joined_len(&current) + 1 + sentence.len() > chunk_size {
+            chunks.push(current.join(" "));
+
+            let mut keep = 0;
+            while keep < current.len()
+                && joined_len(&current[current.len() - keep - 1..]) <= chunk_overlap
+            {
+                keep += 1;
+            }
+            current.drain(..current.len() - keep);
+            while !current.is_empty() && joined_len(&current) + 1 + sentence.len() > chunk_size {
+                current.remove(0);
+            }
+        }
+        current.push(sentence);
+    }
+    if !current.is_empty() {
+        chunks.push(current.join(" "));
+    }
+    chunks
+}
+
+/// Forwards everything to `PlainTextOutput`, for the page's text, and to a `RunCollector`,
+/// for where the text is, so each page is only interpreted once.
+struct PageOutput<'a> {
+    text: PlainTextOutput<&'a mut String>,
+    runs: RunCollector,
+}
+
+impl OutputDev for PageOutput<'_> {
+    fn begin_page(
+        &mut self,
+        page_num: u32,
+        media_box: &MediaBox,
+        art_box: Option<(f64, f64, f64, f64)>,
+    ) -> Result<(), OutputError> {
+        self.text.begin_page(page_num, media_box, art_box)?;
+        self.runs.begin_page(page_num, media_box, art_box)
+    }
+
+    fn end_page(&mut self) -> Result<(), OutputError> {
+        self.text.end_page()?;
+        self.runs.end_page()
+    }
+
+    fn output_character(
+        &mut self,
+        trm: &Transform,
+        width: f64,
+        spacing: f64,
+        font_size: f64,
+        char: &str,
+    ) -> Result<(), OutputError> {
+        self.text
+            .output_character(trm, width, spacing, font_size, char)?;
+        self.runs
+            .output_character(trm, width, spacing, font_size, char)
+    }
+
+    fn begin_word(&mut self) -> Result<(), OutputError> {
+        self.text.begin_word()?;
+        self.runs.begin_word()
+    }
+
+    fn end_word(&mut self) -> Result<(), OutputError> {
+        self.text.end_word()?;
+        self.runs.end_word()
+    }
+
+    fn end_line(&mut self) -> Result<(), OutputError> {
+        self.text.end_line()?;
+        self.runs.end_line()
+    }
+}
+
+/// The text of each page, the tables found on them with their page numbers, and what the
+/// document says about itself.
+struct ExtractedPages {
+    pages: Vec<String>,
+    tables: Vec<(u32, Table)>,
+    metadata: DocumentMetadata,
+    /// Pages pdf_extract failed on; their text is left empty.
+    unreadable_pages: Vec<u32>,
+}
+
+/// Reads every page; with `extraction.tables`, the tables' text is taken out of the pages'
+/// text. A page that fails is left empty, unless every page does.
+fn extract_pages(
+    bytes: &[u8],
+    extraction: &ExtractionConfig,
+) -> Result<ExtractedPages, UnreadablePdf> {
+    let mut doc = Document::load_mem(bytes).map_err(|e| UnreadablePdf::Corrupt(e.to_string()))?;
+    if doc.is_encrypted() {
+        decrypt(&mut doc, extraction.password.as_deref())?;
+    }
+    let page_count = doc.get_pages().len() as u32;
+    if page_count == 0 {
+        return Err(UnreadablePdf::Corrupt("it has no pages".to_string()));
+    }
+    let mut pages = Vec::new();
+    let mut found = Vec::new();
+    let mut unreadable_pages = Vec::new();
+    let mut last_error = None;
+    for page_num in 1..=page_count {
+        let mut text = String::new();
+        let mut output = PageOutput {
+            text: PlainTextOutput::new(&mut text),
+            runs: RunCollector::default(),
+        };
+        if let Err(e) = pdf_extract::output_doc_page(&doc, &mut output, page_num) {
+            tracing::warn!("Can't read page {} of {}: {}", page_num, page_count, e);
+            last_error = Some(e.to_string());
+            unreadable_pages.push(page_num);
+            pages.push(String::new());
+            continue;
+        }
+        let runs = output.runs.into_runs();
+        if extraction.tables {
+            let tables = tables::find_tables(&runs);
+            text = tables::remove_table_text(&text, &runs, &tables);
+            found.extend(tables.into_iter().map(|t| (page_num, t)));
+        }
+        pages.push(text);
+    }
+    if unreadable_pages.len() == pages.len() {
+        return Err(UnreadablePdf::Corrupt(format!(
+            "no page could be read ({})",
+            last_error.unwrap_or_default()
+        )));
+    }
+    Ok(ExtractedPages {
+        pages,
+        tables: found,
+        metadata: outline::read_metadata(&doc),
+        unreadable_pages,
+    })
+}
+
+/// Opens an encrypted document with the empty user password, as pdf_extract does, or else
+/// with `password`.
+fn decrypt(doc: &mut Document, password: Option<&str>) -> Result<(), UnreadablePdf> {
+    // lopdf checks the password before it changes anything, so a wrong one can be retried.
+    if std::iter::once("")
+        .chain(password)
+        .any(|password| doc.decrypt(password).is_ok())
+    {
+        Ok(())
+    } else {
+        Err(UnreadablePdf::Encrypted)
+    }
+}
+
+/// Runs `extract_pages`, turning a panic inside pdf_extract or lopdf into an error, so one
+/// malformed file can't take the whole run down.
+fn extract_pages_safely(
+    bytes: &[u8],
+    extraction: &ExtractionConfig,
+) -> Result<ExtractedPages, UnreadablePdf> {
+    panic::catch_unwind(AssertUnwindSafe(|| extract_pages(bytes, extraction))).unwrap_or_else(
+        |payload| {
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown error".to_string());
+            Err(UnreadablePdf::Corrupt(format!(
+                "the PDF parser crashed: {}",
+                message
+            )))
+        },
+    )
+}
+
+pub fn extract_text_from_pdf(
+    file_path: &str,
+    chunking: &ChunkingConfig,
+    extraction: &ExtractionConfig,
+) -> anyhow::Result<ParsedPdf> {
     let bytes = std::fs::read(file_path)?;
-    let out = pdf_extract::extract_text_from_mem(&bytes)
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    let ExtractedPages {
+        pages,
+        tables,
+        metadata,
+        unreadable_pages,
+    } = extract_pages_safely(&bytes, extraction)?;
+    let mut warnings = Vec::new();
+    if !unreadable_pages.is_empty() {
+        warnings.push(format!(
+            "{} of {} pages could not be read and were skipped: {:?}",
+            unreadable_pages.len(),
+            pages.len(),
+            unreadable_pages
+        ));
+    }
+    let characters: usize = pages
+        .iter()
+        .map(|p| p.chars().filter(|c| !c.is_whitespace()).count())
+        .sum();
+    if characters < extraction.min_chars_per_page * pages.len() && tables.is_empty() {
+        warnings.push(format!(
+            "only {} characters of text on {} pages; it may be scanned images that need OCR",
+            characters,
+            pages.len()
+        ));
+    }
+    for warning in &warnings {
+        tracing::warn!("{}: {}", file_path, warning);
+    }
+    let out = clean_pages(&pages, extraction);
     let sentences = split_text_into_sentences(&out);
     //println!("This is the parsed text from {}: {}", file_path, out);
+    tracing::debug!("Found {} sentences in {}", sentences.len(), file_path);
     for (i, s) in sentences.iter().enumerate() {
-        println!("Sentence {i}: {s}")
+        tracing::trace!("Sentence {i}: {s}")
     }
 
-    //
-    let splitter = TextSplitter::new(600, 120); //chunk size= 600, overlap= 120
-    let chunks = splitter.split_text(&out);
+    let chunks = match chunking.chunker {
+        Chunker::Recursive => {
+            TextSplitter::new(chunking.chunk_size, chunking.chunk_overlap).split_text(&out)
+        }
+        Chunker::Sentences => {
+            pack_sentences(&sentences, chunking.chunk_size, chunking.chunk_overlap)
+        }
+    };
+    let mut chunks: Vec<Chunk> = chunks.into_iter().map(Chunk::new).collect();
+    if !tables.is_empty() {
+        tracing::debug!("Found {} tables in {}", tables.len(), file_path);
+    }
+    for (page, table) in tables {
+        chunks.extend(
+            table
+                .markdown_pieces(chunking.chunk_size)
+                .into_iter()
+                .map(|piece| Chunk::table(piece, page)),
+        );
+    }
+    outline::attach_headings(&mut chunks, &out, &pages, &metadata.outline);
 
     let obj = ParsedPdf {
         filename: file_path.to_string(),
         contents: out,
         chunks: chunks,
+        metadata,
+        warnings,
     };
     Ok(obj)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_text_into_sentences() {
+        assert_eq!(
+            split_text_into_sentences("Draw two cards.  Then\nplay one! Done?"),
+            vec!["Draw two cards.", "Then play one!", "Done?"]
+        );
+    }
+
+    #[test]
+    fn test_pack_sentences() {
+        let sentences: Vec<String> = ["Aaaa.", "Bbbb.", "Cccc.", "Dddddddddddddddd."]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(
+            pack_sentences(&sentences, 12, 5),
+            vec!["Aaaa. Bbbb.", "Bbbb. Cccc.", "Dddddddddddddddd."]
+        );
+        assert_eq!(
+            pack_sentences(&sentences, 17, 0),
+            vec!["Aaaa. Bbbb. Cccc.", "Dddddddddddddddd."]
+        );
+    }
+
+    #[test]
+    fn test_tables_become_chunks() {
+        let pdf = concat!(env!("CARGO_MANIFEST_DIR"), "/pdfs/ticket_to_ride.pdf");
+        let parsed = extract_text_from_pdf(
+            pdf,
+            &ChunkingConfig::default(),
+            &ExtractionConfig::default(),
+        )
+        .unwrap();
+        let tables: Vec<&Chunk> = parsed
+            .chunks
+            .iter()
+            .filter(|c| c.metadata.get("kind") == Some(&Value::from("table")))
+            .collect();
+        assert_eq!(tables.len(), 1);
+        assert!(tables[0]
+            .text
+            .starts_with("| Route Length | Points Scored |\n| --- | --- |\n| 1 | 1 |"));
+        assert!(tables[0].text.ends_with("| 6 | 15 |"));
+        assert!(!parsed.contents.contains("Route Length Points Scored"));
+
+        let without = ExtractionConfig {
+            tables: false,
+            ..ExtractionConfig::default()
+        };
+        let parsed = extract_text_from_pdf(pdf, &ChunkingConfig::default(), &without).unwrap();
+        assert!(parsed.chunks.iter().all(|c| c.metadata.is_empty()));
+    }
+
+    #[test]
+    fn test_unreadable_pdfs() {
+        let config = ExtractionConfig::default();
+        assert!(matches!(
+            extract_pages_safely(b"not a pdf at all", &config),
+            Err(UnreadablePdf::Corrupt(_))
+        ));
+
+        let pdf = std::fs::read(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/pdfs/ticket_to_ride.pdf"
+        ))
+        .unwrap();
+        let truncated = &pdf[..pdf.len() / 3];
+        assert!(extract_pages_safely(truncated, &config).is_err());
+    }
+
+    #[test]
+    fn test_encrypted_pdf() {
+        // outline.pdf encrypted by lopdf with the user password "secret".
+        let pdf = std::fs::read(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/fixtures/encrypted.pdf"
+        ))
+        .unwrap();
+        assert!(matches!(
+            extract_pages_safely(&pdf, &ExtractionConfig::default()),
+            Err(UnreadablePdf::Encrypted)
+        ));
+        let wrong = ExtractionConfig {
+            password: Some("guess".to_string()),
+            ..ExtractionConfig::default()
+        };
+        assert!(matches!(
+            extract_pages_safely(&pdf, &wrong),
+            Err(UnreadablePdf::Encrypted)
+        ));
+
+        let right = ExtractionConfig {
+            password: Some("secret".to_string()),
+            ..ExtractionConfig::default()
+        };
+        let pages = extract_pages_safely(&pdf, &right).unwrap();
+        assert!(pages.unreadable_pages.is_empty());
+        assert!(pages.pages.iter().any(|page| page.contains("Scoring")));
+    }
+
+    #[test]
+    fn test_little_text_is_a_warning() {
+        let pdf = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/outline.pdf");
+        let parsed = extract_text_from_pdf(
+            pdf,
+            &ChunkingConfig::default(),
+            &ExtractionConfig::default(),
+        )
+        .unwrap();
+        assert!(parsed.warnings.is_empty());
+
+        let strict = ExtractionConfig {
+            min_chars_per_page: 1000,
+            ..ExtractionConfig::default()
+        };
+        let parsed = extract_text_from_pdf(pdf, &ChunkingConfig::default(), &strict).unwrap();
+        assert_eq!(parsed.warnings.len(), 1);
+        assert!(parsed.warnings[0].contains("OCR"));
+        assert!(!parsed.chunks.is_empty());
+    }
+}
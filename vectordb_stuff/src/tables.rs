@@ -0,0 +1,440 @@
+//! Finds tables in a PDF page from the positions of its text.
+//!
+//! `pdf_extract` writes text in the order it appears in the content stream, which for tables
+//! is often column by column, so a scoring table comes out as a run of loose numbers. Here
+//! the page's text is collected as positioned runs, runs on the same baseline are grouped
+//! into rows, and consecutive rows whose cells line up in the same columns become a table.
+
+use pdf_extract::{MediaBox, OutputDev, OutputError, Transform};
+use regex::Regex;
+use std::sync::LazyLock;
+
+/// A gap wider than this many font sizes separates two cells on a line.
+const CELL_GAP: f64 = 1.0;
+/// Runs whose baselines differ by less than this many font sizes are on the same row.
+const ROW_TOLERANCE: f64 = 0.3;
+/// Rows further apart than this many font sizes don't belong to the same table.
+const MAX_ROW_GAP: f64 = 3.0;
+/// Longer runs are prose, not cells.
+const MAX_CELL_CHARS: usize = 40;
+/// Fewest rows, header included, that make a table.
+const MIN_ROWS: usize = 3;
+
+/// A row starting with one of these is a list item, not a table row.
+static LIST_MARKER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^(\d+[.)]|[•●▪◦\-–])$").unwrap());
+
+/// Text on one line of a page with no gap wider than `CELL_GAP` in it. Coordinates are in
+/// points from the top left corner of the page.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextRun {
+    pub text: String,
+    pub x0: f64,
+    pub x1: f64,
+    pub y: f64,
+    pub size: f64,
+}
+
+/// An `OutputDev` that records where each run of text is on the page.
+#[derive(Debug, Default)]
+pub struct RunCollector {
+    page_height: f64,
+    current: Option<TextRun>,
+    runs: Vec<TextRun>,
+}
+
+impl RunCollector {
+    pub fn into_runs(mut self) -> Vec<TextRun> {
+        self.flush();
+        self.runs
+    }
+
+    fn flush(&mut self) {
+        if let Some(mut run) = self.current.take() {
+            let trimmed = run.text.trim();
+            if !trimmed.is_empty() {
+                run.text = trimmed.to_string();
+                self.runs.push(run);
+            }
+        }
+    }
+}
+
+impl OutputDev for RunCollector {
+    fn begin_page(
+        &mut self,
+        _page_num: u32,
+        media_box: &MediaBox,
+        _art_box: Option<(f64, f64, f64, f64)>,
+    ) -> Result<(), OutputError> {
+        self.page_height = media_box.ury - media_box.lly;
+        Ok(())
+    }
+
+    fn end_page(&mut self) -> Result<(), OutputError> {
+        self.flush();
+        Ok(())
+    }
+
+    fn output_character(
+        &mut self,
+        trm: &Transform,
+        width: f64,
+        _spacing: f64,
+        font_size: f64,
+        char: &str,
+    ) -> Result<(), OutputError> {
+        // The same font size estimate as pdf_extract's PlainTextOutput.
+        let size = ((font_size * (trm.m11 + trm.m21)) * (font_size * (trm.m12 + trm.m22)))
+            .abs()
+            .sqrt();
+        let (x, y) = (trm.m31, self.page_height - trm.m32);
+        match &mut self.current {
+            Some(run)
+                if (y - run.y).abs() <= run.size * ROW_TOLERANCE
+                    && x >= run.x1 - run.size
+                    && x <= run.x1 + run.size * CELL_GAP =>
+            {
+                if x > run.x1 + run.size * 0.1 && !run.text.ends_with(' ') {
+                    run.text.push(' ');
+                }
+                run.text.push_str(char);
+                run.x1 = x + width * size;
+            }
+            _ => {
+                self.flush();
+                self.current = Some(TextRun {
+                    text: char.to_string(),
+                    x0: x,
+                    x1: x + width * size,
+                    y,
+                    size,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn begin_word(&mut self) -> Result<(), OutputError> {
+        Ok(())
+    }
+
+    fn end_word(&mut self) -> Result<(), OutputError> {
+        Ok(())
+    }
+
+    fn end_line(&mut self) -> Result<(), OutputError> {
+        Ok(())
+    }
+}
+
+/// A table found on a page. The first row is taken as the header.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Table {
+    pub rows: Vec<Vec<String>>,
+    /// Where the table is on the page: the baselines of its first and last rows, widened by
+    /// the row tolerance, in points from the top of the page.
+    pub top: f64,
+    pub bottom: f64,
+}
+
+impl Table {
+    /// The table as Markdown split into pieces of at most `max_len` bytes where possible,
+    /// each starting with the header, so a long table fits in several chunks.
+    pub fn markdown_pieces(&self, max_len: usize) -> Vec<String> {
+        let row = |cells: &[String]| {
+            let cells: Vec<String> = cells.iter().map(|c| c.replace('|', "\\|")).collect();
+            format!("| {} |\n", cells.join(" | "))
+        };
+        let header = format!(
+            "{}|{}\n",
+            row(&self.rows[0]),
+            " --- |".repeat(self.rows[0].len())
+        );
+        let mut pieces = Vec::new();
+        let mut piece = header.clone();
+        for cells in &self.rows[1..] {
+            let line = row(cells);
+            if piece.len() > header.len() && piece.len() + line.len() > max_len {
+                pieces.push(std::mem::replace(&mut piece, header.clone()));
+            }
+            piece.push_str(&line);
+        }
+        pieces.push(piece);
+        pieces
+            .into_iter()
+            .map(|p| p.trim_end().to_string())
+            .collect()
+    }
+}
+
+/// Finds the tables among a page's runs: at least `MIN_ROWS` consecutive rows with the same
+/// number of cells, the cells of each column overlapping horizontally, and digits in at
+/// least half of the cells below the header.
+pub fn find_tables(runs: &[TextRun]) -> Vec<Table> {
+    let mut cells: Vec<&TextRun> = runs
+        .iter()
+        .filter(|r| r.text.chars().count() <= MAX_CELL_CHARS)
+        .collect();
+    cells.sort_by(|a, b| a.y.total_cmp(&b.y));
+
+    let mut rows: Vec<Vec<&TextRun>> = Vec::new();
+    for cell in cells {
+        match rows.last_mut() {
+            Some(row) if (cell.y - row[0].y).abs() <= row[0].size * ROW_TOLERANCE => row.push(cell),
+            _ => rows.push(vec![cell]),
+        }
+    }
+    for row in &mut rows {
+        row.sort_by(|a, b| a.x0.total_cmp(&b.x0));
+    }
+    rows.retain(|row| {
+        row.len() >= 2
+            && row.windows(2).all(|w| w[0].x1 <= w[1].x0)
+            && !LIST_MARKER.is_match(&row[0].text)
+    });
+
+    let mut tables = Vec::new();
+    let mut current: Vec<Vec<&TextRun>> = Vec::new();
+    for row in rows {
+        let continues = current.last().is_some_and(|last| {
+            last.len() == row.len()
+                && row[0].y - last[0].y <= MAX_ROW_GAP * last[0].size
+                && (0..row.len()).all(|k| {
+                    let x0 = current.iter().map(|r| r[k].x0).fold(f64::MAX, f64::min);
+                    let x1 = current.iter().map(|r| r[k].x1).fold(f64::MIN, f64::max);
+                    row[k].x0 <= x1 && row[k].x1 >= x0
+                })
+        });
+        if !continues {
+            tables.extend(as_table(&current));
+            current.clear();
+        }
+        current.push(row);
+    }
+    tables.extend(as_table(&current));
+    tables
+}
+
+fn as_table(rows: &[Vec<&TextRun>]) -> Option<Table> {
+    if rows.len() < MIN_ROWS {
+        return None;
+    }
+    let body: Vec<&&TextRun> = rows[1..].iter().flatten().collect();
+    let numeric = body
+        .iter()
+        .filter(|c| c.text.chars().any(|ch| ch.is_ascii_digit()))
+        .count();
+    if numeric * 2 < body.len() {
+        return None;
+    }
+    let cells = rows.iter().flatten();
+    Some(Table {
+        rows: rows
+            .iter()
+            .map(|row| row.iter().map(|c| c.text.clone()).collect())
+            .collect(),
+        top: cells
+            .clone()
+            .map(|c| c.y - c.size * ROW_TOLERANCE)
+            .fold(f64::MAX, f64::min),
+        bottom: cells
+            .map(|c| c.y + c.size * ROW_TOLERANCE)
+            .fold(f64::MIN, f64::max),
+    })
+}
+
+/// Drops the lines of a page's `text` that lie within one of `tables` and consist only of
+/// its cells, which is where `pdf_extract` left the tables' text. `runs` must be the page's
+/// runs in the order they were collected; they give each line its position.
+pub fn remove_table_text(text: &str, runs: &[TextRun], tables: &[Table]) -> String {
+    if tables.is_empty() {
+        return text.to_string();
+    }
+    let cells: Vec<Vec<Vec<&str>>> = tables
+        .iter()
+        .map(|t| {
+            t.rows
+                .iter()
+                .flatten()
+                .map(|c| c.split_whitespace().collect())
+                .collect()
+        })
+        .collect();
+    // The runs hold the same characters as the text, less the whitespace, in the same order.
+    let mut positions = runs.iter().flat_map(|r| {
+        r.text
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .map(move |c| (c, r.y))
+    });
+    let mut aligned = true;
+    text.lines()
+        .filter(|line| {
+            let mut ys = Vec::new();
+            for c in line.chars().filter(|c| !c.is_whitespace()) {
+                match positions.next() {
+                    Some((run_char, y)) if aligned && run_char == c => ys.push(y),
+                    _ => aligned = false,
+                }
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.is_empty() {
+                return true;
+            }
+            if !aligned {
+                tracing::debug!(
+                    "Keeping {:?}: the text no longer lines up with the runs",
+                    line
+                );
+                return true;
+            }
+            !tables.iter().zip(&cells).any(|(table, cells)| {
+                ys.iter().all(|y| (table.top..=table.bottom).contains(y))
+                    && covered_by(&words, cells)
+            })
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Whether `words` can be split into a sequence of `cells`.
+fn covered_by(words: &[&str], cells: &[Vec<&str>]) -> bool {
+    let mut reachable = vec![false; words.len() + 1];
+    reachable[0] = true;
+    for i in 0..words.len() {
+        if !reachable[i] {
+            continue;
+        }
+        for cell in cells {
+            if words[i..].starts_with(cell) {
+                reachable[i + cell.len()] = true;
+            }
+        }
+    }
+    reachable[words.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(text: &str, x0: f64, x1: f64, y: f64) -> TextRun {
+        TextRun {
+            text: text.to_string(),
+            x0,
+            x1,
+            y,
+            size: 10.0,
+        }
+    }
+
+    /// The route scoring table from the Ticket to Ride rules, with the prose beside it.
+    fn scoring_table_runs() -> Vec<TextRun> {
+        let mut runs = vec![
+            run("Route Length", 320.0, 402.0, 88.1),
+            run("Points Scored", 499.0, 575.6, 88.2),
+            run("Route Scoring Table", 71.9, 243.2, 141.9),
+            run(
+                "When a player claims a route, he records the points he receives by",
+                71.9,
+                300.8,
+                159.5,
+            ),
+        ];
+        for (i, points) in ["1", "2", "4", "7", "10", "15"].iter().enumerate() {
+            let y = 107.4 + 20.5 * i as f64;
+            runs.push(run(points, 550.0, 557.0, y));
+            runs.push(run(&(i + 1).to_string(), 320.0, 325.0, y + 0.6));
+        }
+        runs
+    }
+
+    #[test]
+    fn test_find_tables() {
+        let tables = find_tables(&scoring_table_runs());
+        assert_eq!(tables.len(), 1);
+        assert_eq!(
+            tables[0].markdown_pieces(usize::MAX),
+            vec![
+                "| Route Length | Points Scored |\n\
+             | --- | --- |\n\
+             | 1 | 1 |\n\
+             | 2 | 2 |\n\
+             | 3 | 4 |\n\
+             | 4 | 7 |\n\
+             | 5 | 10 |\n\
+             | 6 | 15 |"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lists_and_prose_are_not_tables() {
+        let runs: Vec<TextRun> = (0..4)
+            .flat_map(|i| {
+                let y = 100.0 + 14.0 * i as f64;
+                [
+                    run("●", 72.0, 76.0, y),
+                    run(&format!("Item {}", i), 90.0, 150.0, y),
+                ]
+            })
+            .collect();
+        assert!(find_tables(&runs).is_empty());
+
+        // A bare letter is a cell, even one a symbol font would draw as a bullet.
+        let letters: Vec<TextRun> = ["u", "v", "w", "x"]
+            .iter()
+            .enumerate()
+            .flat_map(|(i, letter)| {
+                let y = 100.0 + 14.0 * i as f64;
+                [
+                    run(letter, 72.0, 78.0, y),
+                    run(&(i * 10).to_string(), 90.0, 100.0, y),
+                ]
+            })
+            .collect();
+        assert_eq!(find_tables(&letters).len(), 1);
+
+        let words: Vec<TextRun> = (0..4)
+            .flat_map(|i| {
+                let y = 100.0 + 14.0 * i as f64;
+                [
+                    run("Days of Wonder Online", 112.0, 376.0, y),
+                    run("Game design by", 435.0, 567.0, y),
+                ]
+            })
+            .collect();
+        assert!(find_tables(&words).is_empty());
+    }
+
+    #[test]
+    fn test_markdown_pieces_repeat_the_header() {
+        let table = &find_tables(&scoring_table_runs())[0];
+        let pieces = table.markdown_pieces(70);
+        assert_eq!(pieces.len(), 3);
+        assert!(pieces
+            .iter()
+            .all(|p| p.starts_with("| Route Length | Points Scored |\n| --- | --- |\n")));
+        assert!(pieces[2].ends_with("| 6 | 15 |"));
+    }
+
+    #[test]
+    fn test_remove_table_text() {
+        let mut runs = scoring_table_runs();
+        runs.push(run("1", 300.0, 305.0, 760.0));
+        let tables = find_tables(&runs);
+        assert!((tables[0].top - 85.1).abs() < 1e-9);
+        assert!((tables[0].bottom - 213.5).abs() < 1e-9);
+
+        // The lines as pdf_extract writes them, ending with a page number below the table.
+        let text = "Route Length Points Scored\nRoute Scoring Table\n\
+                    When a player claims a route, he records the points he receives by\n\
+                    1 1\n2 2\n\n4 3\n7 4\n10 5\n15 6\n1";
+        assert_eq!(
+            remove_table_text(text, &runs, &tables),
+            "Route Scoring Table\n\
+             When a player claims a route, he records the points he receives by\n\n1"
+        );
+    }
+}